@@ -0,0 +1,216 @@
+use std::{collections::{BTreeMap, BTreeSet, HashMap}, env, fmt::Write as _, io::{self, Write}, net::SocketAddr};
+use serde::Deserialize;
+use tokio::net::UdpSocket;
+use tokio::time::{self, Duration, Instant};
+use single_elevator::control::NodeStatus as Status;
+use single_elevator::elevator::elevio::{poll::CallButton, protocol::{CallType, Floor}};
+use single_elevator::networking::world_view::WorldView;
+
+// Live view of a whole elevator group, from the status every node sends to `--dashboard`.
+//
+//   dashboard [--port PORT]
+//
+// Shows every elevator and the hall requests as the nodes see them. Anything the nodes
+// disagree on for longer than it takes a change to spread is shown in red: a hall lamp lit
+// on some nodes but not others, a cab call backed up differently than its owner has it, or
+// a node that hears a peer which does not hear it back.
+
+const DEFAULT_PORT: u16 = 20200;
+const REDRAW_PERIOD: Duration = Duration::from_millis(200);
+const SILENT_AFTER: Duration = Duration::from_secs(1);      // A node is shown as silent when no status came for this long
+const GRACE: Duration = Duration::from_secs(1);             // How long the nodes may disagree before it is highlighted
+
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Deserialize)]
+struct StatusMessage {
+    from: u8,
+    status: Status,
+}
+
+struct Node {
+    status: Status,
+    addr: SocketAddr,
+    heard: Instant,
+}
+
+#[derive(Default)]
+struct Dashboard {
+    nodes: BTreeMap<u8, Node>,
+    disagreements: HashMap<String, Instant>,    // Since when each disagreement has been seen
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let port = match env::args().skip(1).collect::<Vec<_>>().as_slice() {
+        [] => DEFAULT_PORT,
+        [flag, port] if flag == "--port" => port.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid port: {port}")))?,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "usage: dashboard [--port PORT]")),
+    };
+    let sock = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], port))).await?;
+
+    // Draw on the alternate screen, so the terminal is left as it was on Ctrl-C
+    print!("\x1b[?1049h\x1b[?25l");
+    let mut dashboard = Dashboard::default();
+    let mut redraw = time::interval(REDRAW_PERIOD);
+    let mut buf = vec![0; 65536];
+    loop {
+        tokio::select! {
+            Ok((len, addr)) = sock.recv_from(&mut buf) => dashboard.receive(&buf[..len], addr),
+            _ = redraw.tick() => {
+                let screen = dashboard.render(port, Instant::now());
+                print!("\x1b[H\x1b[2J{screen}");
+                let _ = io::stdout().flush();
+            }
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+    print!("\x1b[?25h\x1b[?1049l");
+    Ok(())
+}
+
+impl Dashboard {
+    // Anything but a status message is ignored, nodes only send those here
+    fn receive(&mut self, bytes: &[u8], addr: SocketAddr) {
+        let Ok(message) = serde_json::from_slice::<serde_json::Value>(bytes) else { return };
+        let Some(Ok(StatusMessage { from, status })) = message.get("Status").map(StatusMessage::deserialize) else { return };
+        self.nodes.insert(from, Node { status, addr, heard: Instant::now() });
+    }
+
+    fn render(&mut self, port: u16, now: Instant) -> String {
+        let live: BTreeSet<u8> = self.nodes.iter().filter(|(_, node)| now.duration_since(node.heard) < SILENT_AFTER).map(|(id, _)| *id).collect();
+        let mut out = String::new();
+        let _ = writeln!(out, "Elevator group, listening on port {port}, {} of {} nodes live\n", live.len(), self.nodes.len());
+
+        // ---------- ELEVATORS ----------
+        let _ = writeln!(out, "{:<6}{:<7}{:<6}{:<8}{:<26}{:<14}{:<34}peers", "node", "floor", "dir", "door", "faults", "cab", "orders");
+        let ids: Vec<u8> = self.nodes.keys().copied().collect();
+        for id in ids {
+            let line = self.elevator_line(id, &live, now);
+            let _ = writeln!(out, "{line}");
+        }
+
+        // ---------- HALL REQUESTS ----------
+        // Each cell lists the nodes with the lamp lit, and the node that has it in its orders
+        let floors = self.nodes.values().map(|node| node.status.view.hall.len()).max().unwrap_or(0) as u8;
+        let _ = writeln!(out, "\n{:<7}{:<24}{:<24}", "floor", "hall up", "hall down");
+        for floor in (0..floors).rev().filter_map(|floor| Floor::new(floor, floors)) {
+            let mut row = format!("{floor:<7}");
+            for call in CallType::HALL {
+                let cell = self.hall_cell(floor, call, &live, now);
+                row.push_str(&cell);
+            }
+            let _ = writeln!(out, "{row}");
+        }
+        let _ = writeln!(out, "\n{DIM}lamps lit on nodes -> node serving it, {RED}red{RESET}{DIM}: nodes disagree, {YELLOW}yellow{RESET}{DIM}: one-way link, Ctrl-C to quit{RESET}");
+        out
+    }
+
+    fn elevator_line(&mut self, id: u8, live: &BTreeSet<u8>, now: Instant) -> String {
+        let node = &self.nodes[&id];
+        let status = &node.status;
+        let silent = !live.contains(&id);
+
+        let floor = status.car.floor.map(|floor| floor.to_string()).unwrap_or("?".to_string());
+        let direction = status.car.direction.to_string();
+        let door = if status.car.door_open { "open" } else { "closed" };
+
+        let mut faults = Vec::new();
+        if silent {
+            faults.push(format!("silent {:.0}s", now.duration_since(node.heard).as_secs_f64()));
+        }
+        if status.car.stopped {
+            faults.push("stop".to_string());
+        }
+        if status.car.obstructed {
+            faults.push("obstructed".to_string());
+        }
+        if status.view.out_of_service.contains(&id) {
+            faults.push("out of service".to_string());
+        }
+        let faults = if faults.is_empty() { "-".to_string() } else { faults.join(", ") };
+
+        // The cab calls as the node has them, red if a live peer backs up something else
+        let own = cab_floors(&status.view, id);
+        let backups_differ = live.iter()
+            .filter(|peer| **peer != id && !silent)
+            .any(|peer| cab_floors(&self.nodes[peer].status.view, id) != own);
+        let cab = list(own.iter().map(|floor| floor.to_string()));
+
+        let orders = list(status.current.iter().chain(status.queued.iter()).map(describe));
+
+        // Peers we hear that do not hear us back
+        let one_way: BTreeSet<u8> = status.peers.iter()
+            .filter(|peer| live.contains(peer) && !self.nodes[peer].status.peers.contains(&id))
+            .copied()
+            .collect();
+        let peers = list(status.peers.iter().map(|peer| match one_way.contains(peer) {
+            true => format!("{YELLOW}{peer}{RESET}"),
+            false => peer.to_string(),
+        }));
+
+        let addr = node.addr;
+        let cab_disagrees = self.persists(format!("cab {id}"), backups_differ, now);
+        let cab = if cab_disagrees { format!("{RED}{cab:<14}{RESET}") } else { format!("{cab:<14}") };
+        let line = format!("{id:<6}{floor:<7}{direction:<6}{door:<8}{faults:<26}{cab}{orders:<34}{peers} {DIM}({addr}){RESET}");
+        if silent { format!("{DIM}{line}{RESET}") } else { line }
+    }
+
+    fn hall_cell(&mut self, floor: Floor, call: CallType, live: &BTreeSet<u8>, now: Instant) -> String {
+        let lit: Vec<u8> = live.iter().copied().filter(|id| self.nodes[id].status.hall_lamps.contains(&(floor, call))).collect();
+        let serving: Vec<u8> = live.iter().copied()
+            .filter(|id| {
+                let status = &self.nodes[id].status;
+                status.current.iter().chain(status.queued.iter()).any(|order| *order == CallButton { floor, call })
+            })
+            .collect();
+        let active_somewhere = live.iter().any(|id| self.nodes[id].status.view.hall.get(floor.get() as usize).is_some_and(|requests| requests[call.index()].is_active()));
+
+        let mut cell = if lit.is_empty() { "-".to_string() } else { list(lit.iter().map(|id| id.to_string())) };
+        if !serving.is_empty() {
+            cell.push_str(&format!(" -> {}", list(serving.iter().map(|id| id.to_string()))));
+        }
+
+        // Lit on some live nodes but not all, or active in a view without any lamp lit
+        let partly_lit = !lit.is_empty() && lit.len() < live.len();
+        let unlit = lit.is_empty() && active_somewhere;
+        if self.persists(format!("hall {floor} {}", call.index()), partly_lit || unlit, now) {
+            format!("{RED}{cell:<24}{RESET}")
+        } else {
+            format!("{cell:<24}")
+        }
+    }
+
+    // Whether `key` has been disagreed on for longer than GRACE
+    fn persists(&mut self, key: String, disagree: bool, now: Instant) -> bool {
+        if !disagree {
+            self.disagreements.remove(&key);
+            return false;
+        }
+        now.duration_since(*self.disagreements.entry(key).or_insert(now)) >= GRACE
+    }
+}
+
+
+// ---------- PURE FUNCTIONS ----------
+
+fn cab_floors(view: &WorldView, node: u8) -> Vec<Floor> {
+    view.cab.get(&node).map(|cab| cab.active_floors()).unwrap_or_default()
+}
+
+fn describe(call: &CallButton) -> String {
+    let button = match call.call {
+        CallType::HallUp => "up",
+        CallType::HallDown => "down",
+        CallType::Cab => "cab",
+    };
+    format!("{button} {}", call.floor)
+}
+
+fn list(items: impl Iterator<Item = String>) -> String {
+    let items: Vec<String> = items.collect();
+    if items.is_empty() { "-".to_string() } else { items.join(" ") }
+}
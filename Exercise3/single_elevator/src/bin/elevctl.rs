@@ -0,0 +1,62 @@
+use std::{env, io::{self, Read, Write}, net::Shutdown, os::unix::net::UnixStream, path::PathBuf, process::ExitCode};
+use single_elevator::control;
+
+// Operator CLI for a running node, talking to its control socket.
+//
+//   elevctl <node id | --socket PATH> <command>...
+//
+// The socket of node n is control_<n>.sock in ELEV_STATE_DIR, or the working directory,
+// like the node puts it. Commands:
+//
+//   call <hall up|hall down|cab> <floor>   press a button on the node's elevator
+//   obstruction <on|off>                   simulate the obstruction switch
+//   stop <on|off>                          simulate the stop button
+//   service <in|out>                       take the elevator in or out of service
+//   orders                                 show the order table
+//   peers                                  show the peer list
+//   log <filter>                           change the node's log filter
+
+const USAGE: &str = "usage: elevctl <node id | --socket PATH> <command>...";
+
+fn main() -> ExitCode {
+    match run(env::args().skip(1).collect()) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("elevctl: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// Returns whether the node accepted the command
+fn run(mut args: Vec<String>) -> io::Result<bool> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidInput, what.to_string());
+
+    let socket = match args.first().map(String::as_str) {
+        Some("--socket") if args.len() >= 2 => {
+            let path = PathBuf::from(&args[1]);
+            args.drain(..2);
+            path
+        }
+        Some(id) if args.len() >= 2 => {
+            let path = control::socket_path(id.parse().map_err(|_| invalid(USAGE))?);
+            args.remove(0);
+            path
+        }
+        _ => return Err(invalid(USAGE)),
+    };
+    if args.is_empty() {
+        return Err(invalid(USAGE));
+    }
+
+    let mut stream = UnixStream::connect(&socket)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", socket.display())))?;
+    stream.write_all(format!("{}\n", args.join(" ")).as_bytes())?;
+    stream.shutdown(Shutdown::Write)?;
+
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    print!("{reply}");
+    Ok(!reply.starts_with("error:"))
+}
@@ -0,0 +1,291 @@
+use std::{env, io, path::PathBuf, process::{ExitStatus, Stdio}};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::process::{Child, ChildStdin, Command};
+use tokio::time::{self, Duration, Instant};
+use single_elevator::elevator::SERVER_ENV;
+use single_elevator::order_management::persistence::STATE_DIR_ENV;
+
+// Runs a whole elevator group on this machine: one simulator and one node per elevator,
+// all on localhost. Every line the children print is shown with the name of its child in
+// front, and the nodes can be killed and restarted from the prompt to see how the rest copes.
+//
+//   launcher [--nodes N] [--port BASE] [--sim-port BASE] [--simulator COMMAND] [--dashboard PORT]
+//
+// Node n (counting from 1) listens on 127.0.0.1:<port + n> and drives the simulator on
+// 127.0.0.1:<sim-port + n>. The simulator is `single_elevator sim-server` unless another
+// command is given, which is then started with `--port <port>`, e.g. SimElevatorServer.
+// Every node sends its status to 127.0.0.1:<dashboard>, run `dashboard` to watch them.
+
+const DEFAULT_NODES: u8 = 3;
+const DEFAULT_PORT: u16 = 20100;
+const DEFAULT_SIM_PORT: u16 = 15657;
+const DEFAULT_DASHBOARD_PORT: u16 = 20200;
+const SIMULATOR_TIMEOUT: Duration = Duration::from_secs(5);   // How long a simulator may take to accept connections
+const REAP_PERIOD: Duration = Duration::from_millis(200);     // How often to look for children that exited
+
+const HELP: &str = "\
+commands:
+  kill <n>                          crash node n
+  restart <n>                       start node n again, killing it first if it runs
+  press <n> <floor> <up|down|cab>   press a button on the simulator of node n
+  status                            show which nodes are running
+  quit                              stop everything";
+
+struct Config {
+    nodes: u8,
+    port: u16,
+    sim_port: u16,
+    simulator: Option<String>,      // None for the built-in simulator
+    dashboard: u16,
+}
+
+struct Launcher {
+    config: Config,
+    node_bin: PathBuf,
+    state_dir: PathBuf,
+    simulators: Vec<Proc>,
+    nodes: Vec<Proc>,
+}
+
+struct Proc {
+    name: String,
+    child: Option<Child>,           // None once it exited or was killed
+    stdin: Option<ChildStdin>,
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let config = Config::from_args(env::args().skip(1))?;
+
+    // The node binary is built next to this one
+    let node_bin = env::current_exe()?.with_file_name("single_elevator");
+    if !node_bin.is_file() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} not found, run `cargo build` first", node_bin.display())));
+    }
+
+    // Saved orders and flight recordings of every node, kept across restarts of a node
+    let state_dir = match env::var(STATE_DIR_ENV) {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => env::temp_dir().join(format!("elevator_launcher_{}", std::process::id())),
+    };
+    std::fs::create_dir_all(&state_dir)?;
+
+    let mut launcher = Launcher { config, node_bin, state_dir, simulators: Vec::new(), nodes: Vec::new() };
+    launcher.start().await?;
+    println!("{HELP}");
+    println!("state in {}", launcher.state_dir.display());
+
+    let mut commands = BufReader::new(tokio::io::stdin()).lines();
+    let mut reap = time::interval(REAP_PERIOD);
+    loop {
+        tokio::select! {
+            line = commands.next_line() => match line? {
+                Some(line) => {
+                    if !launcher.command(&line).await {
+                        break;
+                    }
+                }
+                None => break,
+            },
+            _ = reap.tick() => launcher.reap(),
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    launcher.stop().await;
+    Ok(())
+}
+
+impl Config {
+    fn from_args(mut args: impl Iterator<Item = String>) -> io::Result<Config> {
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid argument: {what}"));
+        let mut config = Config { nodes: DEFAULT_NODES, port: DEFAULT_PORT, sim_port: DEFAULT_SIM_PORT, simulator: None, dashboard: DEFAULT_DASHBOARD_PORT };
+
+        while let Some(arg) = args.next() {
+            let value = args.next().ok_or_else(|| invalid(&arg))?;
+            match arg.as_str() {
+                "--nodes" => config.nodes = value.parse().ok().filter(|n| *n >= 1).ok_or_else(|| invalid(&value))?,
+                "--port" => config.port = value.parse().map_err(|_| invalid(&value))?,
+                "--sim-port" => config.sim_port = value.parse().map_err(|_| invalid(&value))?,
+                "--simulator" => config.simulator = Some(value),
+                "--dashboard" => config.dashboard = value.parse().map_err(|_| invalid(&value))?,
+                _ => return Err(invalid(&arg)),
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl Launcher {
+    // Start every simulator, wait until they accept connections, then start every node
+    async fn start(&mut self) -> io::Result<()> {
+        for id in 1..=self.config.nodes {
+            let port = self.config.sim_port + id as u16;
+            let mut command = match &self.config.simulator {
+                Some(simulator) => Command::new(simulator),
+                None => {
+                    let mut command = Command::new(&self.node_bin);
+                    command.arg("sim-server");
+                    command
+                }
+            };
+            command.args(["--port", &port.to_string()]);
+            self.simulators.push(Proc::spawn(format!("sim{id}"), command)?);
+        }
+        for id in 1..=self.config.nodes {
+            wait_for_simulator(self.config.sim_port + id as u16).await?;
+        }
+
+        for id in 1..=self.config.nodes {
+            let node = Proc::spawn(format!("node{id}"), self.node_command(id))?;
+            self.nodes.push(node);
+        }
+        Ok(())
+    }
+
+    fn node_command(&self, id: u8) -> Command {
+        let mut command = Command::new(&self.node_bin);
+        command.arg(id.to_string());
+        command.args(["--port", &(self.config.port + id as u16).to_string()]);
+        for peer in (1..=self.config.nodes).filter(|peer| *peer != id) {
+            command.args(["--peer", &format!("{peer}@127.0.0.1:{}", self.config.port + peer as u16)]);
+        }
+        command.args(["--dashboard", &format!("127.0.0.1:{}", self.config.dashboard)]);
+        command.env(SERVER_ENV, format!("127.0.0.1:{}", self.config.sim_port + id as u16));
+        command.env(STATE_DIR_ENV, &self.state_dir);
+        command
+    }
+
+    // Handle one line from the prompt, returns false to quit
+    async fn command(&mut self, line: &str) -> bool {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let node = |word: Option<&&str>| word.and_then(|n| n.parse::<u8>().ok()).filter(|n| (1..=self.config.nodes).contains(n));
+
+        let result = match words.as_slice() {
+            [] => Ok(()),
+            ["kill", n] => match node(Some(n)) {
+                Some(id) => self.nodes[id as usize - 1].kill().await,
+                None => Err(format!("no node {n}")),
+            },
+            ["restart", n] => match node(Some(n)) {
+                Some(id) => self.restart(id).await,
+                None => Err(format!("no node {n}")),
+            },
+            ["press", n, floor, call] => match node(Some(n)) {
+                Some(_) if self.config.simulator.is_some() => Err("press only works with the built-in simulator".to_string()),
+                Some(id) => self.simulators[id as usize - 1].send(&format!("{floor} {call}")).await,
+                None => Err(format!("no node {n}")),
+            },
+            ["status"] => {
+                for proc in self.simulators.iter().chain(self.nodes.iter()) {
+                    println!("{}", proc.status());
+                }
+                Ok(())
+            }
+            ["help"] => {
+                println!("{HELP}");
+                Ok(())
+            }
+            ["quit"] | ["exit"] => return false,
+            _ => Err(format!("unknown command: {line}")),
+        };
+        if let Err(e) = result {
+            println!("launcher | {e}");
+        }
+        true
+    }
+
+    async fn restart(&mut self, id: u8) -> Result<(), String> {
+        let command = self.node_command(id);
+        let node = &mut self.nodes[id as usize - 1];
+        if node.child.is_some() {
+            node.kill().await?;
+        }
+        *node = Proc::spawn(format!("node{id}"), command).map_err(|e| e.to_string())?;
+        println!("launcher | restarted node{id}");
+        Ok(())
+    }
+
+    // Report children that exited on their own
+    fn reap(&mut self) {
+        for proc in self.simulators.iter_mut().chain(self.nodes.iter_mut()) {
+            if let Some(status) = proc.child.as_mut().and_then(|child| child.try_wait().ok().flatten()) {
+                proc.child = None;
+                println!("launcher | {} exited: {}", proc.name, describe(status));
+            }
+        }
+    }
+
+    // Nodes first, so none of them sees its simulator disappear
+    async fn stop(&mut self) {
+        for proc in self.nodes.iter_mut().chain(self.simulators.iter_mut()) {
+            let _ = proc.kill().await;
+        }
+    }
+}
+
+impl Proc {
+    // Start `command` and print its output line by line, prefixed with `name`
+    fn spawn(name: String, mut command: Command) -> io::Result<Proc> {
+        command.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).kill_on_drop(true);
+        let mut child = command.spawn()?;
+        if let Some(stdout) = child.stdout.take() {
+            tokio::spawn(print_lines(name.clone(), stdout));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            tokio::spawn(print_lines(name.clone(), stderr));
+        }
+        let stdin = child.stdin.take();
+        println!("launcher | started {name} (pid {})", child.id().unwrap_or_default());
+        Ok(Proc { name, child: Some(child), stdin })
+    }
+
+    // SIGKILL, so the node gets no chance to clean up, like a crash or a power cut
+    async fn kill(&mut self) -> Result<(), String> {
+        let mut child = self.child.take().ok_or_else(|| format!("{} is not running", self.name))?;
+        child.kill().await.map_err(|e| e.to_string())?;
+        println!("launcher | killed {}", self.name);
+        Ok(())
+    }
+
+    async fn send(&mut self, line: &str) -> Result<(), String> {
+        let stdin = self.stdin.as_mut().filter(|_| self.child.is_some()).ok_or_else(|| format!("{} is not running", self.name))?;
+        stdin.write_all(format!("{line}\n").as_bytes()).await.map_err(|e| e.to_string())
+    }
+
+    fn status(&self) -> String {
+        match &self.child {
+            Some(child) => format!("{:>6} | running (pid {})", self.name, child.id().unwrap_or_default()),
+            None => format!("{:>6} | stopped", self.name),
+        }
+    }
+}
+
+async fn print_lines(name: String, output: impl AsyncRead + Unpin) {
+    let mut lines = BufReader::new(output).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        println!("{name:>6} | {line}");
+    }
+}
+
+// Retry until the simulator accepts a connection
+async fn wait_for_simulator(port: u16) -> io::Result<()> {
+    let deadline = Instant::now() + SIMULATOR_TIMEOUT;
+    loop {
+        match TcpStream::connect(("127.0.0.1", port)).await {
+            Ok(_) => return Ok(()),
+            Err(e) if Instant::now() >= deadline => {
+                return Err(io::Error::new(e.kind(), format!("simulator on port {port} did not start: {e}")));
+            }
+            Err(_) => time::sleep(Duration::from_millis(100)).await,
+        }
+    }
+}
+
+fn describe(status: ExitStatus) -> String {
+    match status.code() {
+        Some(code) => format!("exit code {code}"),
+        None => "killed by a signal".to_string(),
+    }
+}
@@ -0,0 +1,261 @@
+use std::{collections::{BTreeMap, BTreeSet}, fs, io, sync::Arc};
+
+use crate::elevator::elevio::{poll::CallButton, protocol::{CallType, Floor}};
+use crate::networking::NodeId;
+
+// Path of a file describing the building, the standard layout is used without one
+pub const BUILDING_ENV: &str = "ELEV_BUILDING";
+
+// Which floors the building has, which hall buttons each floor has and which floors each
+// car may serve. The layout file has one statement per line, # starts a comment:
+//
+//   floors 6            floors 0 to 5
+//   hall 3 up           hall buttons on floor 3: up, down, both or none
+//   car 2 0 1 2 3       car 2 only serves floors 0 to 3
+//
+// Floors have both hall buttons unless told otherwise, except that the top floor has no up
+// button and the bottom floor no down button. Cars not mentioned serve every floor, and
+// some car of the group must serve each floor with a hall button.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Building {
+    pub num_floors: u8,
+    hall: Vec<[bool; 2]>,                   // [floor][hall up / hall down]
+    cars: BTreeMap<NodeId, BTreeSet<u8>>,   // Floors served by the cars that do not serve all of them
+}
+
+// The building as seen from one car, for the tasks of that car's node
+#[derive(Debug, Clone)]
+pub struct CarLayout {
+    pub car: NodeId,
+    pub building: Arc<Building>,
+}
+
+impl Building {
+    pub fn standard(num_floors: u8) -> Building {
+        let hall = (0..num_floors).map(|floor| [floor + 1 < num_floors, floor > 0]).collect();
+        Building { num_floors, hall, cars: BTreeMap::new() }
+    }
+
+    // The building in the file named by ELEV_BUILDING, or the standard one with `num_floors`
+    pub fn from_env(num_floors: u8) -> io::Result<Building> {
+        match std::env::var(BUILDING_ENV) {
+            Ok(path) => Building::load(&path, num_floors),
+            Err(_) => Ok(Building::standard(num_floors)),
+        }
+    }
+
+    // The building in the layout file at `path`, which must have the `num_floors` of the hardware
+    pub fn load(path: &str, num_floors: u8) -> io::Result<Building> {
+        let in_file = |e: io::Error| io::Error::new(e.kind(), format!("{path}: {e}"));
+        let building = Building::parse(&fs::read_to_string(path).map_err(in_file)?).map_err(in_file)?;
+        if building.num_floors != num_floors {
+            return Err(in_file(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("{} floors, but the hardware has {num_floors}", building.num_floors))));
+        }
+        Ok(building)
+    }
+
+    pub fn parse(text: &str) -> io::Result<Building> {
+        let mut building: Option<Building> = None;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = |what: String| io::Error::new(io::ErrorKind::InvalidInput, format!("line {}: {what}", number + 1));
+            let words: Vec<&str> = line.split_whitespace().collect();
+
+            if let ["floors", count] = words.as_slice() {
+                let count: u8 = count.parse().ok().filter(|count| *count >= 2).ok_or_else(|| invalid(format!("invalid number of floors: {count}")))?;
+                if building.is_some() {
+                    return Err(invalid("floors given twice".to_string()));
+                }
+                building = Some(Building::standard(count));
+                continue;
+            }
+            let building = building.as_mut().ok_or_else(|| invalid("expected `floors` first".to_string()))?;
+            let floor = |word: &str| word.parse::<u8>().ok().filter(|floor| *floor < building.num_floors).ok_or_else(|| invalid(format!("no floor {word}")));
+
+            match words.as_slice() {
+                ["hall", at, buttons] => {
+                    let at = floor(at)?;
+                    building.hall[at as usize] = match *buttons {
+                        "up" => [true, false],
+                        "down" => [false, true],
+                        "both" => [true, true],
+                        "none" => [false, false],
+                        _ => return Err(invalid(format!("expected up, down, both or none, not {buttons}"))),
+                    };
+                }
+                ["car", car, floors @ ..] if !floors.is_empty() => {
+                    let car: NodeId = car.parse().map_err(|_| invalid(format!("invalid car {car}")))?;
+                    let floors = floors.iter().map(|word| floor(word)).collect::<io::Result<BTreeSet<u8>>>()?;
+                    building.cars.insert(car, floors);
+                }
+                _ => return Err(invalid(format!("unknown statement: {line}"))),
+            }
+        }
+        building.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no floors given"))
+    }
+
+    pub fn car(self: &Arc<Building>, car: NodeId) -> CarLayout {
+        CarLayout { car, building: self.clone() }
+    }
+
+    pub fn serves(&self, car: NodeId, floor: u8) -> bool {
+        floor < self.num_floors && self.cars.get(&car).is_none_or(|floors| floors.contains(&floor))
+    }
+
+    pub fn has_hall_button(&self, floor: u8, call: CallType) -> bool {
+        call.is_hall() && self.hall.get(floor as usize).is_some_and(|buttons| buttons[call.index()])
+    }
+
+    // Whether `cars` serve every floor with a hall button. A call on any other floor would
+    // never be assigned, and its lamp would stay lit for good.
+    pub fn check_cars(&self, cars: &BTreeSet<NodeId>) -> io::Result<()> {
+        let unserved = (0..self.num_floors).find(|floor| {
+            CallType::HALL.iter().any(|call| self.has_hall_button(*floor, *call)) && !cars.iter().any(|car| self.serves(*car, *floor))
+        });
+        match unserved {
+            Some(floor) => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("floor {floor} has hall buttons, but none of the cars {cars:?} serves it"))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl CarLayout {
+    // Whether `call` is a button this car has and may be asked to serve, and if not why
+    pub fn check(&self, call: &CallButton) -> io::Result<()> {
+        let building = &self.building;
+        let invalid = |what: String| Err(io::Error::new(io::ErrorKind::InvalidInput, what));
+        match call.call {
+            _ if call.floor.get() >= building.num_floors => invalid(format!("no floor {}", call.floor)),
+            CallType::HallUp | CallType::HallDown if !building.has_hall_button(call.floor.get(), call.call) => {
+                invalid(format!("no {} button at floor {}", call.call, call.floor))
+            }
+            CallType::HallUp | CallType::HallDown => Ok(()),
+            CallType::Cab if !building.serves(self.car, call.floor.get()) => invalid(format!("car {} does not serve floor {}", self.car, call.floor)),
+            CallType::Cab => Ok(()),
+        }
+    }
+
+    // Every button on this car's panels that is worth polling
+    pub fn buttons(&self) -> Vec<CallButton> {
+        (0..self.building.num_floors)
+            .filter_map(|floor| Floor::new(floor, self.building.num_floors))
+            .flat_map(|floor| CallType::ALL.map(|call| CallButton { floor, call }))
+            .filter(|call| self.check(call).is_ok())
+            .collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Any floor at all, so there are floors past the top for the checks to reject
+    fn call(floor: u8, call: CallType) -> CallButton {
+        CallButton { floor: Floor::new(floor, u8::MAX).unwrap(), call }
+    }
+
+    fn error(text: &str) -> String {
+        let error = Building::parse(text).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        error.to_string()
+    }
+
+    #[test]
+    fn the_ends_have_no_button_leading_out_of_the_building() {
+        let building = Building::parse("floors 4").unwrap();
+        assert_eq!(building, Building::standard(4));
+        assert!(!building.has_hall_button(3, CallType::HallUp));
+        assert!(!building.has_hall_button(0, CallType::HallDown));
+        assert!(building.has_hall_button(3, CallType::HallDown) && building.has_hall_button(0, CallType::HallUp));
+        assert!((1..3).all(|floor| CallType::ALL.iter().filter(|c| c.is_hall()).all(|c| building.has_hall_button(floor, *c))));
+
+        let layout = Arc::new(building).car(1);
+        assert!(layout.check(&call(3, CallType::HallUp)).is_err());
+        assert!(layout.check(&call(0, CallType::HallDown)).is_err());
+        assert!(layout.check(&call(4, CallType::Cab)).is_err());
+        assert_eq!(layout.buttons().len(), 4 + 3 + 3);
+    }
+
+    #[test]
+    fn hall_buttons_can_be_given_per_floor() {
+        let text = "
+            floors 5    # a comment
+            hall 1 none
+            hall 2 up
+            hall 3 down
+            hall 4 both
+        ";
+        let layout = Arc::new(Building::parse(text).unwrap()).car(1);
+        let hall = |floor| [CallType::HallUp, CallType::HallDown].map(|c| layout.check(&call(floor, c)).is_ok());
+        assert_eq!((1..5).map(hall).collect::<Vec<_>>(), [[false, false], [true, false], [false, true], [true, true]]);
+        assert!(layout.check(&call(1, CallType::Cab)).is_ok());
+    }
+
+    #[test]
+    fn cars_can_be_restricted_to_some_floors() {
+        let building = Arc::new(Building::parse("floors 4\ncar 2 0 2 3").unwrap());
+        let (restricted, free) = (building.car(2), building.car(1));
+
+        assert!(restricted.check(&call(1, CallType::Cab)).unwrap_err().to_string().contains("car 2 does not serve floor 1"));
+        assert!([0, 2, 3].iter().all(|floor| restricted.check(&call(*floor, CallType::Cab)).is_ok()));
+        assert!((0..4).all(|floor| free.check(&call(floor, CallType::Cab)).is_ok()));
+        assert!(!building.serves(2, 1) && building.serves(1, 1));
+
+        // The hall buttons are the building's, whichever car is asked
+        assert!(restricted.check(&call(1, CallType::HallUp)).is_ok());
+        assert!(!restricted.buttons().contains(&call(1, CallType::Cab)));
+    }
+
+    #[test]
+    fn every_floor_with_hall_buttons_needs_a_car() {
+        let building = Building::parse("floors 4\ncar 1 0 1 2\ncar 2 0 2").unwrap();
+        let error = building.check_cars(&BTreeSet::from([1, 2])).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(error.to_string().starts_with("floor 3 has hall buttons"));
+
+        // A car not mentioned serves every floor, and a floor without hall buttons needs no car
+        assert!(building.check_cars(&BTreeSet::from([1, 2, 3])).is_ok());
+        let building = Building::parse("floors 4\ncar 1 0 1 2\nhall 3 none").unwrap();
+        assert!(building.check_cars(&BTreeSet::from([1])).is_ok());
+    }
+
+    #[test]
+    fn floors_must_be_given_once_and_first() {
+        assert!(error("").contains("no floors given"));
+        assert!(error("# only a comment").contains("no floors given"));
+        assert!(error("hall 1 up\nfloors 4").starts_with("line 1: expected `floors` first"));
+        assert!(error("floors 4\nfloors 5").starts_with("line 2: floors given twice"));
+        assert!(error("floors 1").contains("invalid number of floors"));
+    }
+
+    #[test]
+    fn mistakes_are_reported_with_their_line() {
+        assert!(error("floors 4\nhall 4 up").starts_with("line 2: no floor 4"));
+        assert!(error("floors 4\nhall 1 sideways").contains("expected up, down, both or none"));
+        assert!(error("floors 4\ncar x 1").contains("invalid car x"));
+        assert!(error("floors 4\ncar 2 1 9").contains("no floor 9"));
+        assert!(error("floors 4\ncar 2").contains("unknown statement"));
+        assert!(error("floors 4\nbasement 1").contains("unknown statement"));
+    }
+
+    #[test]
+    fn a_layout_file_must_match_the_hardware() {
+        let path = std::env::temp_dir().join(format!("elevator_building_{}.txt", std::process::id()));
+        fs::write(&path, "floors 6\nhall 2 none\n").unwrap();
+        let path_str = path.to_str().unwrap();
+
+        assert_eq!(Building::load(path_str, 6).unwrap().num_floors, 6);
+        let error = Building::load(path_str, 4).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(error.to_string().contains("6 floors, but the hardware has 4"), "{error}");
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(Building::load(path_str, 6).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
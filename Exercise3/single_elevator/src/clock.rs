@@ -0,0 +1,185 @@
+use std::{future::Future, pin::Pin, sync::{Arc, Mutex}, task::{Context, Poll, Waker}, time::{SystemTime, UNIX_EPOCH}};
+use tokio::time::{self, Duration, Instant};
+
+pub type Sleep<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+pub type SharedClock = Arc<dyn Clock>;
+
+// Source of time for everything that waits or times out, so the timing can be
+// driven by hand instead of by the wall clock
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    // Milliseconds since the Unix epoch, for counters that must keep growing across restarts
+    fn epoch_millis(&self) -> u64;
+
+    fn sleep(&self, duration: Duration) -> Sleep<'_>;
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep<'_> {
+        self.sleep(deadline.saturating_duration_since(self.now()))
+    }
+}
+
+// The real time, as seen by tokio
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn epoch_millis(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep<'_> {
+        Box::pin(time::sleep(duration))
+    }
+}
+
+pub fn system() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+
+// Where the epoch time of a virtual clock starts
+const VIRTUAL_EPOCH_MILLIS: u64 = 1_700_000_000_000;
+
+// Time that only moves when `advance` is called. Sleepers whose deadline has passed are
+// woken right away, so hours of door timeouts and peer expiries run in no time at all.
+// After advancing, yield (e.g. tokio::task::yield_now) to let the woken tasks run.
+pub struct VirtualClock {
+    start: Instant,
+    state: Mutex<VirtualState>,
+}
+
+struct VirtualState {
+    elapsed: Duration,
+    waiting: Vec<Waker>,
+}
+
+impl VirtualClock {
+    pub fn new() -> Arc<VirtualClock> {
+        Arc::new(VirtualClock {
+            start: Instant::now(),
+            state: Mutex::new(VirtualState { elapsed: Duration::ZERO, waiting: Vec::new() }),
+        })
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.state.lock().unwrap().elapsed
+    }
+
+    // Move time forward and wake every sleeper, the ones not due yet go back to sleep
+    pub fn advance(&self, by: Duration) {
+        let waiting = {
+            let mut state = self.state.lock().unwrap();
+            state.elapsed += by;
+            std::mem::take(&mut state.waiting)
+        };
+        for waker in waiting {
+            waker.wake();
+        }
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    // Starts at the same date on every run, so runs do not depend on the wall clock
+    fn epoch_millis(&self) -> u64 {
+        VIRTUAL_EPOCH_MILLIS + self.elapsed().as_millis() as u64
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep<'_> {
+        Box::pin(VirtualSleep { clock: self, deadline: self.now() + duration })
+    }
+}
+
+struct VirtualSleep<'a> {
+    clock: &'a VirtualClock,
+    deadline: Instant,
+}
+
+impl Future for VirtualSleep<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.clock.state.lock().unwrap();
+        if self.clock.start + state.elapsed >= self.deadline {
+            return Poll::Ready(());
+        }
+        if !state.waiting.iter().any(|waker| waker.will_wake(cx.waker())) {
+            state.waiting.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[test]
+    fn advance_moves_time_by_exactly_that_much() {
+        let clock = VirtualClock::new();
+        let start = clock.now();
+        assert_eq!(clock.elapsed(), Duration::ZERO);
+
+        clock.advance(Duration::from_millis(250));
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(clock.elapsed(), Duration::from_millis(2250));
+        assert_eq!(clock.now() - start, Duration::from_millis(2250));
+    }
+
+    #[test]
+    fn epoch_time_is_virtual_too() {
+        let (a, b) = (VirtualClock::new(), VirtualClock::new());
+        assert_eq!(a.epoch_millis(), b.epoch_millis());
+
+        a.advance(Duration::from_millis(1500));
+        assert_eq!(a.epoch_millis(), b.epoch_millis() + 1500);
+    }
+
+    #[tokio::test]
+    async fn sleepers_wake_in_deadline_order_and_not_before() {
+        let clock = VirtualClock::new();
+        let woken = Arc::new(Mutex::new(Vec::new()));
+
+        for ms in [30, 10, 20] {
+            let (clock, woken) = (clock.clone(), woken.clone());
+            tokio::spawn(async move {
+                clock.sleep(Duration::from_millis(ms)).await;
+                woken.lock().unwrap().push((ms, clock.elapsed()));
+            });
+        }
+        settle().await;
+        assert!(woken.lock().unwrap().is_empty());
+
+        for _ in 0..8 {
+            clock.advance(Duration::from_millis(5));
+            settle().await;
+        }
+        let woken = woken.lock().unwrap().clone();
+        assert_eq!(woken, [10, 20, 30].map(|ms| (ms, Duration::from_millis(ms))));
+    }
+
+    #[tokio::test]
+    async fn a_deadline_in_the_past_is_immediate() {
+        let clock = VirtualClock::new();
+        let deadline = clock.now() + Duration::from_millis(10);
+        clock.advance(Duration::from_millis(20));
+
+        let mut sleep = clock.sleep_until(deadline);
+        let waker = std::task::Waker::noop();
+        assert!(sleep.as_mut().poll(&mut Context::from_waker(waker)).is_ready());
+    }
+}
@@ -0,0 +1,281 @@
+use std::{collections::{BTreeSet, VecDeque}, fmt::Write as _, fs, io, path::{Path, PathBuf}, sync::{Arc, Mutex}};
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, net::{UnixListener, UnixStream}, sync::mpsc::UnboundedSender as UTx};
+use serde::{Deserialize, Serialize};
+use tracing::{Instrument, info, warn};
+
+use crate::building::CarLayout;
+use crate::elevator::ElevatorState;
+use crate::elevator::elevio::{elev::Elevio, poll::CallButton, protocol::{CallType, Floor}};
+use crate::logging;
+use crate::networking::{NetEvent, NodeId, world_view::WorldView};
+use crate::order_management::{OrderEvent, persistence::STATE_DIR_ENV, planner};
+
+// Local control socket of a node, used by the `elevctl` operator CLI. A client sends one
+// command per connection and reads the answer as text until the node closes the connection.
+//
+//   call <hall up|hall down|cab> <floor>   as if the button was pressed on this elevator
+//   obstruction <on|off>                   simulated obstruction switch
+//   stop <on|off>                          simulated stop button
+//   service <in|out>                       no hall calls are assigned to nodes out of service
+//   orders                                 orders of this elevator and requests of the group
+//   peers                                  every node known, reachable or not
+//   log <filter>                           change the log filter, EnvFilter syntax
+
+pub type SharedStatus = Arc<Mutex<NodeStatus>>;
+
+// What the operator can look at, each part written by the task that owns it.
+// Also sent to the dashboard, if there is one.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct NodeStatus {
+    #[serde(flatten)]
+    pub car: ElevatorState,
+    pub current: Option<CallButton>,    // Order the car is on its way to
+    pub queued: Vec<CallButton>,
+    pub stops: Vec<CallButton>,         // Stops of the sweep the car is on, in order
+    pub peers: BTreeSet<NodeId>,        // Peers heard from recently
+    pub hall_lamps: BTreeSet<(Floor, CallType)>,   // (floor, call) of the hall lamps lit on this elevator
+    pub view: WorldView,
+}
+
+impl NodeStatus {
+    pub fn set_orders(&mut self, orders: &VecDeque<CallButton>, current_orders: &[Option<CallButton>], position: Option<u8>) {
+        self.current = current_orders.first().cloned().flatten();
+        self.queued = orders.iter().cloned().collect();
+        self.stops = match (position, &self.current) {
+            (Some(position), Some(current)) => planner::sweep(position, current, orders),
+            _ => Vec::new(),
+        };
+    }
+}
+
+// Everything the commands act on
+#[derive(Clone)]
+pub struct Control {
+    pub id: NodeId,
+    pub io: Elevio,
+    pub layout: CarLayout,
+    pub order_tx: UTx<OrderEvent>,
+    pub net_event_tx: UTx<NetEvent>,
+    pub status: SharedStatus,
+}
+
+// The socket of node `id` in ELEV_STATE_DIR, or the working directory
+pub fn socket_path(id: NodeId) -> PathBuf {
+    let dir = std::env::var(STATE_DIR_ENV).unwrap_or_else(|_| ".".to_string());
+    Path::new(&dir).join(format!("control_{id}.sock"))
+}
+
+pub async fn control_runner(path: PathBuf, control: Control) -> io::Result<()> {
+    // A socket left behind by an earlier run of this node would make bind fail
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+    info!(path = %path.display(), "Control socket listening");
+
+    // Each connection gets its own task, so a client that never sends its command holds up no one else
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let control = control.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, &control).await {
+                warn!(error = %e, "Control connection failed");
+            }
+        }.in_current_span());
+    }
+}
+
+async fn handle(stream: UnixStream, control: &Control) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut line = String::new();
+    BufReader::new(reader).read_line(&mut line).await?;
+
+    let reply = match command(line.trim(), control) {
+        Ok(reply) => reply,
+        Err(e) => format!("error: {e}\n"),
+    };
+    writer.write_all(reply.as_bytes()).await?;
+    writer.shutdown().await
+}
+
+fn command(line: &str, control: &Control) -> Result<String, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    info!(command = line, "Operator command");
+    match words.as_slice() {
+        ["call", button @ .., floor] => {
+            let call = match button {
+                ["hall", "up"] => CallType::HallUp,
+                ["hall", "down"] => CallType::HallDown,
+                ["cab"] => CallType::Cab,
+                _ => return Err("expected hall up, hall down or cab".to_string()),
+            };
+            let floor = floor.parse().ok().and_then(|number| Floor::new(number, control.layout.building.num_floors)).ok_or(format!("no floor {floor}"))?;
+            let call = CallButton { floor, call };
+            control.layout.check(&call).map_err(|e| e.to_string())?;
+            control.order_tx.send(OrderEvent::Pressed(call)).map_err(|_| "order management is not running")?;
+            Ok(format!("{} at floor {floor} pressed\n", button.join(" ")))
+        }
+        ["obstruction", state] => {
+            control.io.simulate_obstruction(on_off(state)?);
+            Ok(format!("obstruction {state}\n"))
+        }
+        ["stop", state] => {
+            control.io.simulate_stop(on_off(state)?);
+            Ok(format!("stop {state}\n"))
+        }
+        ["service", state] => {
+            let in_service = match *state {
+                "in" => true,
+                "out" => false,
+                _ => return Err("expected in or out".to_string()),
+            };
+            control.net_event_tx.send(NetEvent::Service(in_service)).map_err(|_| "networking is not running")?;
+            Ok(format!("node {} {} of service\n", control.id, if in_service { "back in" } else { "out" }))
+        }
+        ["orders"] => Ok(orders(&control.status.lock().unwrap())),
+        ["peers"] => Ok(peers(control.id, &control.status.lock().unwrap())),
+        ["log", filter] => {
+            logging::set_filter(filter)?;
+            Ok(format!("log filter set to {filter}\n"))
+        }
+        _ => Err(format!("unknown command: {line}")),
+    }
+}
+
+fn orders(status: &NodeStatus) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "current   {}", status.current.as_ref().map(describe).unwrap_or("-".to_string()));
+    let _ = writeln!(out, "queued    {}", list(status.queued.iter().map(describe)));
+    let _ = writeln!(out, "stops     {}", list(status.stops.iter().map(describe)));
+
+    let hall = status.view.active_hall_calls().into_iter().map(|(floor, call)| describe(&CallButton { floor, call }));
+    let _ = writeln!(out, "hall      {}", list(hall));
+    for (node, cab) in status.view.cab.iter() {
+        let _ = writeln!(out, "cab {node:<5} {}", list(cab.active_floors().iter().map(|floor| format!("floor {floor}"))));
+    }
+    out
+}
+
+fn peers(id: NodeId, status: &NodeStatus) -> String {
+    let mut nodes: BTreeSet<NodeId> = status.view.positions.keys().chain(status.peers.iter()).copied().collect();
+    nodes.insert(id);
+
+    let mut out = String::new();
+    for node in nodes {
+        let reach = match node {
+            _ if node == id => "this node",
+            _ if status.peers.contains(&node) => "reachable",
+            _ => "unreachable",
+        };
+        let service = if status.view.in_service(node) { "in service" } else { "out of service" };
+        let floor = status.view.positions.get(&node).map(|floor| floor.to_string()).unwrap_or("?".to_string());
+        let _ = writeln!(out, "node {node:<4} {reach:<12} {service:<15} floor {floor}");
+    }
+    out
+}
+
+
+// ---------- PURE FUNCTIONS ----------
+
+fn on_off(state: &str) -> Result<bool, String> {
+    match state {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err("expected on or off".to_string()),
+    }
+}
+
+fn describe(call: &CallButton) -> String {
+    format!("{} at floor {}", call.call, call.floor)
+}
+
+fn list(items: impl Iterator<Item = String>) -> String {
+    let items: Vec<String> = items.collect();
+    if items.is_empty() { "-".to_string() } else { items.join(", ") }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Cursor, time::Duration};
+    use tokio::{io::AsyncReadExt, sync::mpsc::{self, UnboundedReceiver as URx}, time::timeout};
+    use crate::building::Building;
+
+    fn control() -> (Control, URx<OrderEvent>, URx<NetEvent>) {
+        let (order_tx, order_rx) = mpsc::unbounded_channel();
+        let (net_event_tx, net_event_rx) = mpsc::unbounded_channel();
+        let control = Control {
+            id: 1,
+            io: Elevio::from_connection(Box::new(Cursor::new(Vec::new())), "test", 4),
+            layout: Arc::new(Building::standard(4)).car(1),
+            order_tx,
+            net_event_tx,
+            status: SharedStatus::default(),
+        };
+        (control, order_rx, net_event_rx)
+    }
+
+    #[test]
+    fn a_call_is_pressed_on_this_elevator() {
+        let (control, mut order_rx, _) = control();
+        assert_eq!(command("call hall up 2", &control).unwrap(), "hall up at floor 2 pressed\n");
+        assert!(matches!(order_rx.try_recv(), Ok(OrderEvent::Pressed(CallButton { floor, call: CallType::HallUp })) if floor.get() == 2));
+        command("call cab 0", &control).unwrap();
+        assert!(matches!(order_rx.try_recv(), Ok(OrderEvent::Pressed(CallButton { floor, call: CallType::Cab })) if floor.get() == 0));
+    }
+
+    #[test]
+    fn switches_and_service_are_answered() {
+        let (control, _, mut net_event_rx) = control();
+        assert_eq!(command("obstruction on", &control).unwrap(), "obstruction on\n");
+        assert_eq!(command("stop off", &control).unwrap(), "stop off\n");
+
+        assert_eq!(command("service out", &control).unwrap(), "node 1 out of service\n");
+        assert!(matches!(net_event_rx.try_recv(), Ok(NetEvent::Service(false))));
+    }
+
+    #[test]
+    fn bad_input_is_an_error_and_presses_nothing() {
+        let (control, mut order_rx, _) = control();
+        for (line, error) in [
+            ("", "unknown command: "),
+            ("dance", "unknown command: dance"),
+            ("call hall sideways 2", "expected hall up, hall down or cab"),
+            ("call cab", "expected hall up, hall down or cab"),
+            ("call cab two", "no floor two"),
+            ("call hall down 0", "no hall down button at floor 0"),
+            ("call cab 9", "no floor 9"),
+            ("obstruction maybe", "expected on or off"),
+            ("stop", "unknown command: stop"),
+            ("service away", "expected in or out"),
+        ] {
+            assert_eq!(command(line, &control).unwrap_err(), error, "{line}");
+        }
+        assert!(order_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn a_silent_client_does_not_hold_up_the_next() {
+        let path = std::env::temp_dir().join(format!("elevator_control_{}.sock", std::process::id()));
+        let (control, _order_rx, _net_event_rx) = control();
+        let runner = tokio::spawn(control_runner(path.clone(), control));
+        tokio::task::yield_now().await;
+
+        let _silent = timeout(Duration::from_secs(1), async {
+            loop {
+                match UnixStream::connect(&path).await {
+                    Ok(stream) => break stream,
+                    Err(_) => tokio::task::yield_now().await,
+                }
+            }
+        }).await.unwrap();
+
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        client.write_all(b"bogus\n").await.unwrap();
+        let mut reply = String::new();
+        timeout(Duration::from_secs(1), client.read_to_string(&mut reply)).await.unwrap().unwrap();
+        assert_eq!(reply, "error: unknown command: bogus\n");
+
+        runner.abort();
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -5,99 +5,289 @@ mod elevator_lights;
 pub mod elevio;
 use elevio::elev::Elevio;
 use elevio::poll::CallButton as CallButton;
-use tokio::sync::mpsc::{UnboundedReceiver as URx, UnboundedSender as UTx, unbounded_channel as uc};
-use crate::order_management::Order;
+use elevio::protocol::Direction;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex as AsyncMutex, watch, mpsc::{UnboundedReceiver as URx, UnboundedSender as UTx, unbounded_channel as uc}};
+use crate::building::CarLayout;
+use crate::order_management::OrderEvent;
+use crate::supervisor::{Policy, Supervisor};
+use crate::clock::SharedClock;
 
-use std::{io::*, time::*, sync::{Arc, Mutex}};
+use std::{io::*, time::*, sync::Arc};
 
 
 pub const NUM_FLOORS: u8 = 4;
+pub const ELEVATOR_ADDR: &str = "localhost:15657";
+// Address of the elevator server to use instead of ELEVATOR_ADDR, e.g. ELEV_SERVER=localhost:15658
+pub const SERVER_ENV: &str = "ELEV_SERVER";
 
-#[derive(PartialEq)]
-enum ElevState {
+pub fn server_addr() -> String {
+    std::env::var(SERVER_ENV).unwrap_or_else(|_| ELEVATOR_ADDR.to_string())
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Behaviour {
+    #[default]
+    Idle,
     Moving,
-    Stationary,
-    DoorsOpen
+    DoorOpen,
 }
 
+// Everything the car is doing right now, published on a watch channel whenever it changes,
+// so any task can read the latest state without asking the elevator for it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ElevatorState {
+    pub floor: Option<u8>,      // Last floor the car was at, None until it has found one
+    pub between_floors: bool,   // Left `floor` and not at another one yet
+    pub direction: Direction,   // Motor direction
+    pub behaviour: Behaviour,
+    pub door_open: bool,
+    pub stopped: bool,          // Stop button held
+    pub obstructed: bool,
+}
 
-pub struct Elevator {
-    io: Elevio,
-    elev_state: Mutex<ElevState>,
-    door_state: bool,
-    pub last_floor: Mutex<Option<u8>>,
-    id: usize,
+// Sent by order management to the motor control
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ElevatorCommand {
+    GoTo(CallButton),           // Drive to the floor of the call and open the door there
+    Cancel,                     // Drop the target, a moving car stops at the next floor with the door shut
 }
 
-impl Elevator {
-    async fn init() -> Result<Elevator> {
+// Sent to the lamp task, by order management for cab lamps and by networking for hall lamps
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LampCommand {
+    pub call: CallButton,
+    pub on: bool,
+}
 
-        let elevator = Self {
-            io: Elevio::init("localhost:15657", NUM_FLOORS)?,
-            elev_state: Mutex::new(ElevState::Stationary),
-            door_state: false,
-            last_floor: Mutex::new(None),
-            id: 0,
-        };
 
-        Ok(elevator)
+pub struct Elevator {
+    io: Elevio,
+    state: watch::Sender<ElevatorState>,
+    clock: SharedClock,
+    layout: CarLayout,
+}
 
-        
+impl Elevator {
+    fn new(io: Elevio, clock: SharedClock, state: watch::Sender<ElevatorState>, layout: CarLayout) -> Elevator {
+        Self { io, state, clock, layout }
     }
 }
 
 
 
-pub async fn elevator_runner(floor_order_tx: UTx<CallButton>, floor_msg_tx: UTx<CallButton>, floor_cmd_rx: URx<CallButton>, elev_req_rx: URx<bool>, elev_resp_tx: UTx<u8>, floor_msg_light_rx: URx<(Order, bool)>) -> Result<()> {
+// Start the elevator tasks under the supervisor. Receivers are shared, so a restarted task picks up where the old one left off.
+// The state of the car is published on `state_tx`, button presses and served calls are sent on `order_tx`.
+// Only the buttons and lamps `layout` has on this car are used.
+#[allow(clippy::too_many_arguments)]
+pub async fn elevator_runner(supervisor: &mut Supervisor, clock: SharedClock, io: Elevio, layout: CarLayout, state_tx: watch::Sender<ElevatorState>, order_tx: UTx<OrderEvent>, command_rx: URx<ElevatorCommand>, lamp_rx: URx<LampCommand>) -> Result<()> {
 
     // Initialize elevator
-    let my_elev = Arc::new(Elevator::init().await?);
+    let buttons = layout.buttons();
+    let my_elev = Arc::new(Elevator::new(io, clock.clone(), state_tx, layout));
 
     let motor_control_elevio = my_elev.io.clone();
     let io_sensing_elevio = my_elev.io.clone();
-    let io_light_elevio = my_elev.io.clone();
     let poll_period = Duration::from_millis(25);
 
     // Create channels to elevator IO for motor control task
     let (floor_sensor_tx, floor_sensor_rx) = uc::<Option<u8>>();{
-        let elevator = motor_control_elevio.clone();
-        tokio::spawn(async move {
-            elevio::poll::floor_sensor(elevator, floor_sensor_tx, poll_period).await;
+        let (elevator, clock) = (motor_control_elevio.clone(), clock.clone());
+        supervisor.spawn("floor_sensor", Policy::critical(3), move || {
+            elevio::poll::floor_sensor(elevator.clone(), floor_sensor_tx.clone(), poll_period, clock.clone())
         });}
 
     // Create channels to elevator IO for io sensing task
     let (call_button_tx, call_button_rx) = uc::<elevio::poll::CallButton>();{
-        let elevator = io_sensing_elevio.clone();
-        tokio::spawn(async move {
-            elevio::poll::call_buttons(elevator, call_button_tx, poll_period).await;
+        let (elevator, clock) = (io_sensing_elevio.clone(), clock.clone());
+        supervisor.spawn("call_buttons", Policy::critical(3), move || {
+            elevio::poll::call_buttons(elevator.clone(), call_button_tx.clone(), poll_period, clock.clone(), buttons.clone())
         });}
 
-    
+    // Create channels to elevator IO for the stop button, handled by motor control
+    let (stop_tx, stop_rx) = uc::<bool>();{
+        let (elevator, clock) = (motor_control_elevio.clone(), clock.clone());
+        supervisor.spawn("stop_button", Policy::critical(3), move || {
+            elevio::poll::stop_button(elevator.clone(), stop_tx.clone(), poll_period, clock.clone())
+        });}
+
+    let command_rx = Arc::new(AsyncMutex::new(command_rx));
+    let floor_sensor_rx = Arc::new(AsyncMutex::new(floor_sensor_rx));
+    let stop_rx = Arc::new(AsyncMutex::new(stop_rx));
+    let call_button_rx = Arc::new(AsyncMutex::new(call_button_rx));
+    let lamp_rx = Arc::new(AsyncMutex::new(lamp_rx));
+
     // Start tasks
-    let motor_control_task = tokio::spawn({
-        let elev = Arc::clone(&my_elev);
-        async move {
-            elev.motor_control(floor_cmd_rx, floor_msg_tx, floor_sensor_rx).await;
+    supervisor.spawn("motor_control", Policy::critical(3), {
+        let (elev, order_tx) = (Arc::clone(&my_elev), order_tx.clone());
+        move || {
+            let (elev, order_tx) = (elev.clone(), order_tx.clone());
+            let (command_rx, floor_sensor_rx, stop_rx) = (command_rx.clone(), floor_sensor_rx.clone(), stop_rx.clone());
+            async move {
+                let (mut command_rx, mut floor_sensor_rx, mut stop_rx) = (command_rx.lock().await, floor_sensor_rx.lock().await, stop_rx.lock().await);
+                elev.motor_control(&mut command_rx, order_tx, &mut floor_sensor_rx, &mut stop_rx).await;
+            }
         }
     });
 
-    let io_sensing_task = tokio::spawn({
+    supervisor.spawn("io_sensing", Policy::critical(3), {
         let elev = Arc::clone(&my_elev);
-        async move {
-            elev.io_sensing(call_button_rx, floor_order_tx, elev_req_rx, elev_resp_tx).await;
+        move || {
+            let (elev, order_tx, call_button_rx) = (elev.clone(), order_tx.clone(), call_button_rx.clone());
+            async move {
+                elev.io_sensing(&mut *call_button_rx.lock().await, order_tx).await;
+            }
         }
     });
 
-    let io_light_task = tokio::spawn({
+    supervisor.spawn("io_light", Policy::best_effort(3), {
         let elev = Arc::clone(&my_elev);
-        async move {
-            elev.set_lights(floor_msg_light_rx).await;
+        move || {
+            let (elev, lamp_rx) = (elev.clone(), lamp_rx.clone());
+            async move {
+                elev.set_lights(&mut *lamp_rx.lock().await).await;
+            }
         }
     });
 
-    let _ = tokio::join!(motor_control_task, io_sensing_task, io_light_task);
     Ok(())
 
 }
 
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::building::Building;
+    use crate::clock::VirtualClock;
+    use crate::elevator::elevio::protocol::{CallType, Floor};
+    use crate::simulation::hardware::SimCar;
+
+    const FLOORS: u8 = 4;
+    const STEP: Duration = Duration::from_millis(10);
+
+    // One car on a simulated shaft, with the test standing in for order management
+    struct Rig {
+        clock: Arc<VirtualClock>,
+        car: SimCar,
+        state_rx: watch::Receiver<ElevatorState>,
+        order_rx: URx<OrderEvent>,
+        command_tx: UTx<ElevatorCommand>,
+    }
+
+    async fn start(floor: u8) -> (Rig, Supervisor) {
+        let clock = VirtualClock::new();
+        let car = SimCar::new(FLOORS, floor, clock.clone());
+        let io = Elevio::from_connection(Box::new(car.connect()), "test", FLOORS);
+        let layout = Arc::new(Building::standard(FLOORS)).car(1);
+        let (state_tx, state_rx) = watch::channel(ElevatorState::default());
+        let (order_tx, order_rx) = uc();
+        let (command_tx, command_rx) = uc();
+        let (_lamp_tx, lamp_rx) = uc();
+
+        let mut supervisor = Supervisor::new(clock.clone());
+        elevator_runner(&mut supervisor, clock.clone(), io, layout, state_tx, order_tx, command_rx, lamp_rx).await.unwrap();
+        (Rig { clock, car, state_rx, order_rx, command_tx }, supervisor)
+    }
+
+    impl Rig {
+        // Run in virtual time until the elevator sends an event or `limit` has passed
+        async fn next_event(&mut self, limit: Duration) -> Option<OrderEvent> {
+            let mut waited = Duration::ZERO;
+            while waited < limit {
+                if let Ok(event) = self.order_rx.try_recv() {
+                    return Some(event);
+                }
+                self.clock.advance(STEP);
+                waited += STEP;
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            None
+        }
+
+        // Serve `target` the way order management does, sending it again when asked to
+        async fn serve(&mut self, target: CallButton) {
+            self.command_tx.send(ElevatorCommand::GoTo(target.clone())).unwrap();
+            loop {
+                match self.next_event(Duration::from_secs(30)).await {
+                    Some(OrderEvent::Reissue) => self.command_tx.send(ElevatorCommand::GoTo(target.clone())).unwrap(),
+                    Some(OrderEvent::Served(served)) => return assert_eq!(served, target),
+                    other => panic!("expected {target:?} to be served, got {other:?}"),
+                }
+            }
+        }
+
+        async fn run_until(&mut self, done: impl Fn(&ElevatorState) -> bool) {
+            for _ in 0..3000 {
+                if done(&self.state_rx.borrow()) {
+                    return;
+                }
+                self.clock.advance(STEP);
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            panic!("never got there, the car is {:?}", *self.state_rx.borrow());
+        }
+    }
+
+    fn cab(floor: u8) -> CallButton {
+        CallButton { floor: Floor::new(floor, NUM_FLOORS).unwrap(), call: CallType::Cab }
+    }
+
+    #[tokio::test]
+    async fn a_trip_completes_when_motor_control_is_killed_on_the_way() {
+        let (mut rig, supervisor) = start(0).await;
+        let motor_control = supervisor.abort_handle("motor_control").unwrap();
+        tokio::spawn(supervisor.run());
+
+        rig.run_until(|state| state.floor == Some(0)).await;
+        rig.command_tx.send(ElevatorCommand::GoTo(cab(3))).unwrap();
+        rig.run_until(|state| state.floor == Some(1) && state.between_floors).await;
+        motor_control.abort();
+
+        // Asked for its target again, and only then does the trip end, at the right floor
+        match rig.next_event(Duration::from_secs(30)).await {
+            Some(OrderEvent::Reissue) => rig.serve(cab(3)).await,
+            other => panic!("expected the order to be asked for again, got {other:?}"),
+        }
+        assert_eq!(rig.car.floor(), Some(3));
+        assert!(!rig.car.is_moving());
+        assert_eq!(rig.car.take_violations(), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn a_restart_at_a_floor_leaves_the_car_standing() {
+        let (mut rig, supervisor) = start(0).await;
+        let motor_control = supervisor.abort_handle("motor_control").unwrap();
+        tokio::spawn(supervisor.run());
+
+        rig.run_until(|state| state.floor == Some(0)).await;
+        rig.serve(cab(2)).await;
+        motor_control.abort();
+
+        // Nothing to do, so nothing happens, where it used to drive off looking for a floor
+        assert!(matches!(rig.next_event(Duration::from_secs(5)).await, Some(OrderEvent::Reissue)));
+        assert!(rig.next_event(Duration::from_secs(5)).await.is_none());
+        assert_eq!(rig.car.floor(), Some(2));
+        assert!(!rig.car.is_moving());
+
+        // And it still takes orders
+        rig.serve(cab(0)).await;
+        assert_eq!(rig.car.floor(), Some(0));
+    }
+
+    #[tokio::test]
+    async fn a_cancelled_trip_ends_at_the_next_floor_with_the_door_shut() {
+        let (mut rig, supervisor) = start(0).await;
+        tokio::spawn(supervisor.run());
+
+        rig.run_until(|state| state.floor == Some(0)).await;
+        rig.command_tx.send(ElevatorCommand::GoTo(cab(3))).unwrap();
+        rig.run_until(|state| state.floor == Some(0) && state.between_floors).await;
+        rig.command_tx.send(ElevatorCommand::Cancel).unwrap();
+
+        assert!(rig.next_event(Duration::from_secs(10)).await.is_none());
+        assert_eq!(rig.car.floor(), Some(1));
+        assert!(!rig.car.is_moving());
+        assert!(!rig.state_rx.borrow().door_open);
+    }
+}
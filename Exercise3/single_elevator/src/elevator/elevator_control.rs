@@ -1,86 +1,139 @@
-use crate::elevator::{Elevator, elevio};
+use crate::elevator::{Behaviour, Elevator, ElevatorCommand, LampCommand, elevio};
 use tokio::sync::mpsc::{UnboundedReceiver as URx, UnboundedSender as UTx};
-use tokio::time::{sleep, Duration};
+use tokio::time::Duration;
 use crate::elevator::elevio::poll::CallButton as CallButton;
-use crate::order_management::Order;
+use crate::elevator::elevio::protocol::Direction;
+use crate::order_management::OrderEvent;
+use tracing::{debug, error, info, warn};
+
+const DOOR_OPEN_TIME: Duration = Duration::from_secs(3);
+const OBSTRUCTION_POLL: Duration = Duration::from_millis(25);
 
 impl Elevator {
 
     // Go to a floor, cannot be called if not at a floor
-    pub async fn motor_control(&self, mut floor_cmd_rx: URx<CallButton>, floor_msg_tx: UTx<CallButton>, mut floor_sensor_rx: URx<Option<u8>>) {
+    pub async fn motor_control(&self, command_rx: &mut URx<ElevatorCommand>, order_tx: UTx<OrderEvent>, floor_sensor_rx: &mut URx<Option<u8>>, stop_rx: &mut URx<bool>) {
 
-        // If not at a floor, go to start floor
-        match URx::try_recv(&mut floor_sensor_rx) {
-            Ok(Some(floor)) => {
-                *self.last_floor.lock().unwrap() = Some(floor);
+        let mut direction: Option<Direction> = Some(Direction::Stop);
+        let mut target_call: Option<CallButton> = None;
+        let mut between_floors: bool = false;
+        let mut stopped: bool = false;      // Stop button held, the motor stays off until it is released
+
+        // After a restart the published state still says where the car is and which way the motor
+        // was told to go, only the target is lost, so order management is asked for it again.
+        // The floor sensor only reports changes, so a car standing at a floor has nothing to read.
+        let restored = *self.state.borrow();
+        if let Some(floor) = restored.floor {
+            info!(floor, between_floors = restored.between_floors, direction = %restored.direction, "Restarted, resuming from the last known state");
+            direction = Some(restored.direction);
+            between_floors = restored.between_floors;
+            stopped = restored.stopped;
+
+            // A panic stops the car, so drive it again the way the state says it was going
+            self.io.stop_button_light(stopped);
+            if !stopped && restored.direction != Direction::Stop {
+                self.set_motor(restored.direction);
             }
-            _ => {
-                self.io.motor_direction(elevio::elev::DIRN_UP);
-                loop {
-                    if let Some(floor) = floor_sensor_rx.recv().await.unwrap() {
-                        *self.last_floor.lock().unwrap() = Some(floor);
-                        self.io.motor_direction(elevio::elev::DIRN_STOP);
-                        break;
+            let _ = order_tx.send(OrderEvent::Reissue);
+        }
+
+        // If not at a floor, go to start floor
+        else {
+            match URx::try_recv(floor_sensor_rx) {
+                Ok(Some(floor)) => {
+                    self.set_floor(floor);
+                }
+                _ => {
+                    info!("Not at a floor, moving up to the nearest one");
+                    self.set_motor(Direction::Up);
+                    loop {
+                        if let Some(floor) = floor_sensor_rx.recv().await.unwrap() {
+                            self.set_floor(floor);
+                            self.set_motor(Direction::Stop);
+                            info!(floor, "Arrived at start floor");
+                            break;
+                        }
                     }
                 }
             }
         }
-        
-        let mut direction: Option<u8> = Some(elevio::elev::DIRN_STOP);
-        let mut target_call: CallButton = CallButton { floor: 0, call: 0 };
-        let mut between_floors: bool = false;
 
         loop {
             tokio::select! {
                 biased;
-                
-                // Recieved new target floor
-                Some(call) = floor_cmd_rx.recv() => {
-                    target_call = call;
-                    let last_floor = self.last_floor.lock().unwrap().unwrap();
-
-                    // Update direction of travel, if necessary
-                    match find_direction(last_floor, between_floors, target_call.floor, direction) {
-                        Some(dir) => {
-                            direction = Some(dir);
-                            self.io.motor_direction(dir);
-                            let new_state = match dir {
-                                elevio::elev::DIRN_STOP => crate::elevator::ElevState::Stationary,
-                                _ => crate::elevator::ElevState::Moving,
-                            };
-                            *self.elev_state.lock().unwrap() = new_state;
-                        },
-
-                        // If there is no change in direction, and direction is stop, send order complete message
-                        None => {
-                            if direction == Some(elevio::elev::DIRN_STOP) {
-                                println!("Recieved order to current floor, when stopped");
-                                // TODO: Wait 3 seconds, open doors stuff, THEN send order complete message
-                                sleep(Duration::from_secs(3)).await;
-                                let _ = floor_msg_tx.send(target_call.clone());
-                            }
-                        },
+
+                // Stop button pressed or released, resume the way we were going once released
+                Some(pressed) = stop_rx.recv() => {
+                    stopped = pressed;
+                    self.state.send_modify(|state| state.stopped = pressed);
+                    self.io.stop_button_light(pressed);
+                    if pressed {
+                        warn!("Stop button pressed, motor halted");
+                        self.set_motor(Direction::Stop);
+                    } else if let Some(dir) = direction {
+                        info!(direction = %dir, "Stop button released");
+                        self.set_motor(dir);
                     }
                 }
+                
+                // Recieved new target floor, or lost the target to another car
+                Some(command) = command_rx.recv() => match command {
+                    ElevatorCommand::Cancel => {
+                        debug!(target = ?target_call, "Target cancelled");
+                        target_call = None;
+                    }
+                    ElevatorCommand::GoTo(call) => {
+                        debug!(target.floor = %call.floor, target.call = %call.call, "New target");
+                        target_call = Some(call.clone());
+                        let Some(last_floor) = self.state.borrow().floor else { continue };
+
+                        // Update direction of travel, if necessary
+                        match find_direction(last_floor, between_floors, call.floor.get(), direction) {
+                            Some(dir) => {
+                                direction = Some(dir);
+                                if !stopped {
+                                    self.set_motor(dir);
+                                }
+                                info!(direction = %dir, state = ?self.state.borrow().behaviour, last_floor, "Changing direction");
+                            },
+
+                            // If there is no change in direction, and direction is stop, send order complete message
+                            None => {
+                                if direction == Some(Direction::Stop) {
+                                    info!(floor = last_floor, "Recieved order to current floor, when stopped");
+                                    // TODO: Wait 3 seconds, open doors stuff, THEN send order complete message
+                                    self.hold_door().await;
+                                    let _ = order_tx.send(OrderEvent::Served(call));
+                                }
+                            },
+                        }
+                    }
+                },
 
                 // Recieved new floor sensor measurement
                 Some(floor_opt) = floor_sensor_rx.recv() => {
                     if let Some(floor) = floor_opt {
                         between_floors = false;
-                        *self.last_floor.lock().unwrap() = Some(floor);
+                        self.set_floor(floor);
 
-                        if floor == target_call.floor {
-                            direction = Some(elevio::elev::DIRN_STOP);
-                            self.io.motor_direction(elevio::elev::DIRN_STOP);
-                            *self.elev_state.lock().unwrap() = crate::elevator::ElevState::Stationary;
+                        if let Some(target) = target_call.clone().filter(|target| target.floor.get() == floor) {
+                            direction = Some(Direction::Stop);
+                            self.set_motor(Direction::Stop);
+                            info!(floor, state = ?self.state.borrow().behaviour, "Arrived at target floor");
 
                             // TODO: Wait 3 seconds, open doors stuff, THEN send order complete message
-                            sleep(Duration::from_secs(3)).await;
-                            let _ = floor_msg_tx.send(target_call.clone());
+                            self.hold_door().await;
+                            let _ = order_tx.send(OrderEvent::Served(target));
+                        }
+                        else if target_call.is_none() && direction.is_some_and(|dir| dir != Direction::Stop) {
+                            direction = Some(Direction::Stop);
+                            self.set_motor(Direction::Stop);
+                            info!(floor, "No target, stopping with the door shut");
                         }
                     }
                     else {
                         between_floors = true;
+                        self.leave_floor();
                     }
                 }
                 else => (),
@@ -88,28 +141,65 @@ impl Elevator {
         }
     }
 
-    pub async fn io_sensing(&self, mut call_rx: URx<elevio::poll::CallButton>, floor_order_tx: UTx<CallButton>, mut elev_req_rx: URx<bool>, elev_resp_tx: UTx<u8>) {
-        loop {
-            tokio::select! {
-                
-                Some(call) = call_rx.recv() => {
-                    let _ = floor_order_tx.send(call);
-                }
-
-                Some(_) = elev_req_rx.recv() => {
-                    let _ = elev_resp_tx.send(self.last_floor.lock().unwrap().unwrap());
-                }
+    // Keep the door open for DOOR_OPEN_TIME, and then for as long as it is obstructed
+    async fn hold_door(&self) {
+        self.set_door(true);
+        self.clock.sleep(DOOR_OPEN_TIME).await;
+        if self.io.obstruction() {
+            warn!("Door obstructed, keeping it open");
+            self.state.send_modify(|state| state.obstructed = true);
+            while self.io.obstruction() {
+                self.clock.sleep(OBSTRUCTION_POLL).await;
             }
+            self.state.send_modify(|state| state.obstructed = false);
+            info!("Obstruction cleared");
+        }
+        self.set_door(false);
+    }
+
+    // The hardware outputs go through these, so the published state always shows what the car was told
+    fn set_motor(&self, dir: Direction) {
+        self.io.motor_direction(dir);
+        self.state.send_modify(|state| {
+            state.direction = dir;
+            state.behaviour = if dir == Direction::Stop { Behaviour::Idle } else { Behaviour::Moving };
+        });
+    }
+
+    fn set_door(&self, open: bool) {
+        self.io.door_light(open);
+        self.state.send_modify(|state| {
+            state.door_open = open;
+            state.behaviour = if open { Behaviour::DoorOpen } else { Behaviour::Idle };
+        });
+    }
+
+    fn set_floor(&self, floor: u8) {
+        self.state.send_if_modified(|state| {
+            let changed = state.floor != Some(floor) || state.between_floors;
+            state.floor = Some(floor);
+            state.between_floors = false;
+            changed
+        });
+    }
+
+    fn leave_floor(&self) {
+        self.state.send_if_modified(|state| !std::mem::replace(&mut state.between_floors, true));
+    }
+
+    pub async fn io_sensing(&self, call_rx: &mut URx<elevio::poll::CallButton>, order_tx: UTx<OrderEvent>) {
+        while let Some(call) = call_rx.recv().await {
+            let _ = order_tx.send(OrderEvent::Pressed(call));
         }
     }
     
-    pub async fn set_lights(&self, mut floor_msg_rx: URx<(Order, bool)>) {
-        loop {
-            if let Some((order, on)) = floor_msg_rx.recv().await {
-                if order.elevator == self.id {
-                    self.io.call_button_light(order.call.floor, order.call.call, on);
-                }
+    pub async fn set_lights(&self, lamp_rx: &mut URx<LampCommand>) {
+        while let Some(LampCommand { call, on }) = lamp_rx.recv().await {
+            if let Err(e) = self.layout.check(&call) {
+                error!(floor = %call.floor, call = %call.call, error = %e, "Lamp command for a button this car does not have");
+                continue;
             }
+            self.io.call_button_light(call.floor, call.call, on);
         }
     }
 }
@@ -117,30 +207,30 @@ impl Elevator {
 
 // ---------- PURE FUNCTIONS ----------
 
-fn find_direction(last_floor: u8, between_floors: bool, target_floor: u8, direction: Option<u8>) -> Option<u8> {
+fn find_direction(last_floor: u8, between_floors: bool, target_floor: u8, direction: Option<Direction>) -> Option<Direction> {
 
     // Set direction to appropriate direction, unless it is already set
     if last_floor < target_floor {
         match direction {
-            Some(elevio::elev::DIRN_UP) => None,
-            _ => Some(elevio::elev::DIRN_UP),
+            Some(Direction::Up) => None,
+            _ => Some(Direction::Up),
         }
     } else if last_floor > target_floor {
         match direction {
-            Some(elevio::elev::DIRN_DOWN) => None,
-            _ => Some(elevio::elev::DIRN_DOWN),
+            Some(Direction::Down) => None,
+            _ => Some(Direction::Down),
         }
     } else {
         match direction {
-            Some(elevio::elev::DIRN_STOP) => None,
+            Some(Direction::Stop) => None,
             _ => {
                 if between_floors == false{
-                    Some(elevio::elev::DIRN_STOP)
+                    Some(Direction::Stop)
                 } else {
-                    if direction == Some(elevio::elev::DIRN_UP) {
-                        Some(elevio::elev::DIRN_DOWN)
+                    if direction == Some(Direction::Up) {
+                        Some(Direction::Down)
                     } else  {
-                        Some(elevio::elev::DIRN_UP)
+                        Some(Direction::Up)
                     }
                 }
             }
@@ -1,2 +1,3 @@
 pub mod elev;
 pub mod poll;
+pub mod protocol;
@@ -3,100 +3,238 @@
 use std::fmt;
 use std::io::*;
 use std::net::TcpStream;
-use std::sync::*;
+use std::sync::{*, atomic::{AtomicBool, Ordering}};
+use tokio::sync::mpsc::UnboundedSender as UTx;
+use tracing::{trace, warn};
+
+use super::protocol::{CallType, Command, Direction, Floor, Reply};
+
+// Anything speaking the elevator server protocol, the TCP connection to the
+// server or a simulated car
+pub trait Connection: Read + Write + Send + fmt::Debug {}
+
+impl<T: Read + Write + Send + fmt::Debug> Connection for T {}
 
 #[derive(Clone, Debug)]
 pub struct Elevio {
-    socket: Arc<Mutex<TcpStream>>,
+    socket: Arc<Mutex<Box<dyn Connection>>>,
+    name: String,
     pub num_floors: u8,
+    simulated: Arc<SimulatedInputs>,
+    halted: Arc<AtomicBool>,        // Set by `halt`, the motor stays stopped from then on
+    outputs: Option<UTx<Command>>,  // Every output command is copied here, for the safety monitor
 }
 
-pub const HALL_UP: u8 = 0;
-pub const HALL_DOWN: u8 = 1;
-pub const CAB: u8 = 2;
-
-pub const DIRN_DOWN: u8 = u8::MAX;
-pub const DIRN_STOP: u8 = 0;
-pub const DIRN_UP: u8 = 1;
+// Switches set by the operator, read as if they were held down on the panel
+#[derive(Debug, Default)]
+struct SimulatedInputs {
+    stop: AtomicBool,
+    obstruction: AtomicBool,
+}
 
 impl Elevio {
     pub fn init(addr: &str, num_floors: u8) -> Result<Elevio> {
-        Ok(Self {
-            socket: Arc::new(Mutex::new(TcpStream::connect(addr)?)),
-            num_floors
-        })
+        Ok(Self::from_connection(Box::new(TcpStream::connect(addr)?), addr, num_floors))
     }
 
-    pub fn motor_direction(&self, dirn: u8) {
-        let buf = [1, dirn, 0, 0];
-        let mut sock = self.socket.lock().unwrap();
-        sock.write(&buf).unwrap();
+    pub fn from_connection(connection: Box<dyn Connection>, name: &str, num_floors: u8) -> Elevio {
+        Self {
+            socket: Arc::new(Mutex::new(connection)),
+            name: name.to_string(),
+            num_floors,
+            simulated: Arc::default(),
+            halted: Arc::default(),
+            outputs: None,
+        }
     }
 
-    pub fn call_button_light(&self, floor: u8, call: u8, on: bool) {
-        let buf = [2, call, floor, on as u8];
-        let mut sock = self.socket.lock().unwrap();
-        sock.write(&buf).unwrap();
+    // Copy every output command sent through this handle, and its clones, to `tx`
+    pub fn with_output_tap(mut self, tx: UTx<Command>) -> Elevio {
+        self.outputs = Some(tx);
+        self
     }
 
-    pub fn floor_indicator(&self, floor: u8) {
-        let buf = [3, floor, 0, 0];
-        let mut sock = self.socket.lock().unwrap();
-        sock.write(&buf).unwrap();
+    // `floor` as a floor of this elevator, if it has one that high
+    pub fn floor(&self, floor: u8) -> Option<Floor> {
+        Floor::new(floor, self.num_floors)
+    }
+
+    // Stop the car and keep the motor stopped for the rest of the run, whatever it is told.
+    // Waits for a request another task has in flight, the stop must get through.
+    pub fn halt(&self) {
+        self.halted.store(true, Ordering::Relaxed);
+        let mut sock = self.socket.lock().unwrap_or_else(PoisonError::into_inner);
+        write_stop(&mut sock);
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted.load(Ordering::Relaxed)
+    }
+
+    pub fn simulate_stop(&self, on: bool) {
+        self.simulated.stop.store(on, Ordering::Relaxed);
+    }
+
+    pub fn simulate_obstruction(&self, on: bool) {
+        self.simulated.obstruction.store(on, Ordering::Relaxed);
+    }
+
+    pub fn motor_direction(&self, direction: Direction) {
+        let direction = if self.is_halted() && direction != Direction::Stop {
+            warn!(%direction, "Car is halted, keeping the motor stopped");
+            Direction::Stop
+        } else {
+            direction
+        };
+        self.send(Command::MotorDirection(direction));
+    }
+
+    pub fn call_button_light(&self, floor: Floor, call: CallType, on: bool) {
+        self.send(Command::CallButtonLight { call, floor, on });
+    }
+
+    pub fn floor_indicator(&self, floor: Floor) {
+        self.send(Command::FloorIndicator(floor));
     }
 
     pub fn door_light(&self, on: bool) {
-        let buf = [4, on as u8, 0, 0];
-        let mut sock = self.socket.lock().unwrap();
-        sock.write(&buf).unwrap();
+        self.send(Command::DoorLight(on));
     }
 
     pub fn stop_button_light(&self, on: bool) {
-        let buf = [5, on as u8, 0, 0];
-        let mut sock = self.socket.lock().unwrap();
-        sock.write(&buf).unwrap();
+        self.send(Command::StopButtonLight(on));
     }
 
-    pub fn call_button(&self, floor: u8, call: u8) -> bool {
-        let mut buf = [6, call, floor, 0];
-        let mut sock = self.socket.lock().unwrap();
-        sock.write(&mut buf).unwrap();
-        sock.read(&mut buf).unwrap();
-        buf[1] != 0
+    pub fn call_button(&self, floor: Floor, call: CallType) -> bool {
+        matches!(self.ask(Command::CallButton { call, floor }), Some(Reply::CallButton(true)))
     }
 
-    pub fn floor_sensor(&self) -> Option<u8> {
-        let mut buf = [7, 0, 0, 0];
-        let mut sock = self.socket.lock().unwrap();
-        sock.write(&buf).unwrap();
-        sock.read(&mut buf).unwrap();
-        if buf[1] != 0 {
-            Some(buf[2])
-        } else {
-            None
+    pub fn floor_sensor(&self) -> Option<Floor> {
+        match self.ask(Command::FloorSensor) {
+            Some(Reply::FloorSensor(floor)) => floor,
+            _ => None,
         }
     }
 
     pub fn stop_button(&self) -> bool {
-        let mut buf = [8, 0, 0, 0];
-        let mut sock = self.socket.lock().unwrap();
-        sock.write(&buf).unwrap();
-        sock.read(&mut buf).unwrap();
-        buf[1] != 0
+        matches!(self.ask(Command::StopButton), Some(Reply::StopButton(true))) || self.simulated.stop.load(Ordering::Relaxed)
     }
 
     pub fn obstruction(&self) -> bool {
-        let mut buf = [9, 0, 0, 0];
+        matches!(self.ask(Command::Obstruction), Some(Reply::Obstruction(true))) || self.simulated.obstruction.load(Ordering::Relaxed)
+    }
+
+    fn send(&self, command: Command) {
+        trace!(?command, "Output");
+        let mut sock = self.socket.lock().unwrap();
+        sock.write_all(&command.encode()).unwrap();
+        drop(sock);
+        if let Some(tx) = &self.outputs {
+            let _ = tx.send(command);
+        }
+    }
+
+    // None if the elevator answers with something that does not fit the protocol
+    fn ask(&self, command: Command) -> Option<Reply> {
+        let mut frame = [0; 4];
         let mut sock = self.socket.lock().unwrap();
-        sock.write(&buf).unwrap();
-        sock.read(&mut buf).unwrap();
-        buf[1] != 0
+        sock.write_all(&command.encode()).unwrap();
+        sock.read_exact(&mut frame).unwrap();
+        drop(sock);
+        Reply::decode(command, frame, self.num_floors)
+            .inspect_err(|e| warn!(?command, error = %e, "Invalid reply from the elevator"))
+            .ok()
+    }
+
+    // Stop the motor and show a defined lamp state without ever blocking or panicking,
+    // safe to call from a panic hook while another thread holds the socket lock
+    pub fn emergency_stop(&self) {
+        let mut sock = match self.socket.try_lock() {
+            Ok(sock) => sock,
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) => return,
+        };
+        write_stop(&mut sock);
+    }
+}
+
+// Motor stopped, door lamp off and stop lamp on, ignoring write errors
+fn write_stop(sock: &mut Box<dyn Connection>) {
+    for command in [Command::MotorDirection(Direction::Stop), Command::DoorLight(false), Command::StopButtonLight(true)] {
+        let _ = sock.write_all(&command.encode());
     }
+    let _ = sock.flush();
 }
 
 impl fmt::Display for Elevio {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let addr = self.socket.lock().unwrap().peer_addr().unwrap();
-        write!(f, "Elevio@{}({})", addr, self.num_floors)
+        write!(f, "Elevio@{}({})", self.name, self.num_floors)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{thread, time::Duration};
+
+    // Keeps everything written to it, and answers every request with zeros
+    #[derive(Debug, Clone, Default)]
+    struct Recording(Arc<Mutex<Vec<u8>>>);
+
+    impl Read for Recording {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            buf.fill(0);
+            Ok(buf.len())
+        }
+    }
+
+    impl Write for Recording {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn halt_waits_for_a_request_in_flight_and_stops_the_motor() {
+        let written = Recording::default();
+        let io = Elevio::from_connection(Box::new(written.clone()), "test", 4);
+
+        // Another task is half way through a request on the shared socket
+        let busy = io.socket.lock().unwrap();
+        let halting = thread::spawn({
+            let io = io.clone();
+            move || io.halt()
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(written.0.lock().unwrap().is_empty());
+        assert!(io.is_halted());
+
+        drop(busy);
+        halting.join().unwrap();
+        assert_eq!(written.0.lock().unwrap()[..4], Command::MotorDirection(Direction::Stop).encode());
+
+        // And the motor stays stopped
+        io.motor_direction(Direction::Up);
+        assert_eq!(written.0.lock().unwrap()[12..], Command::MotorDirection(Direction::Stop).encode());
+    }
+
+    #[test]
+    fn emergency_stop_never_blocks() {
+        let written = Recording::default();
+        let io = Elevio::from_connection(Box::new(written.clone()), "test", 4);
+
+        let busy = io.socket.lock().unwrap();
+        io.emergency_stop();
+        drop(busy);
+        assert!(written.0.lock().unwrap().is_empty());
+
+        io.emergency_stop();
+        assert_eq!(written.0.lock().unwrap().len(), 12);
     }
 }
@@ -1,33 +1,126 @@
+use std::collections::BTreeSet;
 use tokio::sync::mpsc;
 use tokio::time;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
 
 use super::elev;
+use super::protocol::{CallType, Floor};
+use crate::clock::SharedClock;
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub struct CallButton {
-    pub floor: u8,
-    pub call: u8,
+    pub floor: Floor,
+    pub call: CallType,
 }
 
+// Only `buttons` are polled, the ones the building has on this car
 pub async fn call_buttons(
     elev: elev::Elevio,
     ch: mpsc::UnboundedSender<CallButton>,
     period: time::Duration,
+    clock: SharedClock,
+    buttons: Vec<CallButton>,
 ) {
-    let mut prev = vec![[false; 3]; elev.num_floors.into()];
+    let mut prev = vec![false; buttons.len()];
     loop {
-        for f in 0..elev.num_floors {
-            for c in 0..3 {
-                let v = elev.call_button(f, c);
-                if v && prev[f as usize][c as usize] != v {
-                    if ch.send(CallButton { floor: f, call: c }).is_err() {
-                        return;
-                    }
+        for (button, prev) in buttons.iter().zip(prev.iter_mut()) {
+            let v = elev.call_button(button.floor, button.call);
+            if v && *prev != v {
+                debug!(floor = %button.floor, call = %button.call, "Call button pressed");
+                if ch.send(button.clone()).is_err() {
+                    return;
                 }
-                prev[f as usize][c as usize] = v;
             }
+            *prev = v;
         }
-        time::sleep(period).await;
+        clock.sleep(period).await;
+    }
+}
+
+// Number of polls in a row a new floor sensor reading must be seen before it is passed on
+pub const FLOOR_SAMPLES_ENV: &str = "ELEV_FLOOR_SAMPLES";
+
+const DEFAULT_FLOOR_SAMPLES: u32 = 2;
+
+// Filters the floor sensor, so a reading is passed on only once it has been seen `samples`
+// polls in a row, and never when it skips a floor or is not a floor at all.
+// A fast car may pass a floor in fewer polls than that, so a floor counts as reached for
+// the skip check as soon as it has been read at all, confirmed or not.
+pub struct FloorFilter {
+    samples: u32,
+    num_floors: u8,
+    passed: Option<Option<u8>>,             // Last reading passed on, None before the first
+    last_floor: Option<u8>,                 // Last floor passed on
+    glimpsed: BTreeSet<u8>,                 // Floors read since `last_floor`, without being passed on
+    candidate: Option<(Option<u8>, u32)>,   // New reading, and how many polls in a row it was seen
+    rejected: Option<u8>,                   // Impossible reading last warned about, to warn once while it lasts
+}
+
+impl FloorFilter {
+    pub fn new(num_floors: u8, samples: u32) -> FloorFilter {
+        FloorFilter { samples: samples.max(1), num_floors, passed: None, last_floor: None, glimpsed: BTreeSet::new(), candidate: None, rejected: None }
+    }
+
+    pub fn from_env(num_floors: u8) -> FloorFilter {
+        let samples = std::env::var(FLOOR_SAMPLES_ENV).ok().and_then(|s| s.parse().ok()).unwrap_or(DEFAULT_FLOOR_SAMPLES);
+        FloorFilter::new(num_floors, samples)
+    }
+
+    // The reading to pass on, if `reading` completes one. The first reading is passed on
+    // right away, the car is standing still when polling starts.
+    pub fn sample(&mut self, reading: Option<u8>) -> Option<Option<u8>> {
+        if let Some(floor) = reading {
+            let skipped = self.last_floor.is_some_and(|last| {
+                std::iter::once(last).chain(self.glimpsed.iter().copied()).all(|reached| floor.abs_diff(reached) > 1)
+            });
+            if floor >= self.num_floors || skipped {
+                if self.rejected != Some(floor) {
+                    warn!(floor, last_floor = ?self.last_floor, "Impossible floor sensor reading ignored");
+                    self.rejected = Some(floor);
+                }
+                return None;
+            }
+            if self.last_floor != Some(floor) {
+                self.glimpsed.insert(floor);
+            }
+        }
+        self.rejected = None;
+
+        let Some(passed) = self.passed else {
+            return self.pass(reading);
+        };
+        if reading == passed {
+            if let Some((glitch, seen)) = self.candidate.take() {
+                warn!(reading = ?glitch, polls = seen, "Floor sensor glitch ignored");
+            }
+            return None;
+        }
+        let seen = match self.candidate {
+            Some((candidate, seen)) if candidate == reading => seen + 1,
+            Some((glitch, seen)) => {
+                warn!(reading = ?glitch, polls = seen, "Floor sensor glitch ignored");
+                1
+            }
+            None => 1,
+        };
+        self.candidate = Some((reading, seen));
+        if seen >= self.samples {
+            return self.pass(reading);
+        }
+        None
+    }
+
+    fn pass(&mut self, reading: Option<u8>) -> Option<Option<u8>> {
+        let first = self.passed.is_none();
+        self.passed = Some(reading);
+        if reading.is_some() {
+            self.last_floor = reading;
+            self.glimpsed.clear();
+        }
+        self.candidate = None;
+        // Starting between floors is no news, the car is not known to be anywhere yet
+        (!first || reading.is_some()).then_some(reading)
     }
 }
 
@@ -35,17 +128,17 @@ pub async fn floor_sensor(
     elev: elev::Elevio,
     ch: mpsc::UnboundedSender<Option<u8>>,
     period: time::Duration,
+    clock: SharedClock,
 ) {
-    let mut prev: Option<u8> = None;
+    let mut filter = FloorFilter::from_env(elev.num_floors);
     loop {
-        let current = elev.floor_sensor();
-        if current != prev {
+        if let Some(current) = filter.sample(elev.floor_sensor().map(|floor| floor.get())) {
+            debug!(floor = ?current, "Floor sensor");
             if ch.send(current).is_err() {
                 return;
             }
-            prev = current;
         }
-        time::sleep(period).await;
+        clock.sleep(period).await;
     }
 }
 
@@ -53,17 +146,19 @@ pub async fn stop_button(
     elev: elev::Elevio,
     ch: mpsc::UnboundedSender<bool>,
     period: time::Duration,
+    clock: SharedClock,
 ) {
     let mut prev = false;
     loop {
         let v = elev.stop_button();
         if prev != v {
+            debug!(pressed = v, "Stop button");
             if ch.send(v).is_err() {
                 return;
             }
             prev = v;
         }
-        time::sleep(period).await;
+        clock.sleep(period).await;
     }
 }
 
@@ -71,16 +166,76 @@ pub async fn obstruction(
     elev: elev::Elevio,
     ch: mpsc::UnboundedSender<bool>,
     period: time::Duration,
+    clock: SharedClock,
 ) {
     let mut prev = false;
     loop {
         let v = elev.obstruction();
         if prev != v {
+            debug!(active = v, "Obstruction");
             if ch.send(v).is_err() {
                 return;
             }
             prev = v;
         }
-        time::sleep(period).await;
+        clock.sleep(period).await;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Feed `readings` in order and collect what is passed on
+    fn passed(filter: &mut FloorFilter, readings: &[Option<u8>]) -> Vec<Option<u8>> {
+        readings.iter().filter_map(|reading| filter.sample(*reading)).collect()
+    }
+
+    #[test]
+    fn the_first_floor_is_passed_on_right_away() {
+        assert_eq!(passed(&mut FloorFilter::new(4, 3), &[Some(2)]), [Some(2)]);
+
+        // Starting between floors is not passed on, the floor found after it is as usual
+        assert_eq!(passed(&mut FloorFilter::new(4, 3), &[None, Some(0), Some(0)]), []);
+        assert_eq!(passed(&mut FloorFilter::new(4, 3), &[None, Some(0), Some(0), Some(0)]), [Some(0)]);
+    }
+
+    #[test]
+    fn a_reading_is_passed_on_once_seen_enough_polls_in_a_row() {
+        let mut filter = FloorFilter::new(4, 3);
+        assert_eq!(passed(&mut filter, &[Some(1), None, None]), [Some(1)]);
+        assert_eq!(passed(&mut filter, &[None]), [None]);
+        assert_eq!(passed(&mut filter, &[Some(2), Some(2), Some(2), Some(2)]), [Some(2)]);
+    }
+
+    #[test]
+    fn glitches_are_ignored() {
+        let mut filter = FloorFilter::new(4, 2);
+        assert_eq!(passed(&mut filter, &[Some(1), None, Some(1), Some(2), Some(1), Some(1)]), [Some(1)]);
+
+        // Nor do two different glitches add up
+        assert_eq!(passed(&mut filter, &[None, Some(2), None, Some(1)]), []);
+    }
+
+    #[test]
+    fn a_skipped_floor_or_no_floor_at_all_is_ignored() {
+        let mut filter = FloorFilter::new(4, 2);
+        assert_eq!(passed(&mut filter, &[Some(0), None, None]), [Some(0), None]);
+        assert_eq!(passed(&mut filter, &[Some(2), Some(2), Some(2), Some(7), Some(7)]), []);
+        assert_eq!(passed(&mut filter, &[Some(1), Some(1)]), [Some(1)]);
+    }
+
+    #[test]
+    fn a_floor_passed_too_fast_to_confirm_still_counts_as_reached() {
+        let mut filter = FloorFilter::new(4, 2);
+        assert_eq!(passed(&mut filter, &[Some(0), None, None]), [Some(0), None]);
+
+        // Floors 1 and 2 are only seen for one poll each
+        let readings = [Some(1), None, None, Some(2), None, None, Some(3), Some(3)];
+        assert_eq!(passed(&mut filter, &readings), [Some(3)]);
+
+        // Once there, the floors passed on the way no longer count
+        assert_eq!(passed(&mut filter, &[Some(1), Some(1), Some(2), Some(2)]), [Some(2)]);
     }
 }
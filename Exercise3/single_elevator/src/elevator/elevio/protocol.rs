@@ -0,0 +1,382 @@
+use std::{fmt, io};
+use serde::{Deserialize, Serialize};
+
+// The elevator server protocol: every command is a 4 byte frame, [kind, a, b, c], and the
+// commands that read something are answered with a 4 byte frame of the same kind.
+//
+//   1  motor direction       [1, direction, 0, 0]
+//   2  call button lamp      [2, call, floor, on]
+//   3  floor indicator       [3, floor, 0, 0]
+//   4  door lamp             [4, on, 0, 0]
+//   5  stop button lamp      [5, on, 0, 0]
+//   6  call button           [6, call, floor, 0]   -> [6, pressed, 0, 0]
+//   7  floor sensor          [7, 0, 0, 0]          -> [7, at floor, floor, 0]
+//   8  stop button           [8, 0, 0, 0]          -> [8, pressed, 0, 0]
+//   9  obstruction switch    [9, 0, 0, 0]          -> [9, active, 0, 0]
+//
+// Directions, call types and floors only exist as the types below, so a frame built from
+// them is always valid. Frames coming in are checked when decoded.
+
+pub type Frame = [u8; 4];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(into = "u8", try_from = "u8")]
+pub enum Direction {
+    Down,
+    #[default]
+    Stop,
+    Up,
+}
+
+// Serialized as the number the protocol uses, so saved orders and world views keep their format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(into = "u8", try_from = "u8")]
+pub enum CallType {
+    HallUp,
+    HallDown,
+    Cab,
+}
+
+// A floor of a building with a known number of floors. Stored and sent as the plain number;
+// a floor read back that way is only as good as its source, so calls are checked against
+// the car's layout before they are acted on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Floor(u8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    MotorDirection(Direction),
+    CallButtonLight { call: CallType, floor: Floor, on: bool },
+    FloorIndicator(Floor),
+    DoorLight(bool),
+    StopButtonLight(bool),
+    CallButton { call: CallType, floor: Floor },
+    FloorSensor,
+    StopButton,
+    Obstruction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reply {
+    CallButton(bool),
+    FloorSensor(Option<Floor>),
+    StopButton(bool),
+    Obstruction(bool),
+}
+
+impl Direction {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Direction::Down => u8::MAX,
+            Direction::Stop => 0,
+            Direction::Up => 1,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Direction> {
+        match byte {
+            u8::MAX => Some(Direction::Down),
+            0 => Some(Direction::Stop),
+            1 => Some(Direction::Up),
+            _ => None,
+        }
+    }
+}
+
+impl CallType {
+    pub const ALL: [CallType; 3] = [CallType::HallUp, CallType::HallDown, CallType::Cab];
+    pub const HALL: [CallType; 2] = [CallType::HallUp, CallType::HallDown];
+
+    pub fn to_byte(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_byte(byte: u8) -> Option<CallType> {
+        CallType::ALL.get(byte as usize).copied()
+    }
+
+    // For tables indexed by call type, like the lamps of a floor
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    pub fn is_hall(self) -> bool {
+        self != CallType::Cab
+    }
+
+    // The direction a hall call asks to go in
+    pub fn direction(self) -> Option<Direction> {
+        match self {
+            CallType::HallUp => Some(Direction::Up),
+            CallType::HallDown => Some(Direction::Down),
+            CallType::Cab => None,
+        }
+    }
+}
+
+impl Floor {
+    pub const fn new(floor: u8, num_floors: u8) -> Option<Floor> {
+        if floor < num_floors { Some(Floor(floor)) } else { None }
+    }
+
+    pub fn get(self) -> u8 {
+        self.0
+    }
+}
+
+impl Command {
+    pub fn encode(self) -> Frame {
+        match self {
+            Command::MotorDirection(direction) => [1, direction.to_byte(), 0, 0],
+            Command::CallButtonLight { call, floor, on } => [2, call.to_byte(), floor.0, on as u8],
+            Command::FloorIndicator(floor) => [3, floor.0, 0, 0],
+            Command::DoorLight(on) => [4, on as u8, 0, 0],
+            Command::StopButtonLight(on) => [5, on as u8, 0, 0],
+            Command::CallButton { call, floor } => [6, call.to_byte(), floor.0, 0],
+            Command::FloorSensor => [7, 0, 0, 0],
+            Command::StopButton => [8, 0, 0, 0],
+            Command::Obstruction => [9, 0, 0, 0],
+        }
+    }
+
+    pub fn decode(frame: Frame, num_floors: u8) -> io::Result<Command> {
+        let [kind, a, b, c] = frame;
+        let call = |byte| CallType::from_byte(byte).ok_or_else(|| invalid(frame, "call type"));
+        let floor = |byte| Floor::new(byte, num_floors).ok_or_else(|| invalid(frame, "floor"));
+        let on = |byte| flag(byte).ok_or_else(|| invalid(frame, "lamp state"));
+        Ok(match kind {
+            1 => Command::MotorDirection(Direction::from_byte(a).ok_or_else(|| invalid(frame, "direction"))?),
+            2 => Command::CallButtonLight { call: call(a)?, floor: floor(b)?, on: on(c)? },
+            3 => Command::FloorIndicator(floor(a)?),
+            4 => Command::DoorLight(on(a)?),
+            5 => Command::StopButtonLight(on(a)?),
+            6 => Command::CallButton { call: call(a)?, floor: floor(b)? },
+            7 => Command::FloorSensor,
+            8 => Command::StopButton,
+            9 => Command::Obstruction,
+            _ => return Err(invalid(frame, "command")),
+        })
+    }
+
+    // Whether the server answers this command
+    pub fn has_reply(self) -> bool {
+        matches!(self, Command::CallButton { .. } | Command::FloorSensor | Command::StopButton | Command::Obstruction)
+    }
+}
+
+impl Reply {
+    pub fn encode(self) -> Frame {
+        match self {
+            Reply::CallButton(pressed) => [6, pressed as u8, 0, 0],
+            Reply::FloorSensor(Some(floor)) => [7, 1, floor.0, 0],
+            Reply::FloorSensor(None) => [7, 0, 0, 0],
+            Reply::StopButton(pressed) => [8, pressed as u8, 0, 0],
+            Reply::Obstruction(active) => [9, active as u8, 0, 0],
+        }
+    }
+
+    // The reply to `command`, checked to be of the same kind
+    pub fn decode(command: Command, frame: Frame, num_floors: u8) -> io::Result<Reply> {
+        let [kind, a, b, _] = frame;
+        let set = |byte| flag(byte).ok_or_else(|| invalid(frame, "button state"));
+        Ok(match (command, kind) {
+            (Command::CallButton { .. }, 6) => Reply::CallButton(set(a)?),
+            (Command::FloorSensor, 7) if a == 0 => Reply::FloorSensor(None),
+            (Command::FloorSensor, 7) => Reply::FloorSensor(Some(Floor::new(b, num_floors).ok_or_else(|| invalid(frame, "floor"))?)),
+            (Command::StopButton, 8) => Reply::StopButton(set(a)?),
+            (Command::Obstruction, 9) => Reply::Obstruction(set(a)?),
+            _ => return Err(invalid(frame, "reply")),
+        })
+    }
+}
+
+impl From<Direction> for u8 {
+    fn from(direction: Direction) -> u8 {
+        direction.to_byte()
+    }
+}
+
+impl TryFrom<u8> for Direction {
+    type Error = String;
+
+    fn try_from(byte: u8) -> Result<Direction, String> {
+        Direction::from_byte(byte).ok_or_else(|| format!("invalid direction {byte}"))
+    }
+}
+
+impl From<CallType> for u8 {
+    fn from(call: CallType) -> u8 {
+        call.to_byte()
+    }
+}
+
+impl TryFrom<u8> for CallType {
+    type Error = String;
+
+    fn try_from(byte: u8) -> Result<CallType, String> {
+        CallType::from_byte(byte).ok_or_else(|| format!("invalid call type {byte}"))
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Direction::Down => "down",
+            Direction::Stop => "stop",
+            Direction::Up => "up",
+        })
+    }
+}
+
+impl fmt::Display for CallType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CallType::HallUp => "hall up",
+            CallType::HallDown => "hall down",
+            CallType::Cab => "cab",
+        })
+    }
+}
+
+// Like the number, padding included
+impl fmt::Display for Floor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+
+// ---------- PURE FUNCTIONS ----------
+
+fn flag(byte: u8) -> Option<bool> {
+    match byte {
+        0 => Some(false),
+        1 => Some(true),
+        _ => None,
+    }
+}
+
+fn invalid(frame: Frame, what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid {what} in frame {frame:?}"))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLOORS: u8 = 4;
+
+    fn floor(n: u8) -> Floor {
+        Floor::new(n, FLOORS).unwrap()
+    }
+
+    // Every command with the bytes it must be sent as
+    fn commands() -> Vec<(Command, Frame)> {
+        vec![
+            (Command::MotorDirection(Direction::Down), [1, 255, 0, 0]),
+            (Command::MotorDirection(Direction::Stop), [1, 0, 0, 0]),
+            (Command::MotorDirection(Direction::Up), [1, 1, 0, 0]),
+            (Command::CallButtonLight { call: CallType::HallUp, floor: floor(0), on: true }, [2, 0, 0, 1]),
+            (Command::CallButtonLight { call: CallType::HallDown, floor: floor(3), on: false }, [2, 1, 3, 0]),
+            (Command::CallButtonLight { call: CallType::Cab, floor: floor(2), on: true }, [2, 2, 2, 1]),
+            (Command::FloorIndicator(floor(3)), [3, 3, 0, 0]),
+            (Command::DoorLight(true), [4, 1, 0, 0]),
+            (Command::DoorLight(false), [4, 0, 0, 0]),
+            (Command::StopButtonLight(true), [5, 1, 0, 0]),
+            (Command::StopButtonLight(false), [5, 0, 0, 0]),
+            (Command::CallButton { call: CallType::HallUp, floor: floor(1) }, [6, 0, 1, 0]),
+            (Command::CallButton { call: CallType::Cab, floor: floor(3) }, [6, 2, 3, 0]),
+            (Command::FloorSensor, [7, 0, 0, 0]),
+            (Command::StopButton, [8, 0, 0, 0]),
+            (Command::Obstruction, [9, 0, 0, 0]),
+        ]
+    }
+
+    #[test]
+    fn commands_encode_to_their_frames() {
+        for (command, frame) in commands() {
+            assert_eq!(command.encode(), frame, "{command:?}");
+        }
+    }
+
+    #[test]
+    fn commands_decode_from_their_frames() {
+        for (command, frame) in commands() {
+            assert_eq!(Command::decode(frame, FLOORS).unwrap(), command, "{frame:?}");
+        }
+    }
+
+    #[test]
+    fn only_reading_commands_have_replies() {
+        for (command, frame) in commands() {
+            assert_eq!(command.has_reply(), frame[0] >= 6, "{command:?}");
+        }
+    }
+
+    #[test]
+    fn invalid_commands_are_rejected() {
+        let invalid = [
+            [0, 0, 0, 0],       // No such command
+            [10, 0, 0, 0],
+            [1, 2, 0, 0],       // No such direction
+            [2, 3, 0, 1],       // No such call type
+            [2, 0, 4, 1],       // Floor past the top
+            [2, 0, 0, 2],       // Lamp neither on nor off
+            [3, 4, 0, 0],
+            [4, 7, 0, 0],
+            [5, 2, 0, 0],
+            [6, 3, 0, 0],
+            [6, 0, 9, 0],
+        ];
+        for frame in invalid {
+            assert!(Command::decode(frame, FLOORS).is_err(), "{frame:?}");
+        }
+    }
+
+    #[test]
+    fn replies_roundtrip() {
+        let replies = [
+            (Command::CallButton { call: CallType::HallDown, floor: floor(2) }, Reply::CallButton(true), [6, 1, 0, 0]),
+            (Command::CallButton { call: CallType::Cab, floor: floor(0) }, Reply::CallButton(false), [6, 0, 0, 0]),
+            (Command::FloorSensor, Reply::FloorSensor(Some(floor(2))), [7, 1, 2, 0]),
+            (Command::FloorSensor, Reply::FloorSensor(None), [7, 0, 0, 0]),
+            (Command::StopButton, Reply::StopButton(true), [8, 1, 0, 0]),
+            (Command::Obstruction, Reply::Obstruction(false), [9, 0, 0, 0]),
+        ];
+        for (command, reply, frame) in replies {
+            assert_eq!(reply.encode(), frame, "{reply:?}");
+            assert_eq!(Reply::decode(command, frame, FLOORS).unwrap(), reply, "{frame:?}");
+        }
+    }
+
+    #[test]
+    fn invalid_replies_are_rejected() {
+        let invalid = [
+            (Command::FloorSensor, [7, 1, 4, 0]),       // Floor past the top
+            (Command::FloorSensor, [8, 0, 0, 0]),       // Answer to another command
+            (Command::StopButton, [8, 2, 0, 0]),
+            (Command::Obstruction, [7, 0, 0, 0]),
+            (Command::CallButton { call: CallType::Cab, floor: floor(1) }, [6, 5, 0, 0]),
+        ];
+        for (command, frame) in invalid {
+            assert!(Reply::decode(command, frame, FLOORS).is_err(), "{frame:?}");
+        }
+    }
+
+    #[test]
+    fn floors_past_the_top_do_not_exist() {
+        assert_eq!(Floor::new(3, FLOORS).map(Floor::get), Some(3));
+        assert_eq!(Floor::new(4, FLOORS), None);
+    }
+
+    #[test]
+    fn call_types_and_directions_serialize_as_protocol_bytes() {
+        assert_eq!(serde_json::to_string(&CallType::HallDown).unwrap(), "1");
+        assert_eq!(serde_json::to_string(&Direction::Down).unwrap(), "255");
+        assert_eq!(serde_json::from_str::<CallType>("2").unwrap(), CallType::Cab);
+        assert!(serde_json::from_str::<CallType>("3").is_err());
+        assert!(serde_json::from_str::<Direction>("2").is_err());
+    }
+}
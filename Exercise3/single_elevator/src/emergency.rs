@@ -0,0 +1,52 @@
+use std::{panic, sync::OnceLock};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, warn};
+
+use crate::elevator::elevio::elev::Elevio;
+use crate::recorder;
+
+// Connections used to stop the car when things go wrong. The dedicated one comes first,
+// the shared driver connection is only a fallback since a panicking task may hold its lock.
+static CONNECTIONS: OnceLock<Vec<Elevio>> = OnceLock::new();
+
+// Open the emergency connection and stop the car on any panic, before the panic is reported
+// and the flight recorder is written
+pub fn install(addr: &str, io: Elevio) {
+    let mut connections = Vec::new();
+    match Elevio::init(addr, io.num_floors) {
+        Ok(emergency) => connections.push(emergency),
+        Err(e) => warn!(error = %e, "No dedicated emergency connection, using the driver connection"),
+    }
+    connections.push(io);
+    let _ = CONNECTIONS.set(connections);
+
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        safe_stop();
+        error!(panic = %info, "Panic, car stopped");
+        recorder::dump_after_panic("panic");
+        default_hook(info);
+    }));
+}
+
+// Motor stopped, door lamp off and stop lamp on. Never blocks or panics.
+pub fn safe_stop() {
+    for io in CONNECTIONS.get().into_iter().flatten() {
+        io.emergency_stop();
+    }
+}
+
+// Stop the car and exit on SIGINT or SIGTERM
+pub async fn shutdown_on_signal() {
+    let (Ok(mut interrupt), Ok(mut terminate)) = (signal(SignalKind::interrupt()), signal(SignalKind::terminate())) else {
+        error!("Could not listen for signals");
+        return;
+    };
+    let name = tokio::select! {
+        _ = interrupt.recv() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    };
+    warn!(signal = name, "Stopping the elevator");
+    safe_stop();
+    std::process::exit(0);
+}
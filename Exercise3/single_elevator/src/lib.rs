@@ -0,0 +1,32 @@
+// Everything an elevator node is made of, shared by the node binary, the tools in src/bin
+// and anything else that wants to drive, simulate or watch elevators.
+//
+//   building           floors, hall buttons and which floors each car serves
+//   elevator           driver (elevio) and the task running the car
+//   order_management   orders of this elevator, and saving them to disk
+//   networking         world view shared with the peers, and the node configuration
+//   supervisor         restarts failed tasks, and gives up when a critical one cannot be
+//   emergency          stops the car on a panic or a signal
+//   safety             stops the car when it is told to do something unsafe
+//   logging            log output, and changing its filter while running
+//   recorder           flight recorder of recent events, written when something goes wrong
+//   clock              real or virtual time, for every task that waits
+//   node               wires the tasks of one node together
+//   process_pair       primary and backup processes, shipping state snapshots between them
+//   control            local socket for the elevctl operator commands
+//   simulation         simulated cars and groups, scenarios and the simulator server
+
+pub mod building;
+pub mod elevator;
+pub mod order_management;
+pub mod networking;
+pub mod supervisor;
+pub mod emergency;
+pub mod safety;
+pub mod logging;
+pub mod recorder;
+pub mod clock;
+pub mod node;
+pub mod process_pair;
+pub mod control;
+pub mod simulation;
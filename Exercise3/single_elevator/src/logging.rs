@@ -0,0 +1,36 @@
+use std::sync::OnceLock;
+use tracing_subscriber::{prelude::*, reload, EnvFilter, Registry};
+
+use crate::recorder;
+
+// Filter in EnvFilter syntax, e.g. ELEV_LOG=debug or ELEV_LOG=info,single_elevator::networking=trace
+pub const FILTER_ENV: &str = "ELEV_LOG";
+// "json" for one JSON object per line, anything else for human readable output
+pub const FORMAT_ENV: &str = "ELEV_LOG_FORMAT";
+
+const DEFAULT_FILTER: &str = "info";
+
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+// Install the global subscriber. Every line carries a timestamp and the spans it was
+// logged in, so the node id shows up as long as the code runs inside the node span.
+// The flight recorder has its own filter, so it keeps recording what the log leaves out.
+pub fn init() {
+    let filter = EnvFilter::try_from_env(FILTER_ENV).unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+    let (filter, handle) = reload::Layer::new(filter);
+
+    let output = match std::env::var(FORMAT_ENV).as_deref() {
+        Ok("json") => tracing_subscriber::fmt::layer().json().with_current_span(true).with_span_list(true).boxed(),
+        _ => tracing_subscriber::fmt::layer().boxed(),
+    };
+
+    tracing_subscriber::registry().with(output.with_filter(filter)).with(recorder::layer()).init();
+    let _ = FILTER.set(handle);
+}
+
+// Change which levels are logged while running
+pub fn set_filter(filter: &str) -> Result<(), String> {
+    let filter = EnvFilter::try_new(filter).map_err(|e| e.to_string())?;
+    let handle = FILTER.get().ok_or("logging is not initialized")?;
+    handle.reload(filter).map_err(|e| e.to_string())
+}
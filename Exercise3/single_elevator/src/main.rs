@@ -1,39 +1,81 @@
 use std::{io, env};
-use tokio::sync::mpsc::unbounded_channel as uc;
-use elevator::elevio::poll::CallButton as CallButton;
-use order_management::Order as Order;
+use tokio::runtime;
+use single_elevator::{clock, control, elevator, emergency, logging, node, recorder, simulation};
+use single_elevator::elevator::elevio::elev::Elevio;
+use single_elevator::networking::NetConfig;
+use single_elevator::order_management::persistence::OrderStore;
+use single_elevator::supervisor::{Policy, Supervisor};
+use tracing::{Instrument, info_span, error};
 
-pub mod elevator;
-pub mod order_management;
-pub mod networking;
-// use elevator::{elevio::elev::Elevio as e, NUM_FLOORS};
+fn main() -> io::Result<()> {
 
-#[tokio::main]
+    logging::init();
+    let mut args = env::args().skip(1).peekable();
 
-async fn main() -> io::Result<()> {
+    // `single_elevator sim ...` runs a whole group of simulated elevators instead of one node, and
+    // `single_elevator scenario <file>...` runs scripted tests against one. Both use a single
+    // thread, so every node gets to run between two steps of virtual time.
+    // `single_elevator sim-server ...` serves one simulated car in real time for a node to drive.
+    if args.peek().is_some_and(|arg| arg == "sim") {
+        let config = simulation::SimConfig::from_args(args.skip(1))?;
+        return runtime::Builder::new_current_thread().enable_all().build()?.block_on(simulation::run(config));
+    }
+    if args.peek().is_some_and(|arg| arg == "scenario") {
+        let (paths, base_port) = simulation::scenario::from_args(args.skip(1))?;
+        return runtime::Builder::new_current_thread().enable_all().build()?.block_on(simulation::scenario::run_files(&paths, base_port));
+    }
 
-    // let id: u8 = std::env::args().last().unwrap().parse().unwrap();
-    // let mut ids = vec![19, 20, 21];
+    if args.peek().is_some_and(|arg| arg == "sim-server") {
+        let (port, floors) = simulation::server::from_args(args.skip(1))?;
+        return runtime::Runtime::new()?.block_on(simulation::server::serve(port, floors));
+    }
 
-    // ids.retain(|x| *x !=id);
-    // println!("Connecting to {}", ids[1]);
+    let net_config = NetConfig::from_args(args)?;
+    recorder::set_node(net_config.id);
+    runtime::Runtime::new()?.block_on(node_main(net_config))
+}
+
+async fn node_main(net_config: NetConfig) -> io::Result<()> {
+
+    // Everything logged by this node is tagged with its id
+    let node_span = info_span!("node", id = net_config.id);
+    let result = run(net_config).instrument(node_span).await;
+    if let Err(e) = &result {
+        error!(error = %e, "Fatal error");
+        recorder::dump("fatal error");
+    }
+    result
+}
+
+async fn run(net_config: NetConfig) -> io::Result<()> {
+
+    let clock = clock::system();
+    let mut order_store = OrderStore::for_node(net_config.id);
+    let mut supervisor = Supervisor::new(clock.clone());
+
+    // As one half of a process pair, wait for the other half to go before taking the car
+    if let Some(pair) = net_config.pair.clone() {
+        node::take_over(&mut supervisor, clock.clone(), pair, net_config.backup_args(), &mut order_store).await?;
+    }
+
+    let server = elevator::server_addr();
+    let io = Elevio::init(&server, net_config.building.num_floors)?;
 
-    // networking::udptest(ids[1]).await;
+    // Never leave the motor running on a panic, a signal or when giving up
+    emergency::install(&server, io.clone());
 
-    // Create channels for module communication
-    let (floor_order_tx, floor_order_rx) = uc::<CallButton>(); // Elevator sends order requests to order management
-    let (floor_cmd_tx, floor_cmd_rx) = uc::<CallButton>(); // Order management sends commands to elevator
-    let (floor_msg_tx, floor_msg_rx) = uc::<CallButton>(); // Elevator sends floor messages to order management
-    let (elev_req_tx, elev_req_rx) = uc::<bool>(); // Order management sends requests to elevator
-    let (elev_resp_tx, elev_resp_rx) = uc::<u8>(); // Elevator sends responses to order management
-    let (floor_msg_light_tx, floor_msg_light_rx) = uc::<(Order, bool)>(); // Elevator sends floor messages to light handling task
+    supervisor.on_escalate(emergency::safe_stop);
+    supervisor.on_escalate(|| { recorder::dump("escalation"); });
+    supervisor.spawn_once("signals", false, emergency::shutdown_on_signal());
+    supervisor.spawn("flight_recorder", Policy::best_effort(3), recorder::dump_on_signal);
 
-    let order_management_task = tokio::spawn(async move {
-        order_management::order_management_runner(floor_order_rx, floor_msg_rx, floor_cmd_tx, elev_req_tx, elev_resp_rx, floor_msg_light_tx).await});
-    let elevator_runner_task = tokio::spawn(async move {
-        elevator::elevator_runner(floor_order_tx, floor_msg_tx, floor_cmd_rx, elev_req_rx, elev_resp_tx, floor_msg_light_rx).await });
+    let control_socket = control::socket_path(net_config.id);
+    node::start(&mut supervisor, net_config, clock, io, order_store, Some(control_socket)).await?;
 
-    let _ = tokio::join!(order_management_task, elevator_runner_task);
-    
+    // Exit right away, so the backup takes over and no blocking task keeps this process alive
+    if let Err(e) = supervisor.run().await {
+        error!(error = %e, "Giving up, exiting");
+        std::process::exit(1);
+    }
     Ok(())
 }
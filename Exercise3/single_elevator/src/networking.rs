@@ -1,13 +1,363 @@
-use tokio::{net::UdpSocket, time};
-use std::{io, time::Duration, sync::Arc};
+pub mod fault;
+pub mod peers;
+pub mod world_view;
+
+use tokio::{time::{self, Instant}, sync::{oneshot, watch, mpsc::{UnboundedReceiver as URx, UnboundedSender as UTx}}};
+use serde::{Deserialize, Serialize};
+use std::{io, net::SocketAddr, time::Duration, sync::{Arc, Mutex}, collections::BTreeSet};
+use fault::{FaultRules, FaultySocket};
+use peers::{PeerTracker, PeerUpdate};
+use world_view::WorldView;
+use tracing::{debug, info, trace, warn};
+
+use crate::building::Building;
+use crate::elevator::{ElevatorState, LampCommand, NUM_FLOORS, elevio::{poll::CallButton, protocol::{CallType, Floor}}};
+use crate::order_management::OrderEvent;
+use crate::recorder;
+use crate::clock::{self, Clock, SharedClock};
+use crate::control::{NodeStatus, SharedStatus};
+use crate::process_pair::{self, PairConfig, Role};
+
+pub type NodeId = u8;
+
+const LAB_IDS: [NodeId; 3] = [19, 20, 21];
+const DEFAULT_PORT: u16 = 20011;
+const BROADCAST_PERIOD: Duration = Duration::from_millis(100);
+const PEER_TIMEOUT: Duration = Duration::from_millis(1000);
+const RESTORE_TIMEOUT: Duration = Duration::from_millis(1500);
+
+pub struct NetConfig {
+    pub id: NodeId,
+    pub local: SocketAddr,
+    pub peers: Vec<(NodeId, SocketAddr)>,
+    pub building: Arc<Building>,           // From ELEV_BUILDING, or the standard building
+    pub faults: Arc<Mutex<FaultRules>>,    // Shared, so a test can partition nodes while they run
+    pub fault_seed: u64,
+    pub dashboard: Option<SocketAddr>,      // Where to send the status of this node to, if anywhere
+    pub pair: Option<PairConfig>,           // Run as a process pair, protecting the orders in a backup process
+}
+
+impl NetConfig {
+
+    // Parse `<id> [--port <port>] [--peer <id>@<host:port>]... [--dashboard <host:port>]
+    //   [--pair <local host:port>,<remote host:port> [--role primary|backup]]`
+    // Without any --peer, the other lab machines are used as peers
+    pub fn from_args(mut args: impl Iterator<Item = String>) -> io::Result<NetConfig> {
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid argument: {what}"));
+
+        let id: NodeId = match args.next() {
+            Some(id) => id.parse().map_err(|_| invalid(&id))?,
+            None => 0,
+        };
+        let mut port = DEFAULT_PORT;
+        let mut peers = Vec::new();
+        let mut dashboard = None;
+        let mut pair = None;
+        let mut role = Role::Backup;
+
+        while let Some(arg) = args.next() {
+            let value = args.next().ok_or_else(|| invalid(&arg))?;
+            match arg.as_str() {
+                "--port" => port = value.parse().map_err(|_| invalid(&value))?,
+                "--peer" => {
+                    let (peer_id, addr) = value.split_once('@').ok_or_else(|| invalid(&value))?;
+                    peers.push((peer_id.parse().map_err(|_| invalid(&value))?, addr.parse().map_err(|_| invalid(&value))?));
+                }
+                "--dashboard" => dashboard = Some(value.parse().map_err(|_| invalid(&value))?),
+                "--pair" => {
+                    let (local, remote) = value.split_once(',').ok_or_else(|| invalid(&value))?;
+                    pair = Some((local.parse().map_err(|_| invalid(&value))?, remote.parse().map_err(|_| invalid(&value))?));
+                }
+                "--role" => role = value.parse()?,
+                _ => return Err(invalid(&arg)),
+            }
+        }
+
+        if peers.is_empty() {
+            peers = LAB_IDS.iter()
+                .filter(|peer| **peer != id)
+                .map(|peer| (*peer, SocketAddr::from(([10, 100, 23, *peer], DEFAULT_PORT))))
+                .collect();
+        }
+
+        // Bind to loopback when every peer is local, so fault rules can tell nodes apart
+        let local = if peers.iter().all(|(_, addr)| addr.ip().is_loopback()) {
+            SocketAddr::from(([127, 0, 0, 1], port))
+        } else {
+            SocketAddr::from(([0, 0, 0, 0], port))
+        };
+
+        let pair = pair.map(|(local, remote)| PairConfig {
+            local,
+            remote,
+            period: process_pair::DEFAULT_PERIOD,
+            timeout: process_pair::DEFAULT_TIMEOUT,
+            role,
+        });
+
+        let (rules, fault_seed) = FaultRules::from_env()?;
+        let building = Arc::new(Building::from_env(NUM_FLOORS)?);
+        building.check_cars(&peers.iter().map(|(peer, _)| *peer).chain([id]).collect())?;
+        Ok(NetConfig { id, local, peers, building, faults: Arc::new(Mutex::new(rules)), fault_seed, dashboard, pair })
+    }
+
+    // The arguments `from_args` takes to start this node again as the backup of its process pair
+    pub fn backup_args(&self) -> Vec<String> {
+        let mut args = vec![self.id.to_string(), "--port".to_string(), self.local.port().to_string()];
+        for (peer, addr) in self.peers.iter() {
+            args.extend(["--peer".to_string(), format!("{peer}@{addr}")]);
+        }
+        if let Some(dashboard) = self.dashboard {
+            args.extend(["--dashboard".to_string(), dashboard.to_string()]);
+        }
+        if let Some(pair) = &self.pair {
+            args.extend(["--pair".to_string(), format!("{},{}", pair.remote, pair.local)]);
+            args.extend(["--role".to_string(), "backup".to_string()]);
+        }
+        args
+    }
+}
+
+// Events from order management that change the shared world view
+#[derive(Debug, Clone)]
+pub enum NetEvent {
+    HallCall(CallButton),   // Hall button pressed on this elevator
+    Served(CallButton),     // Hall call served by this elevator
+    CabCall(Floor),         // Cab call accepted by this elevator
+    CabServed(Floor),       // Cab call served by this elevator
+    FloorServed(Floor),        // Every hall call at the floor served by this elevator, whoever it was given to
+    Service(bool),          // This elevator was taken in or out of service
+}
+
+#[derive(Serialize, Deserialize)]
+enum Message {
+    View { from: NodeId, view: WorldView },
+    RestoreRequest { from: NodeId },    // Sent on startup, peers answer with their view right away
+    Status { from: NodeId, status: NodeStatus },    // Only sent to the dashboard
+}
+
+
+// Hands the cab calls backed up by the peers to `restored_tx` once they are known,
+// or after a timeout when no peer answers, then keeps the world view in sync
+#[allow(clippy::too_many_arguments)]
+pub async fn network_runner(config: NetConfig, clock: SharedClock, mut net_event_rx: URx<NetEvent>, order_tx: UTx<OrderEvent>, lamp_tx: UTx<LampCommand>, restored_tx: oneshot::Sender<Vec<Floor>>, status: SharedStatus, mut state_rx: watch::Receiver<ElevatorState>) -> io::Result<()> {
+
+    let sock = FaultySocket::bind(config.local, config.faults.clone(), config.fault_seed, clock.clone()).await?;
+
+    let mut view = WorldView::new(config.building.num_floors);
+    let mut tracker = PeerTracker::new(PEER_TIMEOUT);
+    let mut buf = vec![0; 65536];
+
+    // ---------- RESTORE CAB CALLS ----------
+    send_message(&sock, &config, &Message::RestoreRequest { from: config.id }).await;
+    let deadline = clock.now() + RESTORE_TIMEOUT;
+    while let Some((len, _addr)) = recv_before(&sock, &mut buf, &*clock, deadline).await {
+        if let Ok(Message::View { from, view: theirs }) = serde_json::from_slice(&buf[..len]) {
+            if from == config.id {
+                continue;
+            }
+            if let Some(update) = tracker.seen(from, clock.now()) {
+                report_peers(&update);
+            }
+            view.merge(from, &theirs);
+            if theirs.cab.contains_key(&config.id) {
+                break;
+            }
+        }
+    }
+    let restored = view.cab_requests(config.id).active_floors();
+    if !restored.is_empty() {
+        info!(floors = ?restored, "Restored cab calls from peers");
+    }
+    let _ = restored_tx.send(restored);
+
+    let mut lit: BTreeSet<(Floor, CallType)> = BTreeSet::new();       // Hall lamps currently on
+    let mut handed: BTreeSet<(Floor, CallType)> = BTreeSet::new();    // Hall calls given to this elevator
+    let mut next_broadcast = clock.now();
+
+    loop {
+        let mut changed = false;    // Whether this node changed the view, so the peers hear at once
+        tokio::select! {
+            Some(event) = net_event_rx.recv() => {
+                debug!(?event, "World view event");
+                match event {
+                    NetEvent::HallCall(call) => {
+                        if let Some(request) = view.hall_request(&call) {
+                            request.accept(config.id, clock.epoch_millis());
+                        }
+                    }
+                    NetEvent::Served(call) => {
+                        if let Some(request) = view.hall_request(&call) {
+                            request.serve();
+                        }
+                    }
+                    NetEvent::FloorServed(floor) => {
+                        for request in view.hall.get_mut(floor.get() as usize).into_iter().flatten() {
+                            request.serve();
+                        }
+                    }
+                    NetEvent::CabCall(floor) => {
+                        view.cab_requests(config.id).set(floor, true, clock.epoch_millis());
+                    }
+                    NetEvent::CabServed(floor) => {
+                        view.cab_requests(config.id).set(floor, false, clock.epoch_millis());
+                    }
+                    NetEvent::Service(in_service) => {
+                        info!(in_service, "Service state changed");
+                        view.set_in_service(config.id, in_service);
+                    }
+                }
+                changed = true;
+            }
+
+            // Only a new floor changes the world view, the rest of the state is for the status
+            Ok(()) = state_rx.changed() => {
+                let Some(floor) = state_rx.borrow_and_update().floor else { continue };
+                if view.positions.insert(config.id, floor) == Some(floor) {
+                    continue;
+                }
+                changed = true;
+            }
+
+            Ok((len, addr)) = sock.recv_from(&mut buf) => {
+                match serde_json::from_slice::<Message>(&buf[..len]) {
+                    Ok(Message::View { from, view: theirs }) if from != config.id => {
+                        trace!(from, %addr, len, "Received world view");
+                        if let Some(update) = tracker.seen(from, clock.now()) {
+                            report_peers(&update);
+                        }
+                        view.merge(from, &theirs);
+                    }
+                    Ok(Message::RestoreRequest { from }) if from != config.id => {
+                        info!(from, "Peer asked for its cab calls");
+                        send_view(&sock, &config, &view).await;
+                    }
+                    Ok(_) => continue,
+                    Err(e) => {
+                        warn!(%addr, len, error = %e, "Dropped malformed message");
+                        continue;
+                    }
+                }
+            }
+
+            _ = clock.sleep_until(next_broadcast) => {
+                next_broadcast = clock.now() + BROADCAST_PERIOD;
+                if let Some(update) = tracker.expire(clock.now()) {
+                    report_peers(&update);
+                }
+                send_view(&sock, &config, &view).await;
+                send_status(&sock, &config, &status).await;
+            }
+        }
+
+        recorder::record_world_view(&view);
+
+        // ---------- HALL LIGHTS ----------
+        let active = view.active_hall_calls();
+        for &(floor, call) in active.symmetric_difference(&lit) {
+            let on = active.contains(&(floor, call));
+            debug!(%floor, %call, on, "Hall lamp");
+            let _ = lamp_tx.send(LampCommand { call: CallButton { floor, call }, on });
+        }
+        lit = active;
+        {
+            let mut status = status.lock().unwrap();
+            status.car = *state_rx.borrow();
+            status.peers = tracker.peers();
+            status.hall_lamps = lit.clone();
+            status.view = view.clone();
+        }
+
+        // ---------- ASSIGN HALL CALLS ----------
+        // The assignee is part of the shared view, so every node follows the same one. It is
+        // kept as long as it is reachable and in service, so orders only move when a peer is
+        // lost. Each side of a partition then assigns again what it knows about, and the merge
+        // settles on one assignee when the sides meet again.
+        let mut alive = tracker.peers();
+        alive.insert(config.id);
+        alive.retain(|node| view.in_service(*node));
+
+        // A call given to this elevator can go out without it stopping there, when another car
+        // served the whole floor. Order management then drops it so the car makes no extra stop.
+        for &(floor, call) in handed.difference(&lit) {
+            let _ = order_tx.send(OrderEvent::Withdrawn(CallButton { floor, call }));
+        }
+        handed.retain(|call| lit.contains(call));
+        for &(floor, call) in lit.iter() {
+            let call_button = CallButton { floor, call };
+            let serving: BTreeSet<NodeId> = alive.iter().copied().filter(|node| config.building.serves(*node, floor.get())).collect();
+            let current = view.hall[floor.get() as usize][call.index()].assignee();
+            let assignee = match current {
+                Some(node) if serving.contains(&node) => node,
+                _ => {
+                    let Some(node) = view.assignee(&call_button, &serving) else { continue };
+                    info!(order.floor = %floor, order.call = %call, assignee = node, previous = ?current, ?alive, "Assigned hall call");
+                    if let Some(request) = view.hall_request(&call_button) {
+                        request.assign(node);
+                    }
+                    changed = true;
+                    node
+                }
+            };
+            if assignee == config.id && handed.insert((floor, call)) {
+                debug!(order.floor = %floor, order.call = %call, "Serving hall call");
+                let _ = order_tx.send(OrderEvent::Assigned(call_button));
+            }
+        }
+        if changed {
+            send_view(&sock, &config, &view).await;
+        }
+    }
+}
+
+// Receive a packet, None if the deadline passes first
+async fn recv_before(sock: &FaultySocket, buf: &mut [u8], clock: &dyn Clock, deadline: Instant) -> Option<(usize, SocketAddr)> {
+    tokio::select! {
+        received = sock.recv_from(buf) => received.ok(),
+        _ = clock.sleep_until(deadline) => None,
+    }
+}
+
+async fn send_view(sock: &FaultySocket, config: &NetConfig, view: &WorldView) {
+    send_message(sock, config, &Message::View { from: config.id, view: view.clone() }).await;
+}
+
+async fn send_status(sock: &FaultySocket, config: &NetConfig, status: &SharedStatus) {
+    let Some(addr) = config.dashboard else { return };
+    let status = status.lock().unwrap().clone();
+    if let Ok(bytes) = serde_json::to_vec(&Message::Status { from: config.id, status }) {
+        let _ = sock.send_to(&bytes, addr).await;
+    }
+}
+
+async fn send_message(sock: &FaultySocket, config: &NetConfig, msg: &Message) {
+    let Ok(bytes) = serde_json::to_vec(msg) else { return };
+    for (peer, addr) in config.peers.iter() {
+        trace!(to = peer, %addr, len = bytes.len(), "Sending message");
+        let _ = sock.send_to(&bytes, *addr).await;
+    }
+}
+
+fn report_peers(update: &PeerUpdate) {
+    if update.partitioned() {
+        warn!(lost = ?update.lost, peers = ?update.peers, "Lost contact, serving known orders");
+    }
+    if !update.healed.is_empty() {
+        info!(healed = ?update.healed, "Partition healed, merging world views");
+    }
+    info!(peers = ?update.peers, new = ?update.new, "Peers changed");
+}
 
 pub async fn udptest(id: u8) -> io::Result<()> {
-    let remote_addr = format!("10.100.23.{}:20011",id);
-    let local_addr = "0.0.0.0:20011";
-    let sock = UdpSocket::bind(local_addr).await?;
-    let mysock: Arc<UdpSocket> = Arc::new(sock);
-    let recv_sock: Arc<UdpSocket> = mysock.clone();
-    let send_sock: Arc<UdpSocket> = mysock.clone();
+    let remote_addr = format!("10.100.23.{}:20011",id).parse().unwrap();
+    let local_addr = "0.0.0.0:20011".parse().unwrap();
+
+    // Degrade the link according to ELEV_NET_FAULTS, if set
+    let (rules, seed) = FaultRules::from_env()?;
+    let sock = FaultySocket::bind(local_addr, Arc::new(Mutex::new(rules)), seed, clock::system()).await?;
+    let mysock: Arc<FaultySocket> = Arc::new(sock);
+    let recv_sock: Arc<FaultySocket> = mysock.clone();
+    let send_sock: Arc<FaultySocket> = mysock.clone();
 
 
     // receiving
@@ -15,16 +365,15 @@ pub async fn udptest(id: u8) -> io::Result<()> {
         loop {
             let mut buf= [0; 1024];
             let (n, _addr) = recv_sock.recv_from(&mut buf).await.unwrap();
-            println!("Received message: {:?}", String::from_utf8_lossy(&buf[..n]));
+            info!(message = %String::from_utf8_lossy(&buf[..n]), "Received message");
         }
     });
 
     // sending
     let send_task = tokio::spawn( async move {
-        let remote_addr = remote_addr.clone();
         loop {
-            send_sock.send_to(b"This is a test message", &remote_addr).await.unwrap();
-            println!("Sent message");
+            send_sock.send_to(b"This is a test message", remote_addr).await.unwrap();
+            info!("Sent message");
             time::sleep(Duration::from_millis(1000)).await;
         }
     });
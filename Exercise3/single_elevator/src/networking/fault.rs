@@ -0,0 +1,370 @@
+use tokio::net::UdpSocket;
+use tracing::trace;
+use std::{io, collections::{HashMap, HashSet}, net::SocketAddr, sync::{Arc, Mutex}, time::Duration};
+
+use crate::clock::SharedClock;
+
+// Environment variable used to degrade the network without iptables, e.g.
+// ELEV_NET_FAULTS="loss=0.2,delay=50ms,jitter=30ms,dup=0.05,seed=7"
+pub const FAULTS_ENV: &str = "ELEV_NET_FAULTS";
+
+// Faults applied to every packet sent from one address to another.
+// Reordering happens naturally when jitter is larger than the send period.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LinkFaults {
+    pub loss: f64,          // Probability of dropping a packet
+    pub delay: Duration,    // Fixed latency added to every packet
+    pub jitter: Duration,   // Extra random latency in [0, jitter]
+    pub duplicate: f64,     // Probability of sending a packet twice
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FaultRules {
+    pub default: LinkFaults,
+    links: HashMap<(SocketAddr, SocketAddr), LinkFaults>,
+    partitions: Vec<HashSet<SocketAddr>>,
+}
+
+impl FaultRules {
+
+    // Read the default link faults from ELEV_NET_FAULTS, no faults if it is unset
+    pub fn from_env() -> io::Result<(FaultRules, u64)> {
+        match std::env::var(FAULTS_ENV) {
+            Ok(spec) => FaultRules::parse(&spec),
+            Err(_) => Ok((FaultRules::default(), 0)),
+        }
+    }
+
+    // Parse "key=value" pairs separated by commas, returns the rules and the rng seed
+    pub fn parse(spec: &str) -> io::Result<(FaultRules, u64)> {
+        let mut rules = FaultRules::default();
+        let mut seed = 0;
+
+        for pair in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (key, value) = pair.split_once('=').ok_or_else(|| invalid(pair))?;
+            match key.trim() {
+                "loss" => rules.default.loss = parse_probability(value)?,
+                "dup" | "duplicate" => rules.default.duplicate = parse_probability(value)?,
+                "delay" => rules.default.delay = parse_duration(value)?,
+                "jitter" => rules.default.jitter = parse_duration(value)?,
+                "seed" => seed = value.trim().parse().map_err(|_| invalid(pair))?,
+                _ => return Err(invalid(pair)),
+            }
+        }
+        Ok((rules, seed))
+    }
+
+    // Override the faults for packets sent from `from` to `to`
+    pub fn set_link(&mut self, from: SocketAddr, to: SocketAddr, faults: LinkFaults) {
+        self.links.insert((from, to), faults);
+    }
+
+    // Override the faults in both directions between two addresses
+    pub fn set_pair(&mut self, a: SocketAddr, b: SocketAddr, faults: LinkFaults) {
+        self.set_link(a, b, faults);
+        self.set_link(b, a, faults);
+    }
+
+    // Cut every address in `side` off from every address outside it
+    pub fn partition(&mut self, side: &[SocketAddr]) {
+        self.partitions.push(side.iter().copied().collect());
+    }
+
+    // Remove every partition that has `addr` on its cut-off side
+    pub fn rejoin(&mut self, addr: SocketAddr) {
+        self.partitions.retain(|side| !side.contains(&addr));
+    }
+
+    // Remove all partitions, link overrides are kept
+    pub fn heal(&mut self) {
+        self.partitions.clear();
+    }
+
+    pub fn link(&self, from: SocketAddr, to: SocketAddr) -> LinkFaults {
+        if self.partitions.iter().any(|side| side.contains(&from) != side.contains(&to)) {
+            return LinkFaults { loss: 1.0, ..Default::default() };
+        }
+        self.links.get(&(from, to)).copied().unwrap_or(self.default)
+    }
+}
+
+
+// UDP socket which applies the configured faults to everything it sends.
+// Receiving is untouched, faults are always applied on the sending side.
+pub struct FaultySocket {
+    sock: Arc<UdpSocket>,
+    local: SocketAddr,
+    pub rules: Arc<Mutex<FaultRules>>,
+    rng: Mutex<Rng>,
+    clock: SharedClock,     // Times the delayed packets
+}
+
+impl FaultySocket {
+
+    // `addr` is also the identity used when looking up link rules, so bind to
+    // the address the peers see (e.g. 127.0.0.1:port) when testing locally
+    pub async fn bind(addr: SocketAddr, rules: Arc<Mutex<FaultRules>>, seed: u64, clock: SharedClock) -> io::Result<FaultySocket> {
+        let sock = UdpSocket::bind(addr).await?;
+        Ok(FaultySocket {
+            local: sock.local_addr()?,
+            sock: Arc::new(sock),
+            rules,
+            rng: Mutex::new(Rng::new(seed ^ addr.port() as u64)),
+            clock,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local
+    }
+
+    pub fn set_broadcast(&self, on: bool) -> io::Result<()> {
+        self.sock.set_broadcast(on)
+    }
+
+    pub async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        let faults = self.rules.lock().unwrap().link(self.local, target);
+
+        // Decide the fate of every copy up front, so the rng lock is not held across awaits
+        let delays: Vec<Duration> = {
+            let mut rng = self.rng.lock().unwrap();
+            let copies = if rng.chance(faults.duplicate) { 2 } else { 1 };
+            let mut delays = Vec::with_capacity(copies);
+            for _ in 0..copies {
+                if !rng.chance(faults.loss) {
+                    delays.push(faults.delay + faults.jitter.mul_f64(rng.next_f64()));
+                }
+            }
+            delays
+        };
+        if delays.len() != 1 {
+            trace!(to = %target, copies = delays.len(), "Fault injected");
+        }
+
+        for delay in delays {
+            if delay.is_zero() {
+                self.sock.send_to(buf, target).await?;
+            } else {
+                let sock = self.sock.clone();
+                let clock = self.clock.clone();
+                let packet = buf.to_vec();
+                tokio::spawn(async move {
+                    clock.sleep(delay).await;
+                    let _ = sock.send_to(&packet, target).await;
+                });
+            }
+        }
+
+        // Report success even when dropped, like a real lossy link
+        Ok(buf.len())
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.sock.recv_from(buf).await
+    }
+}
+
+
+// ---------- PURE FUNCTIONS ----------
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("invalid network fault setting: {what}"))
+}
+
+fn parse_probability(value: &str) -> io::Result<f64> {
+    match value.trim().parse::<f64>() {
+        Ok(p) if (0.0..=1.0).contains(&p) => Ok(p),
+        _ => Err(invalid(value)),
+    }
+}
+
+// Accepts "250ms", "2s" or a bare number of milliseconds
+fn parse_duration(value: &str) -> io::Result<Duration> {
+    let value = value.trim();
+    let (number, scale) = if let Some(ms) = value.strip_suffix("ms") {
+        (ms, 1)
+    } else if let Some(s) = value.strip_suffix('s') {
+        (s, 1000)
+    } else {
+        (value, 1)
+    };
+    number.parse::<u64>().map(|n| Duration::from_millis(n * scale)).map_err(|_| invalid(value))
+}
+
+
+// Small xorshift generator, so runs with the same seed drop the same packets
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && self.next_f64() < p
+    }
+
+    // Uniform in [0, n)
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n.max(1)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time;
+    use crate::clock::{self, VirtualClock};
+
+    const QUIET: Duration = Duration::from_millis(100);
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn parses_every_setting() {
+        let (rules, seed) = FaultRules::parse("loss=0.2, delay=50ms,jitter=2s,dup=0.05,seed=7").unwrap();
+        assert_eq!(seed, 7);
+        assert_eq!(rules.default, LinkFaults {
+            loss: 0.2,
+            delay: Duration::from_millis(50),
+            jitter: Duration::from_secs(2),
+            duplicate: 0.05,
+        });
+        assert_eq!(FaultRules::parse("duplicate=1,delay=30").unwrap().0.default.duplicate, 1.0);
+        assert_eq!(FaultRules::parse("delay=30").unwrap().0.default.delay, Duration::from_millis(30));
+        assert_eq!(FaultRules::parse("").unwrap().0.default, LinkFaults::default());
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        for spec in ["loss", "loss=1.5", "loss=-0.1", "dup=x", "delay=fast", "delay=5m", "seed=-1", "drop=0.1"] {
+            assert!(FaultRules::parse(spec).is_err(), "{spec}");
+        }
+    }
+
+    #[test]
+    fn links_override_the_default_and_partitions_override_links() {
+        let (mut rules, _) = FaultRules::parse("loss=0.1").unwrap();
+        let slow = LinkFaults { delay: Duration::from_millis(20), ..Default::default() };
+        rules.set_pair(addr(1), addr(2), slow);
+        assert_eq!(rules.link(addr(1), addr(2)), slow);
+        assert_eq!(rules.link(addr(2), addr(1)), slow);
+        assert_eq!(rules.link(addr(1), addr(3)).loss, 0.1);
+
+        rules.partition(&[addr(1)]);
+        assert_eq!(rules.link(addr(1), addr(2)).loss, 1.0);
+        assert_eq!(rules.link(addr(3), addr(1)).loss, 1.0);
+        assert_eq!(rules.link(addr(2), addr(3)).loss, 0.1);
+
+        rules.rejoin(addr(1));
+        assert_eq!(rules.link(addr(1), addr(2)), slow);
+
+        rules.partition(&[addr(1), addr(2)]);
+        assert_eq!(rules.link(addr(1), addr(2)), slow);
+        assert_eq!(rules.link(addr(2), addr(3)).loss, 1.0);
+        rules.heal();
+        assert_eq!(rules.link(addr(2), addr(3)).loss, 0.1);
+    }
+
+    async fn socket(spec: &str, clock: SharedClock) -> FaultySocket {
+        let (rules, seed) = FaultRules::parse(spec).unwrap();
+        FaultySocket::bind(addr(0), Arc::new(Mutex::new(rules)), seed, clock).await.unwrap()
+    }
+
+    // Send packets 0..count and return the numbers that arrive, in order of arrival
+    async fn deliver(sender: &FaultySocket, receiver: &FaultySocket, count: u8) -> Vec<u8> {
+        for n in 0..count {
+            sender.send_to(&[n], receiver.local_addr()).await.unwrap();
+        }
+        received(receiver).await
+    }
+
+    async fn received(receiver: &FaultySocket) -> Vec<u8> {
+        let mut buf = [0; 16];
+        let mut arrived = Vec::new();
+        while let Ok(Ok((_, _))) = time::timeout(QUIET, receiver.recv_from(&mut buf)).await {
+            arrived.push(buf[0]);
+        }
+        arrived
+    }
+
+    #[tokio::test]
+    async fn without_faults_every_packet_arrives_once() {
+        let receiver = socket("", clock::system()).await;
+        let sender = socket("", clock::system()).await;
+        assert_eq!(deliver(&sender, &receiver, 20).await, (0..20).collect::<Vec<u8>>());
+    }
+
+    #[tokio::test]
+    async fn total_loss_drops_everything() {
+        let receiver = socket("", clock::system()).await;
+        let sender = socket("loss=1", clock::system()).await;
+        assert_eq!(deliver(&sender, &receiver, 20).await, Vec::<u8>::new());
+    }
+
+    #[tokio::test]
+    async fn certain_duplication_sends_everything_twice() {
+        let receiver = socket("", clock::system()).await;
+        let sender = socket("dup=1", clock::system()).await;
+        let expected: Vec<u8> = (0..20).flat_map(|n| [n, n]).collect();
+        assert_eq!(deliver(&sender, &receiver, 20).await, expected);
+    }
+
+    #[tokio::test]
+    async fn a_partition_drops_both_ways_until_healed() {
+        let a = socket("", clock::system()).await;
+        let b = socket("", clock::system()).await;
+        a.rules.lock().unwrap().partition(&[a.local_addr()]);
+        b.rules.lock().unwrap().partition(&[a.local_addr()]);
+        assert!(deliver(&a, &b, 5).await.is_empty());
+        assert!(deliver(&b, &a, 5).await.is_empty());
+
+        a.rules.lock().unwrap().heal();
+        b.rules.lock().unwrap().rejoin(a.local_addr());
+        assert_eq!(deliver(&a, &b, 5).await, vec![0, 1, 2, 3, 4]);
+        assert_eq!(deliver(&b, &a, 5).await, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn the_same_seed_loses_the_same_packets() {
+        let receiver = socket("", clock::system()).await;
+        let mut runs = Vec::new();
+        for spec in ["loss=0.5,dup=0.3,seed=7", "loss=0.5,dup=0.3,seed=7", "loss=0.5,dup=0.3,seed=8"] {
+            let sender = socket(spec, clock::system()).await;
+            runs.push(deliver(&sender, &receiver, 50).await);
+        }
+        assert_eq!(runs[0], runs[1]);
+        assert_ne!(runs[0], runs[2]);
+        assert!(runs[0].len() < 50 && !runs[0].is_empty(), "{:?}", runs[0]);
+    }
+
+    #[tokio::test]
+    async fn delayed_packets_wait_for_the_clock() {
+        let virtual_clock = VirtualClock::new();
+        let receiver = socket("", clock::system()).await;
+        let sender = socket("delay=500ms,jitter=500ms,seed=3", virtual_clock.clone()).await;
+
+        assert!(deliver(&sender, &receiver, 5).await.is_empty());
+        virtual_clock.advance(Duration::from_millis(499));
+        assert!(received(&receiver).await.is_empty());
+
+        virtual_clock.advance(Duration::from_millis(501));
+        let mut arrived = received(&receiver).await;
+        arrived.sort();
+        assert_eq!(arrived, vec![0, 1, 2, 3, 4]);
+    }
+}
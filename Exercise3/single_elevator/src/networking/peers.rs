@@ -0,0 +1,118 @@
+use std::collections::{BTreeSet, HashMap};
+use tokio::time::{Duration, Instant};
+
+use crate::networking::NodeId;
+
+// Change in the set of reachable peers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerUpdate {
+    pub peers: BTreeSet<NodeId>,    // Peers currently reachable
+    pub new: BTreeSet<NodeId>,      // Peers heard from for the first time, or again after being lost
+    pub lost: BTreeSet<NodeId>,     // Peers that went silent
+    pub healed: BTreeSet<NodeId>,   // The subset of `new` that had been lost before
+}
+
+impl PeerUpdate {
+    // Losing a peer we have talked to means we are on one side of a partition,
+    // either because the network split or because the peer died. We cannot tell which.
+    pub fn partitioned(&self) -> bool {
+        !self.lost.is_empty()
+    }
+}
+
+// Keeps track of which peers have been heard from recently
+pub struct PeerTracker {
+    timeout: Duration,
+    last_seen: HashMap<NodeId, Instant>,
+    ever_seen: BTreeSet<NodeId>,
+}
+
+impl PeerTracker {
+    pub fn new(timeout: Duration) -> PeerTracker {
+        PeerTracker { timeout, last_seen: HashMap::new(), ever_seen: BTreeSet::new() }
+    }
+
+    pub fn peers(&self) -> BTreeSet<NodeId> {
+        self.last_seen.keys().copied().collect()
+    }
+
+    // Register a message from `id`, returns an update if it was not already reachable
+    pub fn seen(&mut self, id: NodeId, now: Instant) -> Option<PeerUpdate> {
+        if self.last_seen.insert(id, now).is_some() {
+            return None;
+        }
+        let healed = if self.ever_seen.insert(id) { BTreeSet::new() } else { BTreeSet::from([id]) };
+        Some(PeerUpdate { peers: self.peers(), new: BTreeSet::from([id]), lost: BTreeSet::new(), healed })
+    }
+
+    // Drop peers that have been silent for longer than the timeout
+    pub fn expire(&mut self, now: Instant) -> Option<PeerUpdate> {
+        let timeout = self.timeout;
+        let lost: BTreeSet<NodeId> = self.last_seen.iter()
+            .filter(|(_, seen)| now.duration_since(**seen) > timeout)
+            .map(|(id, _)| *id)
+            .collect();
+        if lost.is_empty() {
+            return None;
+        }
+        self.last_seen.retain(|id, _| !lost.contains(id));
+        Some(PeerUpdate { peers: self.peers(), new: BTreeSet::new(), lost, healed: BTreeSet::new() })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(500);
+
+    #[test]
+    fn a_new_peer_is_reported_once() {
+        let start = Instant::now();
+        let mut tracker = PeerTracker::new(TIMEOUT);
+
+        let update = tracker.seen(2, start).unwrap();
+        assert_eq!(update.peers, BTreeSet::from([2]));
+        assert_eq!(update.new, BTreeSet::from([2]));
+        assert!(update.healed.is_empty() && !update.partitioned());
+
+        // Hearing from it again changes nothing
+        assert_eq!(tracker.seen(2, start + TIMEOUT / 2), None);
+        assert_eq!(tracker.seen(3, start).unwrap().peers, BTreeSet::from([2, 3]));
+    }
+
+    #[test]
+    fn a_silent_peer_expires_after_the_timeout() {
+        let start = Instant::now();
+        let mut tracker = PeerTracker::new(TIMEOUT);
+        tracker.seen(2, start);
+        tracker.seen(3, start);
+        tracker.seen(3, start + TIMEOUT);
+
+        // Exactly the timeout is still in time, and every message starts it over
+        assert_eq!(tracker.expire(start + TIMEOUT), None);
+        let update = tracker.expire(start + TIMEOUT + Duration::from_millis(1)).unwrap();
+        assert_eq!(update.lost, BTreeSet::from([2]));
+        assert_eq!(update.peers, BTreeSet::from([3]));
+        assert!(update.partitioned());
+        assert_eq!(tracker.peers(), BTreeSet::from([3]));
+        assert_eq!(tracker.expire(start + TIMEOUT + Duration::from_millis(1)), None);
+    }
+
+    #[test]
+    fn a_lost_peer_coming_back_is_healed() {
+        let start = Instant::now();
+        let mut tracker = PeerTracker::new(TIMEOUT);
+        tracker.seen(2, start);
+        tracker.expire(start + TIMEOUT * 2).unwrap();
+
+        let update = tracker.seen(2, start + TIMEOUT * 3).unwrap();
+        assert_eq!(update.new, BTreeSet::from([2]));
+        assert_eq!(update.healed, BTreeSet::from([2]));
+        assert_eq!(update.peers, BTreeSet::from([2]));
+
+        // Only peers heard from before count as healed
+        assert!(tracker.seen(3, start + TIMEOUT * 3).unwrap().healed.is_empty());
+    }
+}
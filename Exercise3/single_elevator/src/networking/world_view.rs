@@ -0,0 +1,361 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::elevator::NUM_FLOORS;
+use crate::elevator::elevio::{poll::CallButton, protocol::{CallType, Floor}};
+use crate::networking::NodeId;
+
+// A hall request as seen by the whole group.
+// Every node keeps a counter of the presses it accepted, and a counter of how many
+// of those presses have been served. The request is active as long as some node has
+// accepted a press that nobody has served yet. Merging takes the maximum of every
+// counter, so the result is the same regardless of the order views arrive in, an
+// accepted press is never lost, and a served press is never brought back.
+// The elevator serving the request travels with it, so every node follows the same one.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HallRequest {
+    accepted: BTreeMap<NodeId, u64>,
+    served: BTreeMap<NodeId, u64>,
+    #[serde(default)]
+    assigned: Option<Assignment>,
+}
+
+// Which node serves a press. Merging keeps the greatest one: the newest press, then the
+// latest assignment of it, then the highest node id when two nodes assigned it at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+struct Assignment {
+    press: (u64, NodeId),   // The newest accepted press, and the node that accepted it
+    round: u64,
+    node: NodeId,
+}
+
+impl HallRequest {
+    pub fn is_active(&self) -> bool {
+        self.accepted.iter().any(|(node, count)| count > self.served.get(node).unwrap_or(&0))
+    }
+
+    // `now` is Clock::epoch_millis, a lower bound for the count, so a restarted node with
+    // an empty view still issues a press newer than anything it served before
+    pub fn accept(&mut self, by: NodeId, now: u64) {
+        if self.is_active() {
+            return;
+        }
+        let count = self.accepted.entry(by).or_default();
+        *count = (*count + 1).max(now);
+    }
+
+    // Mark every press known to this node as served
+    pub fn serve(&mut self) {
+        for (node, count) in self.accepted.iter() {
+            let served = self.served.entry(*node).or_default();
+            *served = (*served).max(*count);
+        }
+    }
+
+    // The node serving the newest press, None when nobody has been assigned to it yet
+    pub fn assignee(&self) -> Option<NodeId> {
+        self.assigned
+            .filter(|assigned| self.is_active() && assigned.press == self.press())
+            .map(|assigned| assigned.node)
+    }
+
+    // Give the newest press to `node`, overriding any earlier assignment of it
+    pub fn assign(&mut self, node: NodeId) {
+        let press = self.press();
+        let round = match self.assigned {
+            Some(assigned) if assigned.press == press => assigned.round + 1,
+            _ => 0,
+        };
+        self.assigned = Some(Assignment { press, round, node });
+    }
+
+    pub fn merge(&mut self, other: &HallRequest) {
+        merge_max(&mut self.accepted, &other.accepted);
+        merge_max(&mut self.served, &other.served);
+        self.assigned = self.assigned.max(other.assigned);
+    }
+
+    fn press(&self) -> (u64, NodeId) {
+        self.accepted.iter().map(|(node, count)| (*count, *node)).max().unwrap_or_default()
+    }
+}
+
+
+// The cab calls of one node, backed up by every peer.
+// Only the owner changes them, so the copy with the highest version wins.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CabRequests {
+    version: u64,
+    floors: Vec<bool>,
+}
+
+impl Default for CabRequests {
+    fn default() -> Self {
+        Self { version: 0, floors: vec![false; NUM_FLOORS as usize] }
+    }
+}
+
+impl CabRequests {
+    // Buildings taller than NUM_FLOORS grow the list on demand
+    pub fn set(&mut self, floor: Floor, active: bool, now: u64) {
+        let index = floor.get() as usize;
+        if self.floors.len() <= index {
+            self.floors.resize(index + 1, false);
+        }
+        self.floors[index] = active;
+        // Same trick as for hall requests, a restarted owner must still win over its old copies
+        self.version = (self.version + 1).max(now);
+    }
+
+    pub fn active_floors(&self) -> Vec<Floor> {
+        let num_floors = self.floors.len() as u8;
+        (0..num_floors).filter(|floor| self.floors[*floor as usize]).filter_map(|floor| Floor::new(floor, num_floors)).collect()
+    }
+
+    pub fn merge(&mut self, other: &CabRequests) {
+        if other.version > self.version {
+            *self = other.clone();
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorldView {
+    pub hall: Vec<[HallRequest; 2]>,           // [floor][hall up / hall down]
+    pub cab: BTreeMap<NodeId, CabRequests>,    // Cab calls of every node
+    pub positions: BTreeMap<NodeId, u8>,       // Last known floor of each node
+    #[serde(default)]
+    pub out_of_service: BTreeSet<NodeId>,      // Nodes taken out of service by the operator
+}
+
+impl Default for WorldView {
+    fn default() -> Self {
+        WorldView::new(NUM_FLOORS)
+    }
+}
+
+impl WorldView {
+    pub fn new(num_floors: u8) -> WorldView {
+        Self {
+            hall: vec![Default::default(); num_floors as usize],
+            cab: BTreeMap::new(),
+            positions: BTreeMap::new(),
+            out_of_service: BTreeSet::new(),
+        }
+    }
+
+    pub fn hall_request(&mut self, call: &CallButton) -> Option<&mut HallRequest> {
+        self.hall.get_mut(call.floor.get() as usize)?.get_mut(call.call.index())
+    }
+
+    pub fn cab_requests(&mut self, node: NodeId) -> &mut CabRequests {
+        self.cab.entry(node).or_default()
+    }
+
+    // Merge a view received from `from`. Hall and cab requests are merged, while positions and
+    // service state are only taken for the sender, since every node is the authority on itself.
+    pub fn merge(&mut self, from: NodeId, other: &WorldView) {
+        for (mine, theirs) in self.hall.iter_mut().zip(other.hall.iter()) {
+            for (m, t) in mine.iter_mut().zip(theirs.iter()) {
+                m.merge(t);
+            }
+        }
+        for (node, theirs) in other.cab.iter() {
+            self.cab_requests(*node).merge(theirs);
+        }
+        if let Some(floor) = other.positions.get(&from) {
+            self.positions.insert(from, *floor);
+        }
+        self.set_in_service(from, !other.out_of_service.contains(&from));
+    }
+
+    pub fn in_service(&self, node: NodeId) -> bool {
+        !self.out_of_service.contains(&node)
+    }
+
+    pub fn set_in_service(&mut self, node: NodeId, in_service: bool) {
+        if in_service {
+            self.out_of_service.remove(&node);
+        } else {
+            self.out_of_service.insert(node);
+        }
+    }
+
+    pub fn active_hall_calls(&self) -> BTreeSet<(Floor, CallType)> {
+        let mut active = BTreeSet::new();
+        for (floor, requests) in self.hall.iter().enumerate() {
+            let Some(floor) = Floor::new(floor as u8, self.hall.len() as u8) else { continue };
+            for call in CallType::HALL {
+                if requests[call.index()].is_active() {
+                    active.insert((floor, call));
+                }
+            }
+        }
+        active
+    }
+
+    // Pick which of the reachable nodes should serve a hall call: the closest one, lowest id
+    // on ties. Nodes may know different positions, so the pick is stored with `HallRequest::assign`
+    // for the others to follow rather than worked out by each of them.
+    pub fn assignee(&self, call: &CallButton, alive: &BTreeSet<NodeId>) -> Option<NodeId> {
+        alive.iter()
+            .min_by_key(|node| {
+                let distance = self.positions.get(node)
+                    .map(|floor| floor.abs_diff(call.floor.get()))
+                    .unwrap_or(self.hall.len() as u8);
+                (distance, **node)
+            })
+            .copied()
+    }
+}
+
+
+// ---------- PURE FUNCTIONS ----------
+
+fn merge_max(mine: &mut BTreeMap<NodeId, u64>, theirs: &BTreeMap<NodeId, u64>) {
+    for (node, count) in theirs.iter() {
+        let entry = mine.entry(*node).or_default();
+        *entry = (*entry).max(*count);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UP: CallButton = CallButton { floor: floor(2), call: CallType::HallUp };
+
+    const fn floor(floor: u8) -> Floor {
+        Floor::new(floor, NUM_FLOORS).unwrap()
+    }
+
+    // Every way the views of three nodes can have diverged: each accepted, served or
+    // assigned the call on its own
+    fn diverged() -> Vec<HallRequest> {
+        let mut pressed = HallRequest::default();
+        pressed.accept(1, 0);
+        let mut assigned = pressed.clone();
+        assigned.assign(2);
+        let mut reassigned = assigned.clone();
+        reassigned.assign(3);
+        let mut served = assigned.clone();
+        served.serve();
+        let mut pressed_again = served.clone();
+        pressed_again.accept(3, 0);
+        let mut elsewhere = HallRequest::default();
+        elsewhere.accept(2, 0);
+        elsewhere.assign(1);
+        vec![HallRequest::default(), pressed, assigned, reassigned, served, pressed_again, elsewhere]
+    }
+
+    fn merged(a: &HallRequest, b: &HallRequest) -> HallRequest {
+        let mut merged = a.clone();
+        merged.merge(b);
+        merged
+    }
+
+    #[test]
+    fn hall_merge_is_commutative_associative_and_idempotent() {
+        let requests = diverged();
+        for a in requests.iter() {
+            assert_eq!(&merged(a, a), a);
+            for b in requests.iter() {
+                assert_eq!(merged(a, b), merged(b, a));
+                assert_eq!(merged(&merged(a, b), b), merged(a, b));
+                for c in requests.iter() {
+                    assert_eq!(merged(&merged(a, b), c), merged(a, &merged(b, c)));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn a_served_call_does_not_come_back() {
+        let mut pressed = HallRequest::default();
+        pressed.accept(1, 0);
+        let mut served = pressed.clone();
+        served.serve();
+        assert!(pressed.is_active());
+        assert!(!served.is_active());
+        assert!(!merged(&pressed, &served).is_active());
+        assert!(!merged(&served, &pressed).is_active());
+    }
+
+    #[test]
+    fn a_new_press_after_serving_is_kept() {
+        let mut served = HallRequest::default();
+        served.accept(1, 0);
+        served.serve();
+        let mut pressed_again = served.clone();
+        pressed_again.accept(2, 0);
+        assert!(merged(&served, &pressed_again).is_active());
+        assert!(merged(&pressed_again, &served).is_active());
+    }
+
+    #[test]
+    fn nodes_agree_on_the_assignee_whatever_order_views_arrive_in() {
+        let mut pressed = HallRequest::default();
+        pressed.accept(1, 0);
+        assert_eq!(pressed.assignee(), None);
+
+        // Two nodes assign the call at once, each to the car it thinks is closest
+        let mut by_one = pressed.clone();
+        by_one.assign(3);
+        let mut by_two = pressed.clone();
+        by_two.assign(2);
+        assert_eq!(merged(&by_one, &by_two).assignee(), Some(3));
+        assert_eq!(merged(&by_two, &by_one).assignee(), Some(3));
+
+        // Assigning again, e.g. when the assignee was lost, wins over the first assignment
+        let mut reassigned = by_one.clone();
+        reassigned.assign(1);
+        assert_eq!(merged(&by_two, &reassigned).assignee(), Some(1));
+        assert_eq!(merged(&reassigned, &by_two).assignee(), Some(1));
+    }
+
+    #[test]
+    fn an_assignment_only_holds_for_the_press_it_was_made_for() {
+        let mut request = HallRequest::default();
+        request.accept(1, 0);
+        request.assign(2);
+        assert_eq!(request.assignee(), Some(2));
+        request.serve();
+        assert_eq!(request.assignee(), None);
+        request.accept(3, 0);
+        assert_eq!(request.assignee(), None);
+        request.assign(1);
+        assert_eq!(request.assignee(), Some(1));
+    }
+
+    #[test]
+    fn world_view_merge_keeps_every_call_and_takes_only_the_senders_position() {
+        let mut mine = WorldView::new(4);
+        mine.hall_request(&UP).unwrap().accept(1, 0);
+        mine.positions.insert(1, 0);
+        mine.positions.insert(2, 0);
+        let mut theirs = WorldView::new(4);
+        theirs.hall_request(&CallButton { floor: floor(3), call: CallType::HallDown }).unwrap().accept(2, 0);
+        theirs.cab_requests(2).set(floor(1), true, 0);
+        theirs.positions.insert(1, 3);
+        theirs.positions.insert(2, 2);
+        theirs.set_in_service(2, false);
+
+        let mut a = mine.clone();
+        a.merge(2, &theirs);
+        assert_eq!(a.active_hall_calls(), BTreeSet::from([(floor(2), CallType::HallUp), (floor(3), CallType::HallDown)]));
+        assert_eq!(a.cab_requests(2).active_floors(), vec![floor(1)]);
+        assert_eq!(a.positions, BTreeMap::from([(1, 0), (2, 2)]));
+        assert!(!a.in_service(2));
+
+        let mut twice = a.clone();
+        twice.merge(2, &theirs);
+        assert_eq!(twice, a);
+
+        let mut b = theirs.clone();
+        b.merge(1, &mine);
+        assert_eq!(b.hall, a.hall);
+        assert_eq!(b.cab, a.cab);
+    }
+}
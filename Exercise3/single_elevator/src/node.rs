@@ -0,0 +1,148 @@
+use std::{io, path::PathBuf, sync::Arc};
+use tokio::{sync::{oneshot, watch, Notify, mpsc::unbounded_channel as uc}, task};
+use tracing::{error, info, warn};
+
+use crate::clock::SharedClock;
+use crate::control::{self, Control, SharedStatus};
+use crate::elevator::{self, ElevatorCommand, ElevatorState, LampCommand, elevio::{elev::Elevio, poll::CallButton, protocol::{CallType, Command, Floor}}};
+use crate::networking::{self, NetConfig, NetEvent};
+use crate::order_management::{self, OrderEvent, persistence::OrderStore};
+use crate::process_pair::{self, PairConfig, Role};
+use crate::safety;
+use crate::supervisor::{Policy, Supervisor};
+
+// Wire up every task of one elevator node and hand them to `supervisor`, with a control socket
+// at `control_socket` if given. Returns once the saved cab calls are restored, the caller then
+// runs the supervisor.
+pub async fn start(supervisor: &mut Supervisor, net_config: NetConfig, clock: SharedClock, io: Elevio, order_store: OrderStore, control_socket: Option<PathBuf>) -> io::Result<()> {
+
+    // Create channels for module communication
+    let (order_tx, order_rx) = uc::<OrderEvent>(); // Elevator, networking and the operator send events to order management
+    let (command_tx, command_rx) = uc::<ElevatorCommand>(); // Order management sends commands to elevator
+    let (lamp_tx, lamp_rx) = uc::<LampCommand>(); // Order management and networking send lamp changes to the light task
+    let (state_tx, state_rx) = watch::channel(ElevatorState::default()); // Elevator publishes its state to anyone interested
+    let (net_event_tx, net_event_rx) = uc::<NetEvent>(); // Order management sends world view changes to networking
+    let (output_tx, output_rx) = uc::<Command>(); // The driver copies every output command to the safety monitor
+
+    let (restored_tx, restored_rx) = oneshot::channel::<Vec<Floor>>(); // Networking sends cab calls backed up by the peers
+
+    // Every task drives the car through this tap, so the monitor sees each command from the start.
+    // Running without it is not safe, so its failure exits the process like the tasks below.
+    let io = io.with_output_tap(output_tx);
+    let (safety_io, safety_clock, safety_state_rx, safety_net_event_tx) = (io.clone(), clock.clone(), state_rx.clone(), net_event_tx.clone());
+    supervisor.spawn_once("safety", true, safety::safety_monitor(safety_io, safety_clock, output_rx, safety_state_rx, safety_net_event_tx));
+
+    let persisted = order_store.load().unwrap_or_else(|e| {
+        warn!(error = %e, "Could not read saved orders");
+        Vec::new()
+    });
+    let status = SharedStatus::default();
+    let layout = net_config.building.car(net_config.id);
+    let control = Control {
+        id: net_config.id,
+        io: io.clone(),
+        layout: layout.clone(),
+        order_tx: order_tx.clone(),
+        net_event_tx: net_event_tx.clone(),
+        status: status.clone(),
+    };
+    let (net_order_tx, net_lamp_tx, net_status, net_state_rx) = (order_tx.clone(), lamp_tx.clone(), status.clone(), state_rx.clone());
+    let net_clock = clock.clone();
+
+    // Order management and networking own state that cannot be rebuilt, so if they fail the
+    // process exits and the orders are recovered from disk and the peers on the next start
+    supervisor.spawn_once("network", true, async move {
+        if let Err(e) = networking::network_runner(net_config, net_clock, net_event_rx, net_order_tx, net_lamp_tx, restored_tx, net_status, net_state_rx).await {
+            error!(error = %e, "Network error");
+        }
+    });
+
+    // Restore the orders saved to disk and our own cab calls from the peers before accepting
+    // any new commands. They are queued as if pressed again, which also turns their lights back on.
+    let mut restored = persisted;
+    for floor in restored_rx.await.unwrap_or_default() {
+        let call = CallButton { floor, call: CallType::Cab };
+        if !restored.contains(&call) {
+            restored.push(call);
+        }
+    }
+    for call in restored {
+        let _ = order_tx.send(OrderEvent::Pressed(call));
+    }
+
+    let om_layout = layout.clone();
+    supervisor.spawn_once("order_management", true, async move {
+        let _ = order_management::order_management_runner(om_layout, order_rx, command_tx, lamp_tx, state_rx, net_event_tx, order_store, status).await;
+    });
+
+    // Only a convenience for the operator, so the node carries on without it
+    if let Some(path) = control_socket {
+        supervisor.spawn("control", Policy::best_effort(3), move || {
+            let (path, control) = (path.clone(), control.clone());
+            async move {
+                if let Err(e) = control::control_runner(path, control).await {
+                    warn!(error = %e, "Control socket failed");
+                }
+            }
+        });
+    }
+    elevator::elevator_runner(supervisor, clock, io, layout, state_tx, order_tx, command_rx, lamp_rx).await
+}
+
+
+// ---------- PROCESS PAIR ----------
+
+// Stand by as the backup of this node until its primary goes silent, then take over with the
+// orders it shipped. They are saved along with the orders on disk, so `start` restores them like
+// any other. From then on every save is shipped to a backup process of our own, started with
+// `backup_args`. A newer primary makes this one step down by exiting, like a critical failure.
+// Call before connecting to the car, which the primary holds until it is gone.
+pub async fn take_over(supervisor: &mut Supervisor, clock: SharedClock, pair: PairConfig, backup_args: Vec<String>, order_store: &mut OrderStore) -> io::Result<()> {
+
+    let sock = process_pair::bind(pair.local).await?;
+    let latest = match pair.role {
+        Role::Backup => process_pair::backup::<Vec<CallButton>>(sock.clone(), clock.clone(), &pair, None).await,
+        Role::Primary => None,
+    };
+    let (epoch, version, shipped) = match latest {
+        Some(snapshot) => (snapshot.epoch + 1, snapshot.version, snapshot.state),
+        None => (1, 0, Vec::new()),
+    };
+
+    let mut orders = order_store.load().unwrap_or_else(|e| {
+        warn!(error = %e, "Could not read saved orders");
+        Vec::new()
+    });
+    for call in shipped {
+        if !orders.contains(&call) {
+            orders.push(call);
+        }
+    }
+    order_store.save(&orders)?;
+    info!(epoch, orders = orders.len(), "Primary of the process pair");
+
+    let (state_tx, state_rx) = watch::channel(orders);
+    order_store.replicate(state_tx);
+    let heartbeat = Arc::new(Notify::new());
+
+    let (primary_clock, primary_pair, primary_heartbeat) = (clock.clone(), pair.clone(), heartbeat.clone());
+    supervisor.spawn_once("process_pair", true, async move {
+        process_pair::primary(sock, primary_clock, &primary_pair, state_rx, epoch, version, primary_heartbeat).await;
+    });
+
+    // Start another backup whenever ours exits, until one cannot be started at all
+    supervisor.spawn_once("backup", false, async move {
+        loop {
+            let mut child = match process_pair::spawn_backup(clock.clone(), &pair, &backup_args, heartbeat.clone()).await {
+                Ok(child) => child,
+                Err(e) => {
+                    error!(error = %e, "Could not start backup");
+                    return;
+                }
+            };
+            let status = task::spawn_blocking(move || child.wait()).await;
+            warn!(?status, "Backup exited, starting another");
+        }
+    });
+    Ok(())
+}
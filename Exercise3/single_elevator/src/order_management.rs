@@ -1,98 +1,188 @@
-use tokio::sync::mpsc::{UnboundedReceiver as URx, UnboundedSender as UTx};
-use std::collections::VecDeque;
+pub mod clearing;
+pub mod persistence;
+pub mod planner;
 
-use crate::elevator::elevio::poll::CallButton as CallButton;
+use tokio::sync::{mpsc::{UnboundedReceiver as URx, UnboundedSender as UTx}, watch};
+use std::collections::VecDeque;
 
-pub struct Order {
-    pub call: CallButton,
-    pub elevator: usize,
+use crate::building::CarLayout;
+use crate::elevator::{ElevatorCommand, ElevatorState, LampCommand};
+use crate::elevator::elevio::{poll::CallButton as CallButton, protocol::{CallType, Direction}};
+use crate::control::SharedStatus;
+use crate::networking::NetEvent;
+use clearing::ClearingPolicy;
+use persistence::OrderStore;
+use tracing::{debug, error, info, info_span};
+
+// Everything order management reacts to
+#[derive(Debug, Clone)]
+pub enum OrderEvent {
+    Pressed(CallButton),    // A button on this elevator, or a call restored or sent by the operator
+    Assigned(CallButton),   // A hall call the group gave to this elevator
+    Served(CallButton),     // The car stopped at the floor of this order
+    Reissue,                // Motor control restarted and lost its target, send the current order again
+    Withdrawn(CallButton),  // A hall call given to this elevator was served by another one
 }
 
 const m: u8 = 3; // number of floors
 const n: u8 = 3; // number of elevators
 
-pub async fn order_management_runner(mut floor_order_rx: URx<CallButton>, mut floor_msg_rx: URx<CallButton>, floor_cmd_tx: UTx<CallButton>, elev_req_tx: UTx<bool>, mut elev_resp_rx: URx<u8>, floor_msg_light_tx: UTx<(Order, bool)>) -> std::io::Result<()> {
+#[allow(clippy::too_many_arguments)]
+pub async fn order_management_runner(layout: CarLayout, mut event_rx: URx<OrderEvent>, command_tx: UTx<ElevatorCommand>, lamp_tx: UTx<LampCommand>, mut state_rx: watch::Receiver<ElevatorState>,
+    net_event_tx: UTx<NetEvent>, store: OrderStore, status: SharedStatus) -> std::io::Result<()> {
     
     let mut orders: VecDeque<CallButton> = VecDeque::with_capacity(3*m as usize);       // Ring buffer of all orders
     let mut positions: Vec<Option<u8>> = vec![None; n as usize];                        // List of current positions for each elevator
     let mut current_orders: Vec<Option<CallButton>> = Vec::with_capacity(n as usize);   // List of current order for each elevator
     current_orders.resize_with(n as usize, || None);
+    let policy = ClearingPolicy::from_env();
+    info!(?policy, "Clearing policy");
 
     // (re)assign orders whenever a new order is received or the status of an elevator changes
-    loop {
-        // println!("Orders: {:?}", orders);
-        tokio::select! { 
-            Some(call) = URx::recv(&mut floor_order_rx) => {
-
-                // TODO: Turn on light for new order
-                let order = Order { call: call.clone(), elevator: 0 };
-                let _ = floor_msg_light_tx.send((order, true));
-
-                // ---------- REQUEST ELEVATOR POSITIONS ----------
-                let _ = elev_req_tx.send(true);
-                if let Some(floor) = elev_resp_rx.recv().await {
-                    positions[0] = Some(floor);
+    while let Some(event) = event_rx.recv().await {
+
+        // Calls to buttons the building does not have on this car never become orders
+        if let OrderEvent::Pressed(call) | OrderEvent::Assigned(call) = &event
+            && let Err(e) = layout.check(call) {
+            error!(order.floor = %call.floor, order.call = %call.call, error = %e, "Rejected invalid call");
+            continue;
+        }
+
+        match event {
+            OrderEvent::Pressed(call) => {
+
+                // Hall calls are shared with the other elevators, and come back as `Assigned` if this elevator should serve them
+                debug!(order.floor = %call.floor, order.call = %call.call, "New call");
+                if call.call != CallType::Cab {
+                    let _ = net_event_tx.send(NetEvent::HallCall(call));
+                    continue;
                 }
 
+                // ---------- READ ELEVATOR POSITION ----------
+                read_position(&mut state_rx, &mut positions).await;
+
+
+                // ---------- ASSIGN NEW ORDER ----------
+                let new_order_found = assign_new_orders(call.clone(), &mut orders, &positions, &mut current_orders);
+                if new_order_found {
+                    info!(order = ?current_orders[0], "Serving order");
+                    let _ = command_tx.send(ElevatorCommand::GoTo(current_orders[0].clone().unwrap()));
+                }
+
+                // A lit cab lamp is a promise, so write the call to disk and back it up on the peers first
+                save_orders(&store, &orders, &current_orders);
+                status.lock().unwrap().set_orders(&orders, &current_orders, positions[0]);
+                let _ = net_event_tx.send(NetEvent::CabCall(call.floor));
+                let _ = lamp_tx.send(LampCommand { call, on: true });
+            }
+
+            OrderEvent::Assigned(call) => {
+                debug!(order.floor = %call.floor, order.call = %call.call, "Hall call assigned to this elevator");
+
+                // ---------- READ ELEVATOR POSITION ----------
+                read_position(&mut state_rx, &mut positions).await;
+
 
                 // ---------- ASSIGN NEW ORDER ----------
-                let new_order_found = assign_new_orders(call, &mut orders, &mut positions, &mut current_orders);
+                let new_order_found = assign_new_orders(call, &mut orders, &positions, &mut current_orders);
                 if new_order_found {
-                    let _ = floor_cmd_tx.send(current_orders[0].clone().unwrap());
-                    // println!("Serving order: {:?}", current_orders[0].clone().unwrap());
+                    info!(order = ?current_orders[0], "Serving order");
+                    let _ = command_tx.send(ElevatorCommand::GoTo(current_orders[0].clone().unwrap()));
                 }
+                save_orders(&store, &orders, &current_orders);
+                status.lock().unwrap().set_orders(&orders, &current_orders, positions[0]);
             }
 
-            Some(call) = URx::recv(&mut floor_msg_rx) => {
-                // println!("Arrived at floor: {}", call.floor);
+            OrderEvent::Withdrawn(call) => {
+                // Once the car is at the floor the stop is under way, and ends with `Served` as usual
+                orders.retain(|order| order != &call);
+                let state = *state_rx.borrow();
+                let arrived = state.floor == Some(call.floor.get()) && !state.between_floors;
+                if current_orders[0].as_ref() == Some(&call) && !arrived {
+                    info!(order.floor = %call.floor, order.call = %call.call, "Current order served by another elevator");
+                    read_position(&mut state_rx, &mut positions).await;
+                    current_orders[0] = None;
+
+                    // Head for the next order instead, or stop at the next floor if there is none
+                    match assign_next_order(call.clone(), &mut orders, &mut current_orders) {
+                        Some(next_order) => {
+                            let _ = assign_new_orders(next_order, &mut orders, &positions, &mut current_orders);
+                            info!(order = ?current_orders[0], "Serving order");
+                            let _ = command_tx.send(ElevatorCommand::GoTo(current_orders[0].clone().unwrap()));
+                        }
+                        None => {
+                            let _ = command_tx.send(ElevatorCommand::Cancel);
+                        }
+                    }
+                }
+                save_orders(&store, &orders, &current_orders);
+                status.lock().unwrap().set_orders(&orders, &current_orders, positions[0]);
+            }
 
+            OrderEvent::Reissue => {
+                if let Some(current) = current_orders[0].clone() {
+                    info!(order = ?current, "Sending the current order again");
+                    let _ = command_tx.send(ElevatorCommand::GoTo(current));
+                }
+            }
 
-                // ---------- CLEAR ORDER ----------
-                // Remove cab order to current floor and optionally the hall order the elevator is completing
-                if call.call != 2 {
-                    orders.retain(|order| order != &call);
-                    // TODO: Turn off light
-                    let order = Order { call: call.clone(), elevator: 0 };
-                    let _ = floor_msg_light_tx.send((order, false));
+            OrderEvent::Served(call) => {
+
+                // ---------- READ ELEVATOR POSITION ----------
+                // The floor known before this stop is where the car came from
+                let came_from = positions[0];
+                read_position(&mut state_rx, &mut positions).await;
+                let travel = came_from.map_or(Direction::Stop, |from| clearing::direction(from, call.floor.get()));
+
+
+                // ---------- CLEAR ORDERS ----------
+                // Remove every order the stop served, as the clearing policy has it
+                let cleared = policy.clear(&call, travel, &orders);
+                for served in cleared.now.iter() {
+                    orders.retain(|order| order != served);
+                    if served.call == CallType::Cab {
+                        let _ = net_event_tx.send(NetEvent::CabServed(served.floor));
+                        let _ = lamp_tx.send(LampCommand { call: served.clone(), on: false });
+                    } else {
+                        // Hall lights are turned off by the network once the call is marked as served
+                        let _ = net_event_tx.send(NetEvent::Served(served.clone()));
+                    }
                 }
-                orders.retain(|order| order != &CallButton { floor: call.floor, call: 2 });
-                current_orders[0] = None;        
-                // TODO: Turn off light
-                let order = Order { call: call.clone(), elevator: 0 };
-                let _ = floor_msg_light_tx.send((order, false));
-                println!("Cleared order {:?}. Orders: {:?}", call, orders);
-
-
-                // ---------- REQUEST ELEVATOR POSITIONS ----------
-                let _ = elev_req_tx.send(true);
-                if let Some(floor) = elev_resp_rx.recv().await {
-                    positions[0] = Some(floor);
+                // Every hall call at the floor is served, also the ones the group gave to other cars
+                if policy == ClearingPolicy::All {
+                    let _ = net_event_tx.send(NetEvent::FloorServed(call.floor));
+                }
+                current_orders[0] = None;
+                info!(order.floor = %call.floor, order.call = %call.call, cleared = ?cleared.now, ?orders, "Cleared orders");
+
+
+                // ---------- CHANGE DIRECTION ----------
+                // Open the door again for the hall call the other way, its passengers get on now
+                if let Some(opposite) = cleared.reopen {
+                    info!(floor = %opposite.floor, call = %opposite.call, "Changing direction");
+                    orders.retain(|order| order != &opposite);
+                    current_orders[0] = Some(opposite.clone());
+                    let _ = command_tx.send(ElevatorCommand::GoTo(opposite));
                 }
 
 
                 // ---------- FIND NEXT ORDER ----------
-                if orders.len() > 0 {
-                    let (next_order, clear_call) = assign_next_order(call.clone(), &mut orders, &mut current_orders);
-                    if clear_call.is_some() {
-                        orders.retain(|order| order != clear_call.as_ref().unwrap());
-                        // TODO: Turn off light
-                        let order = Order { call: clear_call.unwrap().clone(), elevator: 0 };
-                        let _ = floor_msg_light_tx.send((order, false));
-                    }
+                else if !orders.is_empty() {
+                    let next_order = assign_next_order(call.clone(), &mut orders, &mut current_orders);
 
                     if next_order.is_some() {
 
                         // ---------- REORDER QUEUE ----------
-                        let _ = assign_new_orders(next_order.unwrap(), &mut orders, &mut positions, &mut current_orders);
-                        println!("Serving order: {:?}", current_orders[0].clone().unwrap());
-                        let _ = floor_cmd_tx.send(current_orders[0].clone().unwrap());
+                        let _ = assign_new_orders(next_order.unwrap(), &mut orders, &positions, &mut current_orders);
+                        info!(order = ?current_orders[0], "Serving order");
+                        let _ = command_tx.send(ElevatorCommand::GoTo(current_orders[0].clone().unwrap()));
                     }
                     else {
-                        // println!("No new order");
-                        // TODO: Turn off light
-                        // let _ = floor_msg_light_tx.send((call.clone(), false));
+                        debug!("No new order");
                     }
                 }
+                save_orders(&store, &orders, &current_orders);
+                status.lock().unwrap().set_orders(&orders, &current_orders, positions[0]);
             }
         }
     }
@@ -100,18 +190,35 @@ pub async fn order_management_runner(mut floor_order_rx: URx<CallButton>, mut fl
 }
 
 
+// The floor of this elevator, waiting for the car to find one after startup
+async fn read_position(state_rx: &mut watch::Receiver<ElevatorState>, positions: &mut [Option<u8>]) {
+    if let Ok(state) = state_rx.wait_for(|state| state.floor.is_some()).await {
+        positions[0] = state.floor;
+    }
+}
+
+
+fn save_orders(store: &OrderStore, orders: &VecDeque<CallButton>, current_orders: &[Option<CallButton>]) {
+    let snapshot: Vec<CallButton> = current_orders.iter().flatten().chain(orders.iter()).cloned().collect();
+    if let Err(e) = store.save(&snapshot) {
+        error!(error = %e, "Failed to save orders");
+    }
+}
+
+
 // ---------- PURE FUNCTIONS ----------
 
 fn assign_new_orders(call: CallButton, orders: &mut VecDeque<CallButton>, positions: &Vec<Option<u8>>,
     mut current_orders: &mut Vec<Option<CallButton>>) -> bool  {
 
     // Assign order to elevator if there is no current order OR assign order on the way to the current order    
+    let _span = info_span!("order", floor = %call.floor, call = %call.call).entered();
 
     // Rebuild the queue with cab orders at the front
     let mut cab_orders: VecDeque<CallButton> = VecDeque::with_capacity(orders.len());
     let mut other_orders: VecDeque<CallButton> = VecDeque::with_capacity(orders.len());
     for order in orders.iter() {
-        if order.call == 2 {
+        if order.call == CallType::Cab {
             cab_orders.push_back(order.clone());
         } else {
             other_orders.push_back(order.clone());
@@ -121,9 +228,9 @@ fn assign_new_orders(call: CallButton, orders: &mut VecDeque<CallButton>, positi
     orders.extend(cab_orders);
     orders.extend(other_orders);
 
-    // If the order already exists, return
+    // If the order already exists, only check that the car stops where it should on the way
     if orders.iter().any(|order| order == &call) {
-        return false;
+        return stop_on_the_way(positions, orders, current_orders);
     }
     else {
         orders.push_back(call.clone());
@@ -135,7 +242,7 @@ fn assign_new_orders(call: CallButton, orders: &mut VecDeque<CallButton>, positi
         let mut closest_distance: u8 = m;
         for i in 0..n as usize {
             if positions[i].is_some() {
-                let new_closest_distance = u8::abs_diff(positions[i].unwrap(), call.clone().floor);
+                let new_closest_distance = u8::abs_diff(positions[i].unwrap(), call.floor.get());
                 if new_closest_distance < closest_distance {
                     closest_distance = new_closest_distance;
                     closest_elev = i;
@@ -144,108 +251,144 @@ fn assign_new_orders(call: CallButton, orders: &mut VecDeque<CallButton>, positi
                 }
             }
         }
-        // println!("Assigning order to elevator: {}", closest_elev);
+        debug!(elevator = closest_elev, "Assigning order to idle elevator");
         current_orders[closest_elev] = Some(orders.pop_front().unwrap());
         return true;
     }
 
-    // TODO: Do this for all elevators
-    let curr_order = current_orders[0].as_ref().unwrap().clone();
-    let mut replacement: CallButton = curr_order.clone();
-
-
-    // TODO: Refactor to work for all elevators
-    for call in orders.iter() {
-        // If there is an order between the elevator and the destination, which is not a hall call in the opposite direction
-        if (curr_order.floor < call.floor && call.floor < positions[0].unwrap()
-                && call.call != 0 && curr_order.call != 0)
-                || (curr_order.floor > call.floor && call.floor > positions[0].unwrap()
-                && call.call != 1 && curr_order.call != 1) {
-            replacement = CallButton { floor: call.floor, call: call.call };
-            // println!("Order on the way, stopping");
-        }
-    }
+    stop_on_the_way(positions, orders, current_orders)
+}
 
+// Make the nearest stop on the way to the current order the current order, true if it changed
+fn stop_on_the_way(positions: &[Option<u8>], orders: &mut VecDeque<CallButton>, current_orders: &mut [Option<CallButton>]) -> bool {
 
-    if replacement == *current_orders[0].as_ref().unwrap() {
+    // TODO: Do this for all elevators
+    let (Some(position), Some(curr_order)) = (positions[0], current_orders[0].clone()) else { return false };
+    let replacement = planner::stops(position, &curr_order, orders).swap_remove(0);
+    if replacement == curr_order {
         return false;
     }
-    else {
-        // Push demoted order to the front of the queue and remove the promoted order from the queue to avoid duplicates.
-        orders.push_front(current_orders[0].take().unwrap());
-        orders.retain(|order| order != &replacement);
-        current_orders[0] = Some(replacement);
-        return true;
-    }
+    debug!(stop.floor = %replacement.floor, stop.call = %replacement.call, "Order on the way, stopping");
 
+    // Push demoted order to the front of the queue and remove the promoted order from the queue to avoid duplicates.
+    orders.push_front(curr_order);
+    orders.retain(|order| order != &replacement);
+    current_orders[0] = Some(replacement);
+    true
 }
 
 fn assign_next_order(call: CallButton, orders: &mut VecDeque<CallButton>,
-    mut current_orders: &mut Vec<Option<CallButton>>) -> (Option<CallButton>, Option<CallButton>) {
+    current_orders: &mut [Option<CallButton>]) -> Option<CallButton> {
 
-    let mut order_found: (Option<CallButton>, Option<CallButton>) = (None, None);
-    match call.call {
-        0 => 'HallUp: {
+    // Orders at the floor the car stopped at are cleared before this, see `ClearingPolicy`
+    let _span = info_span!("order", floor = %call.floor, call = %call.call).entered();
+    let order_found = match call.call {
+        CallType::HallUp => 'HallUp: {
             // Try to assign order above in the same direction, else just assign something
 
             // Hall up or cab order call above
             for order in orders.iter() {
-                if order.floor > call.floor && order.call != 1 {
-                    order_found = (Some(order.clone()), None);
-                    break 'HallUp;
+                if order.floor > call.floor && order.call != CallType::HallDown {
+                    break 'HallUp Some(order.clone());
                 }
             }
             // Hall down call above
             for order in orders.iter() {
-                if order.floor > call.floor && order.call == 1 {
-                    order_found = (Some(order.clone()), None);
-                    break 'HallUp;
+                if order.floor > call.floor && order.call == CallType::HallDown {
+                    break 'HallUp Some(order.clone());
                 }
             }
 
-            // No order above, clear hall down order
-            order_found.1 = Some(CallButton { floor: call.floor, call: 0 });
-            // Changing direction, assign hall up order at the current floor (see spec)
-            order_found.0 = Some(CallButton { floor: call.floor, call: 1 });
-            println!("Changing direction");
+            // No order above, changing direction
+            info!("Changing direction");
+            orders.front().cloned()
         }
-        1 => 'HallDown: {
+        CallType::HallDown => 'HallDown: {
             // Try to assign order below in the same direction, else just assign something
 
             // Hall down or cab order call below
             for order in orders.iter() {
-                if order.floor < call.floor && order.call != 0 {
-                    order_found = (Some(order.clone()), None);
-                    break 'HallDown;
+                if order.floor < call.floor && order.call != CallType::HallUp {
+                    break 'HallDown Some(order.clone());
                 }
             }
             // Hall up call below
             for order in orders.iter() {
-                if order.floor < call.floor && order.call == 0 {
-                    order_found = (Some(order.clone()), None);
-                    break 'HallDown;
+                if order.floor < call.floor && order.call == CallType::HallUp {
+                    break 'HallDown Some(order.clone());
                 }
             }
 
-            // No order below, clear hall up order
-            order_found.1 = Some(CallButton { floor: call.floor, call: 1 });
-            // Changing direction, assign hall down order at the current floor (see spec)
-            order_found.0 = Some(CallButton { floor: call.floor, call: 0 });
-            println!("Changing direction");
-        }
-        _ => 'Cab: {
-            // Pick the first order, see if there are any hall orders at the current floor, in the direction of the first order
-            
-            order_found.0 = Some(orders.pop_front().unwrap());
-            if call.floor < order_found.0.as_ref().unwrap().floor{ order_found.1 = Some(CallButton { floor: call.floor, call: 0 })} // Hall up
-            else if call.floor > order_found.0.as_ref().unwrap().floor{ order_found.1 = Some(CallButton { floor: call.floor, call: 1 })} // Hall down
-            // TODO: Assign cab order below
-            break 'Cab;
+            // No order below, changing direction
+            info!("Changing direction");
+            orders.front().cloned()
         }
+        // Pick the first order
+        CallType::Cab => orders.pop_front(),
+    };
+
+    current_orders[0] = order_found.clone();
+    debug!(next = ?current_orders[0], "Next order");
+    order_found
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elevator::{NUM_FLOORS, elevio::protocol::Floor};
+
+    fn cab(floor: u8) -> CallButton {
+        CallButton { floor: Floor::new(floor, NUM_FLOORS).unwrap(), call: CallType::Cab }
+    }
+
+    fn hall(floor: u8, call: CallType) -> CallButton {
+        CallButton { floor: Floor::new(floor, NUM_FLOORS).unwrap(), call }
+    }
+
+    // The car at `position` on its way to `current`, with `queued` waiting
+    fn car(position: u8, current: CallButton, queued: &[CallButton]) -> (VecDeque<CallButton>, Vec<Option<u8>>, Vec<Option<CallButton>>) {
+        let mut positions = vec![None; n as usize];
+        positions[0] = Some(position);
+        let mut current_orders = vec![None; n as usize];
+        current_orders[0] = Some(current);
+        (queued.iter().cloned().collect(), positions, current_orders)
+    }
+
+    #[test]
+    fn the_nearest_order_on_the_way_is_served_first() {
+        // The last match in the queue is the farther one, which the car used to go to first
+        let (mut orders, positions, mut current) = car(0, cab(3), &[cab(1)]);
+        assert!(assign_new_orders(cab(2), &mut orders, &positions, &mut current));
+        assert_eq!(current[0], Some(cab(1)));
+        assert_eq!(orders, [cab(3), cab(2)]);
+
+        let (mut orders, positions, mut current) = car(3, cab(0), &[cab(2)]);
+        assert!(assign_new_orders(cab(1), &mut orders, &positions, &mut current));
+        assert_eq!(current[0], Some(cab(2)));
     }
 
-    current_orders[0] = order_found.0.clone();
-    // println!("Next order: {:?}", current_orders[0]);
-    return (order_found.0, order_found.1);
+    #[test]
+    fn orders_behind_the_car_are_not_on_the_way() {
+        let (mut orders, positions, mut current) = car(2, cab(3), &[]);
+        assert!(!assign_new_orders(cab(1), &mut orders, &positions, &mut current));
+        assert_eq!(current[0], Some(cab(3)));
 
-}
\ No newline at end of file
+        let (mut orders, positions, mut current) = car(2, cab(0), &[]);
+        assert!(!assign_new_orders(cab(3), &mut orders, &positions, &mut current));
+        assert_eq!(current[0], Some(cab(0)));
+    }
+
+    #[test]
+    fn hall_calls_are_on_the_way_in_the_cars_direction_only() {
+        let (mut orders, positions, mut current) = car(0, cab(3), &[]);
+        assert!(!assign_new_orders(hall(1, CallType::HallDown), &mut orders, &positions, &mut current));
+        assert!(assign_new_orders(hall(2, CallType::HallUp), &mut orders, &positions, &mut current));
+        assert_eq!(current[0], Some(hall(2, CallType::HallUp)));
+
+        // Going down to a hall up call, the hall down calls on the way are stops
+        let (mut orders, positions, mut current) = car(3, hall(0, CallType::HallUp), &[]);
+        assert!(assign_new_orders(hall(1, CallType::HallDown), &mut orders, &positions, &mut current));
+        assert_eq!(current[0], Some(hall(1, CallType::HallDown)));
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::VecDeque;
+use tracing::warn;
+
+use crate::elevator::elevio::{poll::CallButton, protocol::{CallType, Direction}};
+
+// Which requests a stop serves, `all` or `direction`, e.g. ELEV_CLEARING=all
+pub const CLEARING_ENV: &str = "ELEV_CLEARING";
+
+// Which of the requests at a floor are served when the car stops there
+//
+//   all         every request at the floor, whichever way the passengers are going. Hall
+//               calls there that the group gave to other cars are served as well.
+//   direction   the cab call and the hall call in the direction the car leaves in. A hall
+//               call the other way is left for the way back, unless the car turns around
+//               here: then the door opens once more for it, so the passengers waiting
+//               there see the car change direction before they get in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClearingPolicy {
+    All,
+    #[default]
+    InDirection,
+}
+
+// What one stop serves
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Cleared {
+    pub now: Vec<CallButton>,           // Served by the door opening that just ended
+    pub reopen: Option<CallButton>,     // Served by opening the door again, the car changes direction
+}
+
+impl ClearingPolicy {
+    pub fn from_env() -> ClearingPolicy {
+        match std::env::var(CLEARING_ENV).as_deref() {
+            Ok("all") => ClearingPolicy::All,
+            Ok("direction") | Err(_) => ClearingPolicy::InDirection,
+            Ok(other) => {
+                warn!(policy = other, "Unknown clearing policy, clearing in the direction of travel");
+                ClearingPolicy::InDirection
+            }
+        }
+    }
+
+    // The requests served by the car stopping for `served`, out of `served` and `orders`.
+    // `travel` is the way the car came to the floor, Stop if it was standing there already.
+    pub fn clear(self, served: &CallButton, travel: Direction, orders: &VecDeque<CallButton>) -> Cleared {
+        let floor = served.floor;
+        let here = |call: CallType| served.call == call || orders.contains(&CallButton { floor, call });
+        let button = |call: CallType| CallButton { floor, call };
+
+        // A cab call is served by any door opening, if there is one
+        let mut cleared = Cleared { now: Vec::new(), reopen: None };
+        if here(CallType::Cab) {
+            cleared.now.push(button(CallType::Cab));
+        }
+        let halls = CallType::HALL.into_iter().filter(|call| here(*call));
+        if self == ClearingPolicy::All {
+            cleared.now.extend(halls.map(button));
+            return cleared;
+        }
+
+        // A hall call says which way the car goes next, otherwise it carries on the way it
+        // came, or heads for the first order elsewhere when it was standing still
+        let leaving = served.call.direction().unwrap_or(match travel {
+            Direction::Stop => orders.iter().find(|order| order.floor != floor).map_or(Direction::Stop, |order| direction(floor.get(), order.floor.get())),
+            travel => travel,
+        });
+        let (along, against) = match leaving {
+            Direction::Up => (CallType::HallUp, CallType::HallDown),
+            Direction::Down => (CallType::HallDown, CallType::HallUp),
+            Direction::Stop => {
+                cleared.now.extend(halls.map(button));
+                return cleared;
+            }
+        };
+
+        if here(along) {
+            cleared.now.push(button(along));
+        }
+        let turning = !orders.iter().any(|order| direction(floor.get(), order.floor.get()) == leaving);
+        if here(against) && turning {
+            if here(along) {
+                cleared.reopen = Some(button(against));
+            } else {
+                cleared.now.push(button(against));
+            }
+        }
+        cleared
+    }
+}
+
+
+// ---------- PURE FUNCTIONS ----------
+
+// The way from floor `from` to floor `to`
+pub fn direction(from: u8, to: u8) -> Direction {
+    match to.cmp(&from) {
+        std::cmp::Ordering::Greater => Direction::Up,
+        std::cmp::Ordering::Less => Direction::Down,
+        std::cmp::Ordering::Equal => Direction::Stop,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elevator::{NUM_FLOORS, elevio::protocol::Floor};
+
+    const FLOOR: Floor = Floor::new(2, NUM_FLOORS).unwrap();
+    const CAB: CallButton = CallButton { floor: FLOOR, call: CallType::Cab };
+    const UP: CallButton = CallButton { floor: FLOOR, call: CallType::HallUp };
+    const DOWN: CallButton = CallButton { floor: FLOOR, call: CallType::HallDown };
+
+    // Stop at FLOOR for `served`, with `waiting` also requested there and an order at `elsewhere`
+    fn stop(policy: ClearingPolicy, served: CallButton, travel: Direction, waiting: &[CallButton], elsewhere: Option<u8>) -> Cleared {
+        let mut orders: VecDeque<CallButton> = waiting.iter().cloned().collect();
+        orders.extend(elsewhere.map(|floor| CallButton { floor: Floor::new(floor, NUM_FLOORS).unwrap(), call: CallType::Cab }));
+        policy.clear(&served, travel, &orders)
+    }
+
+    fn cleared(now: &[CallButton], reopen: Option<CallButton>) -> Cleared {
+        Cleared { now: now.to_vec(), reopen }
+    }
+
+    #[test]
+    fn all_serves_every_request_at_the_floor() {
+        for served in [CAB, UP, DOWN] {
+            for travel in [Direction::Up, Direction::Stop, Direction::Down] {
+                for elsewhere in [None, Some(0), Some(3)] {
+                    let result = stop(ClearingPolicy::All, served.clone(), travel, &[CAB, UP, DOWN], elsewhere);
+                    assert_eq!(result, cleared(&[CAB, UP, DOWN], None), "{served:?} {travel:?} {elsewhere:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn all_only_clears_what_was_requested() {
+        assert_eq!(stop(ClearingPolicy::All, CAB, Direction::Up, &[], Some(3)), cleared(&[CAB], None));
+        assert_eq!(stop(ClearingPolicy::All, UP, Direction::Down, &[], None), cleared(&[UP], None));
+        assert_eq!(stop(ClearingPolicy::All, CAB, Direction::Down, &[DOWN], Some(0)), cleared(&[CAB, DOWN], None));
+        assert_eq!(stop(ClearingPolicy::All, DOWN, Direction::Down, &[CAB], None), cleared(&[CAB, DOWN], None));
+    }
+
+    #[test]
+    fn direction_clears_a_cab_call_only_if_there_is_one() {
+        assert_eq!(stop(ClearingPolicy::InDirection, UP, Direction::Up, &[], Some(3)), cleared(&[UP], None));
+        assert_eq!(stop(ClearingPolicy::InDirection, UP, Direction::Up, &[CAB], Some(3)), cleared(&[CAB, UP], None));
+        assert_eq!(stop(ClearingPolicy::InDirection, DOWN, Direction::Stop, &[CAB, UP], None), cleared(&[CAB, DOWN], Some(UP)));
+    }
+
+    #[test]
+    fn direction_leaves_the_call_against_travel_for_the_way_back() {
+        assert_eq!(stop(ClearingPolicy::InDirection, CAB, Direction::Up, &[UP, DOWN], Some(3)), cleared(&[CAB, UP], None));
+        assert_eq!(stop(ClearingPolicy::InDirection, CAB, Direction::Down, &[UP, DOWN], Some(0)), cleared(&[CAB, DOWN], None));
+        assert_eq!(stop(ClearingPolicy::InDirection, CAB, Direction::Up, &[DOWN], Some(3)), cleared(&[CAB], None));
+        assert_eq!(stop(ClearingPolicy::InDirection, CAB, Direction::Down, &[UP], Some(0)), cleared(&[CAB], None));
+        assert_eq!(stop(ClearingPolicy::InDirection, UP, Direction::Up, &[DOWN], Some(3)), cleared(&[UP], None));
+        assert_eq!(stop(ClearingPolicy::InDirection, DOWN, Direction::Down, &[UP], Some(0)), cleared(&[DOWN], None));
+    }
+
+    #[test]
+    fn direction_reopens_the_door_when_turning_with_both_calls() {
+        for elsewhere in [None, Some(0)] {
+            assert_eq!(stop(ClearingPolicy::InDirection, CAB, Direction::Up, &[UP, DOWN], elsewhere), cleared(&[CAB, UP], Some(DOWN)));
+            assert_eq!(stop(ClearingPolicy::InDirection, UP, Direction::Up, &[DOWN], elsewhere), cleared(&[UP], Some(DOWN)));
+        }
+        for elsewhere in [None, Some(3)] {
+            assert_eq!(stop(ClearingPolicy::InDirection, CAB, Direction::Down, &[UP, DOWN], elsewhere), cleared(&[CAB, DOWN], Some(UP)));
+            assert_eq!(stop(ClearingPolicy::InDirection, DOWN, Direction::Down, &[UP], elsewhere), cleared(&[DOWN], Some(UP)));
+        }
+    }
+
+    #[test]
+    fn direction_serves_the_only_call_against_travel_when_turning() {
+        assert_eq!(stop(ClearingPolicy::InDirection, CAB, Direction::Up, &[DOWN], None), cleared(&[CAB, DOWN], None));
+        assert_eq!(stop(ClearingPolicy::InDirection, CAB, Direction::Up, &[DOWN], Some(0)), cleared(&[CAB, DOWN], None));
+        assert_eq!(stop(ClearingPolicy::InDirection, CAB, Direction::Down, &[UP], None), cleared(&[CAB, UP], None));
+        assert_eq!(stop(ClearingPolicy::InDirection, DOWN, Direction::Up, &[], Some(0)), cleared(&[DOWN], None));
+    }
+
+    #[test]
+    fn direction_follows_the_served_hall_call_not_the_travel() {
+        // Came down to a hall up call, and leaves upwards
+        assert_eq!(stop(ClearingPolicy::InDirection, UP, Direction::Down, &[DOWN], Some(0)), cleared(&[UP], Some(DOWN)));
+        assert_eq!(stop(ClearingPolicy::InDirection, UP, Direction::Down, &[DOWN], Some(3)), cleared(&[UP], None));
+        assert_eq!(stop(ClearingPolicy::InDirection, DOWN, Direction::Up, &[UP], Some(0)), cleared(&[DOWN], None));
+    }
+
+    #[test]
+    fn direction_heads_for_the_next_order_when_standing_still() {
+        assert_eq!(stop(ClearingPolicy::InDirection, CAB, Direction::Stop, &[UP, DOWN], Some(3)), cleared(&[CAB, UP], None));
+        assert_eq!(stop(ClearingPolicy::InDirection, CAB, Direction::Stop, &[UP, DOWN], Some(0)), cleared(&[CAB, DOWN], None));
+        assert_eq!(stop(ClearingPolicy::InDirection, CAB, Direction::Stop, &[UP, DOWN], None), cleared(&[CAB, UP, DOWN], None));
+        assert_eq!(stop(ClearingPolicy::InDirection, CAB, Direction::Stop, &[], None), cleared(&[CAB], None));
+    }
+
+    #[test]
+    fn directions_between_floors() {
+        assert_eq!(direction(1, 3), Direction::Up);
+        assert_eq!(direction(3, 1), Direction::Down);
+        assert_eq!(direction(2, 2), Direction::Stop);
+    }
+}
@@ -0,0 +1,161 @@
+use std::{fs, io::{self, Write}, path::{Path, PathBuf}};
+use tokio::sync::watch;
+
+use crate::elevator::elevio::poll::CallButton;
+
+// Directory the order snapshot is written to, defaults to the working directory
+pub const STATE_DIR_ENV: &str = "ELEV_STATE_DIR";
+
+// Snapshot of the orders this elevator has accepted, replaced atomically on every change.
+// The new snapshot is written to a temporary file, fsynced and renamed over the old one,
+// so a crash at any point leaves either the old or the new snapshot on disk, never a mix.
+pub struct OrderStore {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    replica: Option<watch::Sender<Vec<CallButton>>>,    // Also gets every snapshot, e.g. for a backup process
+}
+
+impl OrderStore {
+    pub fn new(path: PathBuf) -> OrderStore {
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        OrderStore { path, tmp_path: tmp_path.into(), replica: None }
+    }
+
+    pub fn for_node(id: u8) -> OrderStore {
+        let dir = std::env::var(STATE_DIR_ENV).unwrap_or_else(|_| ".".to_string());
+        OrderStore::new(Path::new(&dir).join(format!("orders_{id}.json")))
+    }
+
+    // Read the last snapshot, an empty list if none has been written yet
+    pub fn load(&self) -> io::Result<Vec<CallButton>> {
+        match fs::read(&self.path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Publish every snapshot saved from now on to `replica` as well
+    pub fn replicate(&mut self, replica: watch::Sender<Vec<CallButton>>) {
+        self.replica = Some(replica);
+    }
+
+    pub fn save(&self, orders: &[CallButton]) -> io::Result<()> {
+        // First, so the replica has the orders even when the disk fails
+        if let Some(replica) = &self.replica {
+            replica.send_replace(orders.to_vec());
+        }
+
+        let bytes = serde_json::to_vec(orders).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let mut file = fs::File::create(&self.tmp_path)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&self.tmp_path, &self.path)?;
+
+        // Make the rename itself durable
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elevator::{NUM_FLOORS, elevio::protocol::{CallType, Floor}};
+
+    // A fresh directory for one test, removed again when dropped
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> TestDir {
+            let dir = std::env::temp_dir().join(format!("elevator_store_{}_{name}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TestDir(dir)
+        }
+
+        fn store(&self) -> OrderStore {
+            OrderStore::new(self.0.join("orders_1.json"))
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn floor(floor: u8) -> Floor {
+        Floor::new(floor, NUM_FLOORS).unwrap()
+    }
+
+    fn orders() -> Vec<CallButton> {
+        vec![
+            CallButton { floor: floor(3), call: CallType::Cab },
+            CallButton { floor: floor(0), call: CallType::HallUp },
+            CallButton { floor: floor(2), call: CallType::HallDown },
+        ]
+    }
+
+    #[test]
+    fn nothing_saved_loads_no_orders() {
+        let dir = TestDir::new("empty");
+        assert_eq!(dir.store().load().unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn saved_orders_are_replayed_by_the_next_store() {
+        let dir = TestDir::new("replay");
+        let store = dir.store();
+        store.save(&orders()[..1]).unwrap();
+        store.save(&orders()).unwrap();
+        drop(store);
+
+        assert_eq!(dir.store().load().unwrap(), orders());
+        assert!(!dir.store().tmp_path.exists());
+    }
+
+    #[test]
+    fn a_leftover_tmp_file_is_ignored() {
+        let dir = TestDir::new("tmp");
+        let store = dir.store();
+        store.save(&orders()).unwrap();
+
+        // A crash half way through the next save
+        fs::write(&store.tmp_path, b"[{\"floor\":1,").unwrap();
+        drop(store);
+        assert_eq!(dir.store().load().unwrap(), orders());
+
+        // And the next save replaces it
+        dir.store().save(&orders()[1..]).unwrap();
+        assert_eq!(dir.store().load().unwrap(), orders()[1..]);
+    }
+
+    #[test]
+    fn every_save_is_replicated() {
+        let dir = TestDir::new("replica");
+        let mut store = dir.store();
+        let (replica_tx, replica_rx) = watch::channel(Vec::new());
+        store.replicate(replica_tx);
+
+        store.save(&orders()).unwrap();
+        assert_eq!(*replica_rx.borrow(), orders());
+
+        // Even when the disk fails
+        fs::remove_dir_all(&dir.0).unwrap();
+        assert!(store.save(&orders()[..1]).is_err());
+        assert_eq!(*replica_rx.borrow(), orders()[..1]);
+    }
+
+    #[test]
+    fn a_corrupt_snapshot_is_an_error() {
+        let dir = TestDir::new("corrupt");
+        fs::write(dir.0.join("orders_1.json"), b"not json").unwrap();
+        assert_eq!(dir.store().load().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::VecDeque;
+
+use crate::elevator::elevio::{poll::CallButton, protocol::{CallType, Direction}};
+use super::clearing::direction;
+
+// The stops the car makes on its way from `position` to `target`, nearest first and ending
+// with `target`, one per floor. On the way it stops for the orders strictly between the two
+// that go its way: cab calls, and hall calls in its direction of travel. For the ETA and
+// lamp logic as much as for picking where the car goes next.
+pub fn stops(position: u8, target: &CallButton, orders: &VecDeque<CallButton>) -> Vec<CallButton> {
+    let travel = direction(position, target.floor.get());
+    let along = match travel {
+        Direction::Up => CallType::HallUp,
+        Direction::Down => CallType::HallDown,
+        Direction::Stop => return vec![target.clone()],
+    };
+
+    let mut stops: Vec<CallButton> = orders.iter()
+        .filter(|order| order.call == CallType::Cab || order.call == along)
+        .filter(|order| direction(position, order.floor.get()) == travel && direction(order.floor.get(), target.floor.get()) == travel)
+        .cloned()
+        .collect();
+
+    // Stable, so the first order queued at a floor stands for it
+    stops.sort_by_key(|order| order.floor.get().abs_diff(position));
+    stops.dedup_by_key(|order| order.floor);
+    stops.push(target.clone());
+    stops
+}
+
+
+// The stops of the sweep the car is on, `current` first: on in the direction of `current`
+// to the farthest order that way. Orders are added and served as the car goes, so this is
+// only the plan as of now.
+pub fn sweep(position: u8, current: &CallButton, orders: &VecDeque<CallButton>) -> Vec<CallButton> {
+    let travel = direction(position, current.floor.get());
+    let end = orders.iter()
+        .filter(|order| direction(position, order.floor.get()) == travel)
+        .fold(current, |end, order| if order.floor.get().abs_diff(position) > end.floor.get().abs_diff(position) { order } else { end });
+
+    // Ahead of the queue, so `current` stands for its floor
+    let mut candidates = orders.clone();
+    candidates.push_front(current.clone());
+    let sweep = stops(position, end, &candidates);
+
+    // A current order the sweep would pass by is where the car goes first all the same
+    if sweep.first() == Some(current) { sweep } else { stops(position, current, orders) }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elevator::{NUM_FLOORS, elevio::protocol::Floor};
+
+    fn cab(floor: u8) -> CallButton {
+        CallButton { floor: Floor::new(floor, NUM_FLOORS).unwrap(), call: CallType::Cab }
+    }
+
+    fn up(floor: u8) -> CallButton {
+        CallButton { floor: Floor::new(floor, NUM_FLOORS).unwrap(), call: CallType::HallUp }
+    }
+
+    fn down(floor: u8) -> CallButton {
+        CallButton { floor: Floor::new(floor, NUM_FLOORS).unwrap(), call: CallType::HallDown }
+    }
+
+    fn plan(position: u8, target: CallButton, orders: &[CallButton]) -> Vec<CallButton> {
+        stops(position, &target, &orders.iter().cloned().collect())
+    }
+
+    #[test]
+    fn nearest_stop_comes_first_whatever_the_queue_order() {
+        assert_eq!(plan(0, cab(3), &[cab(1), cab(2)]), vec![cab(1), cab(2), cab(3)]);
+        assert_eq!(plan(0, cab(3), &[cab(2), cab(1)]), vec![cab(1), cab(2), cab(3)]);
+        assert_eq!(plan(3, cab(0), &[cab(1), cab(2)]), vec![cab(2), cab(1), cab(0)]);
+        assert_eq!(plan(3, cab(0), &[cab(2), cab(1)]), vec![cab(2), cab(1), cab(0)]);
+    }
+
+    #[test]
+    fn only_hall_calls_going_the_cars_way_are_stops() {
+        assert_eq!(plan(0, cab(3), &[down(1), up(2)]), vec![up(2), cab(3)]);
+        assert_eq!(plan(3, cab(0), &[down(1), up(2)]), vec![down(1), cab(0)]);
+    }
+
+    #[test]
+    fn the_direction_is_the_cars_not_the_targets() {
+        // Going down to pick up someone going up, hall down calls on the way are stops
+        assert_eq!(plan(3, up(0), &[down(2), up(1)]), vec![down(2), up(0)]);
+        assert_eq!(plan(0, down(3), &[up(1), down(2)]), vec![up(1), down(3)]);
+    }
+
+    #[test]
+    fn orders_behind_the_car_or_past_the_target_are_not_stops() {
+        assert_eq!(plan(2, cab(3), &[cab(0), cab(1), cab(2), up(2)]), vec![cab(3)]);
+        assert_eq!(plan(1, cab(0), &[cab(2), cab(3), down(1)]), vec![cab(0)]);
+        assert_eq!(plan(0, cab(2), &[cab(3), up(3)]), vec![cab(2)]);
+    }
+
+    #[test]
+    fn a_floor_is_one_stop() {
+        assert_eq!(plan(0, cab(3), &[up(1), cab(1), cab(2)]), vec![up(1), cab(2), cab(3)]);
+        assert_eq!(plan(0, cab(3), &[cab(3), up(3)]), vec![cab(3)]);
+    }
+
+    #[test]
+    fn a_target_at_the_cars_floor_is_the_only_stop() {
+        assert_eq!(plan(2, cab(2), &[cab(1), cab(3)]), vec![cab(2)]);
+    }
+
+    fn sweep_from(position: u8, current: CallButton, orders: &[CallButton]) -> Vec<CallButton> {
+        sweep(position, &current, &orders.iter().cloned().collect())
+    }
+
+    #[test]
+    fn a_sweep_goes_on_to_the_farthest_order_that_way() {
+        assert_eq!(sweep_from(0, cab(1), &[cab(3), cab(2)]), vec![cab(1), cab(2), cab(3)]);
+        assert_eq!(sweep_from(0, cab(1), &[up(1), cab(3)]), vec![cab(1), cab(3)]);
+        assert_eq!(sweep_from(3, cab(2), &[cab(0), down(1), up(1)]), vec![cab(2), down(1), cab(0)]);
+        assert_eq!(sweep_from(2, cab(3), &[cab(0), cab(1)]), vec![cab(3)]);
+    }
+
+    #[test]
+    fn a_sweep_turns_at_a_hall_call_the_other_way() {
+        assert_eq!(sweep_from(0, cab(1), &[down(3), down(2), cab(2)]), vec![cab(1), cab(2), down(3)]);
+    }
+
+    #[test]
+    fn a_sweep_starts_with_the_current_order() {
+        assert_eq!(sweep_from(0, down(2), &[cab(3)]), vec![down(2)]);
+        assert_eq!(sweep_from(2, cab(2), &[cab(3)]), vec![cab(2)]);
+    }
+}
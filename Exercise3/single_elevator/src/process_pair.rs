@@ -0,0 +1,269 @@
+pub mod snapshot;
+
+use std::{fs, io, net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use tokio::{net::UdpSocket, sync::{watch, Notify}};
+use serde::{Serialize, de::DeserializeOwned};
+use tracing::{info, warn};
+
+use snapshot::Snapshot;
+use crate::clock::SharedClock;
+
+pub const DEFAULT_PERIOD: Duration = Duration::from_millis(1000);
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(3000);
+const SPAWN_ATTEMPTS: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Primary,    // Start shipping state right away
+    Backup,     // Wait for the primary to go silent first
+}
+
+impl FromStr for Role {
+    type Err = io::Error;
+
+    fn from_str(role: &str) -> io::Result<Role> {
+        match role {
+            "primary" => Ok(Role::Primary),
+            "backup" => Ok(Role::Backup),
+            _ => Err(invalid(role)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PairConfig {
+    pub local: SocketAddr,      // Where this process listens for snapshots while it is the backup
+    pub remote: SocketAddr,     // Where the primary ships snapshots to
+    pub period: Duration,       // Time between snapshots from the primary
+    pub timeout: Duration,      // Silence after which the backup takes over
+    pub role: Role,
+}
+
+impl PairConfig {
+
+    // Parse `<local addr> <remote addr> [period ms] [timeout ms] [--role primary|backup]`
+    pub fn from_args(mut args: impl Iterator<Item = String>) -> io::Result<PairConfig> {
+        let mut positional = Vec::new();
+        let mut role = Role::Backup;
+
+        while let Some(arg) = args.next() {
+            if arg == "--role" {
+                role = args.next().ok_or_else(|| invalid(&arg))?.parse()?;
+            } else {
+                positional.push(arg);
+            }
+        }
+
+        let mut positional = positional.into_iter();
+        let mut address = |what: &str| -> io::Result<SocketAddr> {
+            let value = positional.next().ok_or_else(|| invalid(&format!("missing {what} address")))?;
+            value.parse().map_err(|_| invalid(&value))
+        };
+        let (local, remote) = (address("local")?, address("remote")?);
+        let mut millis = |default: Duration| -> io::Result<Duration> {
+            match positional.next() {
+                Some(ms) => ms.parse().map(Duration::from_millis).map_err(|_| invalid(&ms)),
+                None => Ok(default),
+            }
+        };
+        let (period, timeout) = (millis(DEFAULT_PERIOD)?, millis(DEFAULT_TIMEOUT)?);
+        if let Some(extra) = positional.next() {
+            return Err(invalid(&extra));
+        }
+
+        Ok(PairConfig { local, remote, period, timeout, role })
+    }
+
+    // The arguments `from_args` takes to start the other half of the pair as the backup
+    pub fn backup_args(&self) -> Vec<String> {
+        vec![
+            self.remote.to_string(),
+            self.local.to_string(),
+            self.period.as_millis().to_string(),
+            self.timeout.as_millis().to_string(),
+            "--role".to_string(),
+            "backup".to_string(),
+        ]
+    }
+}
+
+pub async fn bind(local: SocketAddr) -> io::Result<Arc<UdpSocket>> {
+    Ok(Arc::new(UdpSocket::bind(local).await?))
+}
+
+// Act as backup until the primary has been silent for `timeout`, telling the primary
+// we are alive meanwhile. Returns the newest valid snapshot received, starting from
+// `latest`, or None if no primary ever sent one.
+pub async fn backup<S: DeserializeOwned>(sock: Arc<UdpSocket>, clock: SharedClock, config: &PairConfig, mut latest: Option<Snapshot<S>>) -> Option<Snapshot<S>> {
+
+    let (heartbeat_sock, heartbeat_clock) = (sock.clone(), clock.clone());
+    let (remote, period) = (config.remote, config.period);
+    let heartbeat = tokio::spawn(async move {
+        loop {
+            let _ = heartbeat_sock.send_to(snapshot::HEARTBEAT, remote).await;
+            heartbeat_clock.sleep(period).await;
+        }
+    });
+
+    let mut buf = vec![0; 65536];
+
+    loop {
+        let deadline = clock.now() + config.timeout;
+        let len = tokio::select! {
+            received = sock.recv(&mut buf) => match received {
+                Ok(len) => len,
+                Err(_) => continue,
+            },
+            _ = clock.sleep_until(deadline) => {
+                info!(timeout_ms = config.timeout.as_millis() as u64, "No snapshot from the primary, taking over");
+                heartbeat.abort();
+                return latest;
+            }
+        };
+
+        // Snapshots can arrive out of order, and an old primary may still be talking, only move forward
+        match snapshot::decode::<S>(&buf[..len]) {
+            Some(snapshot) if latest.as_ref().is_none_or(|l| snapshot.is_newer_than(l)) => {
+                info!(epoch = snapshot.epoch, version = snapshot.version, "Received snapshot");
+                latest = Some(snapshot);
+            }
+            Some(_) => (),
+            None => warn!(len, "Dropped corrupt snapshot"),
+        }
+    }
+}
+
+// Ship the current state to the backup every period, with an increasing version.
+// Returns as soon as another primary with a higher epoch shows up, with the snapshot
+// that proved it, so the caller can step down. On equal epochs the lowest address wins.
+pub async fn primary<S: Serialize + DeserializeOwned>(sock: Arc<UdpSocket>, clock: SharedClock, config: &PairConfig, state: watch::Receiver<S>, epoch: u64, mut version: u64, heartbeat: Arc<Notify>) -> Snapshot<S> {
+
+    let mut next_ship = clock.now();
+    let mut buf = vec![0; 65536];
+
+    loop {
+        tokio::select! {
+            _ = clock.sleep_until(next_ship) => {
+                next_ship += config.period;
+                version += 1;
+                let frame = snapshot::encode(epoch, version, &*state.borrow());
+                let _ = sock.send_to(&frame, config.remote).await;
+            }
+
+            Ok((len, from)) = sock.recv_from(&mut buf) => {
+                if &buf[..len] == snapshot::HEARTBEAT {
+                    heartbeat.notify_waiters();
+                    continue;
+                }
+                if let Some(snapshot) = snapshot::decode::<S>(&buf[..len])
+                    && (snapshot.epoch > epoch || (snapshot.epoch == epoch && from < config.local)) {
+                    warn!(%from, theirs = snapshot.epoch, ours = epoch, "Newer primary, stepping down");
+                    return snapshot;
+                }
+            }
+        }
+    }
+}
+
+// Start a backup by re-running this binary with `args`, detached from our terminal and
+// with its output appended to a log file. The spawn only counts once the backup's first
+// heartbeat has arrived, otherwise it is killed and tried again.
+// Heartbeats are received by `primary`, which passes them on through `heartbeat`.
+pub async fn spawn_backup(clock: SharedClock, config: &PairConfig, args: &[String], heartbeat: Arc<Notify>) -> io::Result<Child> {
+
+    let exe = std::env::current_exe()?;
+    let log_path = format!("backup_{}.log", config.remote.port());
+
+    for attempt in 1..=SPAWN_ATTEMPTS {
+        let log = fs::OpenOptions::new().create(true).append(true).open(&log_path)?;
+        let mut starting = Starting(Some(Command::new(&exe)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            .process_group(0)
+            .spawn()?));
+
+        let started = tokio::select! {
+            _ = heartbeat.notified() => true,
+            _ = clock.sleep(config.timeout) => false,
+        };
+        if let Some(child) = starting.0.take().filter(|_| started) {
+            info!(pid = child.id(), log = log_path, "Backup started");
+            return Ok(child);
+        }
+        warn!(attempt, attempts = SPAWN_ATTEMPTS, "No heartbeat from backup");
+    }
+
+    Err(io::Error::new(io::ErrorKind::TimedOut, "backup never sent a heartbeat"))
+}
+
+// A backup that has not sent its first heartbeat yet. It is killed if it never does,
+// or if `spawn_backup` is dropped while waiting, e.g. when the primary steps down.
+struct Starting(Option<Child>);
+
+impl Drop for Starting {
+    fn drop(&mut self) {
+        if let Some(child) = &mut self.0 {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+
+
+// ---------- PURE FUNCTIONS ----------
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("invalid argument: {what}"))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> io::Result<PairConfig> {
+        PairConfig::from_args(args.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn parses_addresses_times_and_role() {
+        let config = parse("127.0.0.1:4000 127.0.0.1:4001 200 900 --role primary").unwrap();
+        assert_eq!(config.local, "127.0.0.1:4000".parse().unwrap());
+        assert_eq!(config.remote, "127.0.0.1:4001".parse().unwrap());
+        assert_eq!((config.period, config.timeout), (Duration::from_millis(200), Duration::from_millis(900)));
+        assert_eq!(config.role, Role::Primary);
+
+        let config = parse("--role backup 127.0.0.1:4000 127.0.0.1:4001").unwrap();
+        assert_eq!((config.period, config.timeout, config.role), (DEFAULT_PERIOD, DEFAULT_TIMEOUT, Role::Backup));
+    }
+
+    #[test]
+    fn bad_arguments_are_errors() {
+        for args in ["", "127.0.0.1:4000", "here 127.0.0.1:4001", "127.0.0.1:4000 127.0.0.1:4001 soon",
+                     "127.0.0.1:4000 127.0.0.1:4001 --role", "127.0.0.1:4000 127.0.0.1:4001 --role boss",
+                     "127.0.0.1:4000 127.0.0.1:4001 1 2 3"] {
+            assert_eq!(parse(args).unwrap_err().kind(), io::ErrorKind::InvalidInput, "{args}");
+        }
+    }
+
+    #[test]
+    fn the_backup_gets_the_addresses_swapped() {
+        let config = parse("127.0.0.1:4000 127.0.0.1:4001 200 900 --role primary").unwrap();
+        let backup = PairConfig::from_args(config.backup_args().into_iter()).unwrap();
+        assert_eq!((backup.local, backup.remote), (config.remote, config.local));
+        assert_eq!((backup.period, backup.timeout, backup.role), (config.period, config.timeout, Role::Backup));
+    }
+
+    #[test]
+    fn a_backup_still_starting_is_killed_when_dropped() {
+        let child = Command::new("sleep").arg("30").spawn().unwrap();
+        let pid = child.id();
+        drop(Starting(Some(child)));
+        assert!(!std::path::Path::new(&format!("/proc/{pid}")).exists());
+    }
+}
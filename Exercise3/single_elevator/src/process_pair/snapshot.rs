@@ -0,0 +1,120 @@
+use serde::{Serialize, de::DeserializeOwned};
+
+// Frame layout: [epoch: u64][version: u64][checksum: u32][state as json], integers big endian.
+// The checksum covers everything but itself, so a corrupted or truncated
+// frame is dropped instead of being taken over.
+const HEADER_LEN: usize = 20;
+
+// Sent by the backup to the primary, too short to be mistaken for a snapshot
+pub const HEARTBEAT: &[u8] = b"alive";
+
+// State shipped from the primary. The epoch is bumped on every takeover, the version on
+// every snapshot, so (epoch, version) orders all snapshots ever sent by any primary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot<S> {
+    pub epoch: u64,
+    pub version: u64,
+    pub state: S,
+}
+
+impl<S> Snapshot<S> {
+    pub fn is_newer_than(&self, other: &Snapshot<S>) -> bool {
+        (self.epoch, self.version) > (other.epoch, other.version)
+    }
+}
+
+pub fn encode<S: Serialize>(epoch: u64, version: u64, state: &S) -> Vec<u8> {
+    let payload = serde_json::to_vec(state).unwrap();
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&epoch.to_be_bytes());
+    frame.extend_from_slice(&version.to_be_bytes());
+    frame.extend_from_slice(&checksum(&frame, &payload).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+// Returns None if the frame is too short, fails the checksum or does not hold an `S`
+pub fn decode<S: DeserializeOwned>(frame: &[u8]) -> Option<Snapshot<S>> {
+    if frame.len() < HEADER_LEN {
+        return None;
+    }
+    let epoch = u64::from_be_bytes(frame[0..8].try_into().unwrap());
+    let version = u64::from_be_bytes(frame[8..16].try_into().unwrap());
+    let sum = u32::from_be_bytes(frame[16..20].try_into().unwrap());
+    let payload = &frame[HEADER_LEN..];
+    if checksum(&frame[..16], payload) != sum {
+        return None;
+    }
+    serde_json::from_slice(payload).ok().map(|state| Snapshot { epoch, version, state })
+}
+
+// CRC-32 (IEEE) of the header followed by the payload
+fn checksum(header: &[u8], payload: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in header.iter().chain(payload.iter()) {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> Vec<(u8, String)> {
+        vec![(3, "cab".to_string()), (0, "hall up".to_string())]
+    }
+
+    #[test]
+    fn a_frame_decodes_to_what_was_encoded() {
+        let frame = encode(7, 42, &state());
+        assert_eq!(decode(&frame), Some(Snapshot { epoch: 7, version: 42, state: state() }));
+        assert_eq!(decode::<u32>(&encode(u64::MAX, 0, &5u32)), Some(Snapshot { epoch: u64::MAX, version: 0, state: 5 }));
+    }
+
+    #[test]
+    fn the_checksum_is_crc32() {
+        // The standard check value, split between header and payload like a frame is
+        assert_eq!(checksum(b"1234", b"56789"), 0xCBF4_3926);
+        assert_eq!(checksum(b"", b""), 0);
+    }
+
+    #[test]
+    fn a_corrupted_frame_is_rejected() {
+        let frame = encode(7, 42, &state());
+        for i in 0..frame.len() {
+            for bit in 0..8 {
+                let mut corrupted = frame.clone();
+                corrupted[i] ^= 1 << bit;
+                assert_eq!(decode::<Vec<(u8, String)>>(&corrupted), None, "bit {bit} of byte {i}");
+            }
+        }
+    }
+
+    #[test]
+    fn a_truncated_frame_is_rejected() {
+        let frame = encode(7, 42, &state());
+        for len in 0..frame.len() {
+            assert_eq!(decode::<Vec<(u8, String)>>(&frame[..len]), None, "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn a_heartbeat_or_another_state_is_not_a_snapshot() {
+        assert_eq!(decode::<u32>(HEARTBEAT), None);
+        assert_eq!(decode::<u32>(&encode(1, 1, &state())), None);
+    }
+
+    #[test]
+    fn snapshots_are_ordered_by_epoch_then_version() {
+        let snapshot = |epoch, version| Snapshot { epoch, version, state: () };
+        assert!(snapshot(1, 2).is_newer_than(&snapshot(1, 1)));
+        assert!(snapshot(2, 0).is_newer_than(&snapshot(1, 9)));
+        assert!(!snapshot(1, 9).is_newer_than(&snapshot(2, 0)));
+        assert!(!snapshot(1, 1).is_newer_than(&snapshot(1, 1)));
+    }
+}
@@ -0,0 +1,322 @@
+use std::{cell::Cell, collections::VecDeque, fmt, fs, path::{Path, PathBuf}, sync::{Mutex, MutexGuard, OnceLock, TryLockError}, time::{SystemTime, UNIX_EPOCH}};
+use serde::Serialize;
+use serde_json::{Map, Value, json};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{Event, Subscriber, error, field::{Field, Visit}, info, span};
+use tracing_subscriber::{EnvFilter, Layer, layer::Context, registry::LookupSpan};
+
+use crate::order_management::persistence::STATE_DIR_ENV;
+
+// Which events are kept, in EnvFilter syntax. Everything from this crate by default,
+// including the network messages and driver commands that are too noisy for the log.
+pub const FILTER_ENV: &str = "ELEV_RECORDER";
+// Number of events kept, the oldest are dropped first
+pub const SIZE_ENV: &str = "ELEV_RECORDER_SIZE";
+
+const DEFAULT_FILTER: &str = "single_elevator=trace";
+const DEFAULT_SIZE: usize = 4096;
+
+// In-memory ring buffer of the most recent events, written to
+// flight_<id>_<unix ms>.json in ELEV_STATE_DIR when something goes wrong
+struct Recorder {
+    events: Mutex<VecDeque<Value>>,
+    capacity: usize,
+    world_view: Mutex<Option<Value>>,
+    node: OnceLock<u8>,
+}
+
+static RECORDER: OnceLock<Recorder> = OnceLock::new();
+
+thread_local! {
+    // Set while this thread is inside `on_event`, so events logged by a panic hook that
+    // interrupted it are skipped instead of waiting for the lock it holds
+    static RECORDING: Cell<bool> = const { Cell::new(false) };
+}
+
+fn recorder() -> &'static Recorder {
+    RECORDER.get_or_init(|| {
+        let capacity = std::env::var(SIZE_ENV).ok().and_then(|s| s.parse().ok()).unwrap_or(DEFAULT_SIZE);
+        Recorder::new(capacity)
+    })
+}
+
+// Layer feeding the ring buffer, installed next to the log output by `logging::init`
+pub fn layer<S>() -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let filter = EnvFilter::try_from_env(FILTER_ENV).unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+    RecorderLayer(recorder()).with_filter(filter)
+}
+
+// Used in the name of the dump file
+pub fn set_node(id: u8) {
+    let _ = recorder().node.set(id);
+}
+
+// Remember the latest world view, it is written along with the events
+pub fn record_world_view(view: &impl Serialize) {
+    if let Ok(view) = serde_json::to_value(view) {
+        *lock(&recorder().world_view) = Some(view);
+    }
+}
+
+// Write the buffered events and the last world view to a new file.
+// Returns the file written, if any.
+pub fn dump(reason: &str) -> Option<PathBuf> {
+    recorder().dump(reason, &state_dir(), false)
+}
+
+// The same from the panic hook, which must neither panic nor block. The panic may have
+// happened while this thread held one of the recorder's locks, so a section whose lock
+// is taken is left out of the file.
+pub fn dump_after_panic(reason: &str) -> Option<PathBuf> {
+    recorder().dump(reason, &state_dir(), true)
+}
+
+// Dump the flight recorder every time SIGUSR1 is received
+pub async fn dump_on_signal() {
+    let Ok(mut user) = signal(SignalKind::user_defined1()) else {
+        error!("Could not listen for SIGUSR1");
+        return;
+    };
+    while user.recv().await.is_some() {
+        dump("SIGUSR1");
+    }
+}
+
+
+impl Recorder {
+    fn new(capacity: usize) -> Recorder {
+        Recorder {
+            events: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            world_view: Mutex::new(None),
+            node: OnceLock::new(),
+        }
+    }
+
+    // Add an event, dropping the oldest when full
+    fn push(&self, record: Value) {
+        let mut events = lock(&self.events);
+        if events.len() >= self.capacity {
+            events.pop_front();
+        }
+        events.push_back(record);
+    }
+
+    // A section is null if its lock was taken after a panic, and `complete` says so
+    fn contents(&self, reason: &str, now: u64, after_panic: bool) -> Value {
+        let world_view = acquire(&self.world_view, after_panic).map(|view| view.clone());
+        let events = acquire(&self.events, after_panic).map(|events| events.iter().cloned().collect::<Vec<_>>());
+        json!({
+            "reason": reason,
+            "node": self.node.get().copied(),
+            "dumped_at": now,
+            "complete": world_view.is_some() && events.is_some(),
+            "world_view": world_view.flatten(),
+            "events": events,
+        })
+    }
+
+    fn dump(&self, reason: &str, dir: &Path, after_panic: bool) -> Option<PathBuf> {
+        let now = unix_millis();
+        let contents = self.contents(reason, now, after_panic);
+        let path = dir.join(format!("flight_{}_{}.json", self.node.get().copied().unwrap_or(0), now));
+        let written = serde_json::to_vec_pretty(&contents).ok().and_then(|bytes| fs::write(&path, bytes).ok());
+
+        match written {
+            Some(()) => {
+                info!(reason, path = %path.display(), "Flight recorder written");
+                Some(path)
+            }
+            None => {
+                error!(reason, path = %path.display(), "Could not write flight recorder");
+                None
+            }
+        }
+    }
+}
+
+
+struct RecorderLayer(&'static Recorder);
+
+// Clears RECORDING when `on_event` returns or unwinds
+struct Recording;
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        RECORDING.with(|recording| recording.set(false));
+    }
+}
+
+// Fields of a span formatted once when it is created, e.g. `id=1`
+struct SpanFields(String);
+
+impl<S> Layer<S> for RecorderLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let mut fields = FieldMap::default();
+        attrs.record(&mut fields);
+        let formatted = fields.0.iter().map(|(k, v)| format!("{k}={v}")).collect::<Vec<_>>().join(" ");
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanFields(formatted));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if RECORDING.with(|recording| recording.replace(true)) {
+            return;
+        }
+        let _recording = Recording;
+
+        let mut fields = FieldMap::default();
+        event.record(&mut fields);
+
+        let spans: Vec<String> = ctx.event_scope(event).into_iter().flat_map(|scope| scope.from_root()).map(|span| {
+            match span.extensions().get::<SpanFields>() {
+                Some(SpanFields(f)) if !f.is_empty() => format!("{}{{{}}}", span.name(), f),
+                _ => span.name().to_string(),
+            }
+        }).collect();
+
+        let record = json!({
+            "time": unix_millis(),
+            "level": event.metadata().level().as_str(),
+            "target": event.metadata().target(),
+            "spans": spans,
+            "fields": fields.0,
+        });
+
+        self.0.push(record);
+    }
+}
+
+#[derive(Default)]
+struct FieldMap(Map<String, Value>);
+
+impl Visit for FieldMap {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name().to_string(), Value::String(format!("{value:?}")));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), Value::String(value.to_string()));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+}
+
+
+// A panic while recording must not stop the dump in the panic hook
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// Only tried after a panic, None if the lock is taken
+fn acquire<T>(mutex: &Mutex<T>, after_panic: bool) -> Option<MutexGuard<'_, T>> {
+    if !after_panic {
+        return Some(lock(mutex));
+    }
+    match mutex.try_lock() {
+        Ok(guard) => Some(guard),
+        Err(TryLockError::Poisoned(poisoned)) => Some(poisoned.into_inner()),
+        Err(TryLockError::WouldBlock) => None,
+    }
+}
+
+fn state_dir() -> PathBuf {
+    PathBuf::from(std::env::var(STATE_DIR_ENV).unwrap_or_else(|_| ".".to_string()))
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::{debug, warn};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    fn leaked(capacity: usize) -> &'static Recorder {
+        Box::leak(Box::new(Recorder::new(capacity)))
+    }
+
+    fn events(recorder: &Recorder) -> Vec<Value> {
+        lock(&recorder.events).iter().cloned().collect()
+    }
+
+    #[test]
+    fn the_oldest_events_are_dropped_when_full() {
+        let recorder = Recorder::new(3);
+        for i in 0..5 {
+            recorder.push(json!(i));
+        }
+        assert_eq!(events(&recorder), vec![json!(2), json!(3), json!(4)]);
+    }
+
+    #[test]
+    fn only_events_passing_the_filter_are_kept() {
+        let recorder = leaked(16);
+        let subscriber = tracing_subscriber::registry().with(RecorderLayer(recorder).with_filter(EnvFilter::new("single_elevator=info")));
+        tracing::subscriber::with_default(subscriber, || {
+            debug!("too detailed");
+            info!(floor = 2, "kept");
+            warn!(stopped = true, "also kept");
+        });
+
+        let events = events(recorder);
+        let levels: Vec<_> = events.iter().map(|event| event["level"].as_str().unwrap()).collect();
+        assert_eq!(levels, ["INFO", "WARN"]);
+        assert_eq!(events[0]["fields"], json!({ "message": "kept", "floor": 2 }));
+        assert_eq!(events[1]["fields"]["stopped"], json!(true));
+    }
+
+    #[test]
+    fn dump_writes_the_reason_world_view_and_events() {
+        let dir = std::env::temp_dir().join(format!("elevator_recorder_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let recorder = Recorder::new(16);
+        let _ = recorder.node.set(3);
+        *lock(&recorder.world_view) = Some(json!({ "hall": [[1, "Up"]] }));
+        recorder.push(json!({ "level": "INFO" }));
+
+        let path = recorder.dump("test", &dir, false).unwrap();
+        assert!(path.file_name().unwrap().to_str().unwrap().starts_with("flight_3_"));
+        let written: Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(written["reason"], "test");
+        assert_eq!(written["node"], 3);
+        assert_eq!(written["complete"], true);
+        assert_eq!(written["world_view"], json!({ "hall": [[1, "Up"]] }));
+        assert_eq!(written["events"], json!([{ "level": "INFO" }]));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn after_a_panic_a_locked_section_is_left_out_instead_of_waited_for() {
+        let recorder = Recorder::new(16);
+        *lock(&recorder.world_view) = Some(json!({ "hall": [] }));
+        recorder.push(json!({ "level": "INFO" }));
+
+        let _held = lock(&recorder.events);
+        let contents = recorder.contents("panic", 0, true);
+        assert_eq!(contents["complete"], false);
+        assert_eq!(contents["events"], Value::Null);
+        assert_eq!(contents["world_view"], json!({ "hall": [] }));
+    }
+}
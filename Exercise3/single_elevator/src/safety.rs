@@ -0,0 +1,248 @@
+use std::{collections::{BTreeSet, VecDeque}, fmt::Write as _};
+use tokio::sync::{mpsc::{UnboundedReceiver as URx, UnboundedSender as UTx}, watch};
+use tokio::time::Instant;
+use tracing::error;
+
+use crate::clock::SharedClock;
+use crate::elevator::{ElevatorState, elevio::{elev::Elevio, protocol::{CallType, Command, Direction}}};
+use crate::networking::NetEvent;
+use crate::recorder;
+
+// Watches every output command sent to the car, and the state of the car, for things that
+// must never happen whatever the rest of the node gets wrong:
+//
+//   the motor runs while the door lamp is on
+//   the motor is driven up from the top floor or down from the bottom floor
+//   the car leaves a floor with a cab call there it has not opened the door for
+//
+// The last rule holds whichever way the car leaves, not only in the direction it is going,
+// and whether it starts from the floor or passes it without stopping.
+// A cab call has no direction of its own, so a car driving off either way has skipped it.
+//
+// On a violation the car is halted for good, the node is taken out of service so its hall
+// calls go to the others, and the events leading up to it are logged.
+
+const HISTORY_LEN: usize = 32;     // Events kept for the report
+
+#[derive(Debug, Clone, Copy)]
+enum Event {
+    Output(Command),
+    State(ElevatorState),
+}
+
+struct Monitor {
+    top: u8,
+    motor: Direction,
+    door_open: bool,
+    state: ElevatorState,
+    unserved: BTreeSet<u8>,                 // Floors with the cab lamp lit and no door opened there since
+    due: bool,                              // The floor the car is at had an unserved cab call when it got there
+    history: VecDeque<(Instant, Event)>,
+}
+
+pub async fn safety_monitor(io: Elevio, clock: SharedClock, mut output_rx: URx<Command>, mut state_rx: watch::Receiver<ElevatorState>, net_event_tx: UTx<NetEvent>) {
+    let start = clock.now();
+    let mut monitor = Monitor::new(io.num_floors, *state_rx.borrow_and_update());
+
+    loop {
+        // An output is sent before the state saying so is published, so take the outputs first
+        let event = tokio::select! {
+            biased;
+            Some(output) = output_rx.recv() => Event::Output(output),
+            Ok(()) = state_rx.changed() => Event::State(*state_rx.borrow_and_update()),
+            else => return,
+        };
+        monitor.record(clock.now(), event);
+
+        // Only the first violation is reported, the car does not move after that
+        let Some(violation) = monitor.check(event) else { continue };
+        if io.is_halted() {
+            continue;
+        }
+        io.halt();
+        let _ = net_event_tx.send(NetEvent::Service(false));
+        error!(violation, history = %monitor.history(start), "Safety violation, car halted and taken out of service");
+        recorder::dump("safety violation");
+    }
+}
+
+impl Monitor {
+    fn new(num_floors: u8, state: ElevatorState) -> Monitor {
+        Monitor {
+            top: num_floors.saturating_sub(1),
+            motor: Direction::Stop,
+            door_open: false,
+            state,
+            unserved: BTreeSet::new(),
+            due: false,
+            history: VecDeque::with_capacity(HISTORY_LEN),
+        }
+    }
+
+    fn record(&mut self, at: Instant, event: Event) {
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back((at, event));
+    }
+
+    // Apply `event`, and describe what it broke if anything
+    fn check(&mut self, event: Event) -> Option<String> {
+        let at_floor = self.state.floor.filter(|_| !self.state.between_floors);
+        match event {
+            Event::Output(Command::MotorDirection(dir)) => {
+                self.motor = dir;
+                if dir == Direction::Stop {
+                    return None;
+                }
+                if self.door_open {
+                    return Some(format!("motor started with the door open, direction {dir}"));
+                }
+                let floor = at_floor?;
+                if dir == Direction::Up && floor == self.top {
+                    return Some(format!("motor driven up from the top floor {floor}"));
+                }
+                if dir == Direction::Down && floor == 0 {
+                    return Some("motor driven down from the bottom floor".to_string());
+                }
+                if self.unserved.contains(&floor) {
+                    return Some(format!("car left floor {floor} with a cab call there"));
+                }
+            }
+            Event::Output(Command::DoorLight(on)) => {
+                self.door_open = on;
+                if on && self.motor != Direction::Stop {
+                    return Some(format!("door opened with the motor running, direction {}", self.motor));
+                }
+                if let Some(floor) = at_floor.filter(|_| on) {
+                    self.unserved.remove(&floor);
+                }
+            }
+            // A cab call made at the floor the door is open at is served by that opening
+            Event::Output(Command::CallButtonLight { call: CallType::Cab, floor, on }) => {
+                let floor = floor.get();
+                if on && !(self.door_open && at_floor == Some(floor)) {
+                    self.unserved.insert(floor);
+                } else if !on {
+                    self.unserved.remove(&floor);
+                }
+            }
+            // A car already moving may pass the floor without a motor command. A call made while
+            // it was passing is let go, there was no time to stop for it.
+            Event::State(state) => {
+                let next = state.floor.filter(|_| !state.between_floors);
+                self.state = state;
+                match (at_floor, next) {
+                    (None, Some(floor)) => self.due = self.unserved.contains(&floor),
+                    (Some(floor), None) if self.due && self.unserved.contains(&floor) => {
+                        return Some(format!("car passed floor {floor} with a cab call there"));
+                    }
+                    _ => (),
+                }
+            }
+            Event::Output(_) => (),
+        }
+        None
+    }
+
+    fn history(&self, start: Instant) -> String {
+        let mut out = String::new();
+        for (at, event) in self.history.iter() {
+            let _ = write!(out, "\n{:>10.3}s {event:?}", at.duration_since(start).as_secs_f64());
+        }
+        out
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elevator::elevio::protocol::Floor;
+
+    const FLOORS: u8 = 4;
+
+    fn standing_at(floor: u8) -> Monitor {
+        Monitor::new(FLOORS, ElevatorState { floor: Some(floor), ..ElevatorState::default() })
+    }
+
+    fn motor(dir: Direction) -> Event {
+        Event::Output(Command::MotorDirection(dir))
+    }
+
+    fn door(on: bool) -> Event {
+        Event::Output(Command::DoorLight(on))
+    }
+
+    fn cab_lamp(floor: u8, on: bool) -> Event {
+        Event::Output(Command::CallButtonLight { call: CallType::Cab, floor: Floor::new(floor, FLOORS).unwrap(), on })
+    }
+
+    // Feed `events` in order and return the first violation
+    fn run(monitor: &mut Monitor, events: &[Event]) -> Option<String> {
+        events.iter().find_map(|event| monitor.check(*event))
+    }
+
+    #[test]
+    fn a_normal_stop_is_fine() {
+        let mut monitor = standing_at(1);
+        let events = [cab_lamp(2, true), motor(Direction::Up), motor(Direction::Stop),
+                      Event::State(ElevatorState { floor: Some(2), ..ElevatorState::default() }),
+                      door(true), cab_lamp(2, false), door(false), motor(Direction::Down)];
+        assert_eq!(run(&mut monitor, &events), None);
+    }
+
+    #[test]
+    fn the_motor_must_not_start_with_the_door_open() {
+        let violation = run(&mut standing_at(1), &[door(true), motor(Direction::Up)]);
+        assert!(violation.unwrap().contains("motor started with the door open"));
+    }
+
+    #[test]
+    fn the_door_must_not_open_with_the_motor_running() {
+        let violation = run(&mut standing_at(1), &[motor(Direction::Down), door(true)]);
+        assert!(violation.unwrap().contains("door opened with the motor running"));
+    }
+
+    #[test]
+    fn the_car_must_not_be_driven_past_the_ends() {
+        let violation = run(&mut standing_at(FLOORS - 1), &[motor(Direction::Up)]);
+        assert!(violation.unwrap().contains("up from the top floor"));
+        let violation = run(&mut standing_at(0), &[motor(Direction::Down)]);
+        assert!(violation.unwrap().contains("down from the bottom floor"));
+
+        // Between floors the ends are not in reach yet
+        let mut monitor = Monitor::new(FLOORS, ElevatorState { floor: Some(0), between_floors: true, ..ElevatorState::default() });
+        assert_eq!(run(&mut monitor, &[motor(Direction::Down)]), None);
+    }
+
+    #[test]
+    fn the_car_must_not_leave_a_cab_call_behind_either_way() {
+        for dir in [Direction::Up, Direction::Down] {
+            let violation = run(&mut standing_at(1), &[cab_lamp(1, true), motor(dir)]);
+            assert!(violation.unwrap().contains("left floor 1 with a cab call"), "{dir}");
+        }
+
+        // Opening the door serves it
+        assert_eq!(run(&mut standing_at(1), &[cab_lamp(1, true), door(true), door(false), motor(Direction::Up)]), None);
+    }
+
+    #[test]
+    fn a_moving_car_must_not_pass_a_cab_call() {
+        let moving = |floor, between_floors| Event::State(ElevatorState { floor: Some(floor), between_floors, ..ElevatorState::default() });
+        let mut monitor = standing_at(0);
+        let events = [cab_lamp(2, true), motor(Direction::Up), moving(0, true), moving(1, false), moving(1, true), moving(2, false), moving(2, true)];
+        assert!(run(&mut monitor, &events).unwrap().contains("passed floor 2 with a cab call"));
+
+        // Unless the call was made as the car was already there
+        let mut monitor = standing_at(0);
+        let events = [motor(Direction::Up), moving(0, true), moving(1, false), cab_lamp(1, true), moving(1, true)];
+        assert_eq!(run(&mut monitor, &events), None);
+    }
+
+    #[test]
+    fn a_cab_call_at_the_open_door_does_not_count() {
+        let events = [door(true), cab_lamp(1, true), door(false), motor(Direction::Up)];
+        assert_eq!(run(&mut standing_at(1), &events), None);
+    }
+}
@@ -0,0 +1,442 @@
+pub mod hardware;
+pub mod scenario;
+pub mod server;
+pub mod traffic;
+
+use std::{env, fs, io, net::SocketAddr, path::PathBuf, process, sync::{Arc, Mutex}};
+use tokio::{task::AbortHandle, time::Duration};
+use tracing::{Instrument, error, info_span};
+use hardware::SimCar;
+use traffic::{Profile, Traffic};
+
+use crate::building::Building;
+use crate::clock::VirtualClock;
+use crate::elevator::{NUM_FLOORS, elevio::{elev::Elevio, protocol::CallType}};
+use crate::logging;
+use crate::networking::{NetConfig, NodeId, fault::FaultRules};
+use crate::node;
+use crate::order_management::persistence::OrderStore;
+use crate::supervisor::Supervisor;
+
+const STEP: Duration = Duration::from_millis(10);       // Virtual time between two looks at the cars
+const YIELDS: usize = 16;                               // Times the node tasks get to run between steps
+const WARMUP: Duration = Duration::from_secs(5);        // Lets the nodes find each other before anything happens
+const DRAIN_LIMIT: Duration = Duration::from_secs(300); // How long to wait for the last passengers after the run
+const LAMP_GRACE: Duration = Duration::from_secs(1);    // A lamp may go out this long after a car left its floor
+pub const DEFAULT_BASE_PORT: u16 = 24000;
+const DEFAULT_LOG_FILTER: &str = "warn";
+
+// Something seen on the simulated cars during a step
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Lamp { car: usize, floor: u8, call: CallType, on: bool },
+    Stopped { car: usize, floor: u8 },                  // The car came to a standstill at a floor
+    Violation { car: usize, what: String },
+}
+
+// A group of complete nodes on simulated cars, all running in virtual time on the current thread.
+// Node n (counting from 1) drives car n-1 and listens on 127.0.0.1:<base_port + n>.
+pub struct Group {
+    pub clock: Arc<VirtualClock>,
+    pub cars: Vec<SimCar>,
+    floors: u8,
+    addrs: Vec<(NodeId, SocketAddr)>,
+    nodes: Vec<Option<AbortHandle>>,
+    faults: Arc<Mutex<FaultRules>>,
+    fault_seed: u64,
+    dir: PathBuf,
+    lamps: Vec<Vec<[bool; 3]>>,             // [car][floor][call] as of the last step
+    stopped_at: Vec<Vec<Option<Duration>>>, // [car][floor] last time the car stood still at the floor
+    standing: Vec<Option<u8>>,              // Floor each car is standing still at
+}
+
+impl Group {
+
+    // Start every node, then let them find each other
+    pub async fn start(floors: u8, elevators: u8, base_port: u16) -> io::Result<Group> {
+        if env::var(logging::FILTER_ENV).is_err() {
+            let _ = logging::set_filter(DEFAULT_LOG_FILTER);
+        }
+        let (rules, fault_seed) = FaultRules::from_env()?;
+        let clock = VirtualClock::new();
+        let dir = env::temp_dir().join(format!("elevator_sim_{}_{}", process::id(), base_port));
+        fs::create_dir_all(&dir)?;
+
+        let mut group = Group {
+            cars: (0..elevators).map(|_| SimCar::new(floors, 0, clock.clone())).collect(),
+            clock,
+            floors,
+            addrs: (1..=elevators).map(|id| (id, SocketAddr::from(([127, 0, 0, 1], base_port + id as u16)))).collect(),
+            nodes: vec![None; elevators as usize],
+            faults: Arc::new(Mutex::new(rules)),
+            fault_seed,
+            dir,
+            lamps: vec![vec![[false; 3]; floors as usize]; elevators as usize],
+            stopped_at: vec![vec![None; floors as usize]; elevators as usize],
+            standing: vec![None; elevators as usize],
+        };
+        for car in 0..group.cars.len() {
+            group.spawn_node(car);
+        }
+        while group.clock.elapsed() < WARMUP {
+            group.step().await;
+        }
+        Ok(group)
+    }
+
+    pub fn elevators(&self) -> usize {
+        self.cars.len()
+    }
+
+    pub fn floors(&self) -> u8 {
+        self.floors
+    }
+
+    pub fn is_running(&self, car: usize) -> bool {
+        self.nodes[car].is_some()
+    }
+
+    // Stop the node abruptly, like a crash. The car keeps doing whatever it was told last.
+    pub fn kill(&mut self, car: usize) {
+        if let Some(node) = self.nodes[car].take() {
+            node.abort();
+        }
+    }
+
+    // Start the node again with the orders it saved, the old one is killed first if still running
+    pub async fn restart(&mut self, car: usize) {
+        if self.is_running(car) {
+            self.kill(car);
+            // Let the aborted tasks drop their socket before it is bound again
+            for _ in 0..YIELDS {
+                tokio::task::yield_now().await;
+            }
+        }
+        self.spawn_node(car);
+    }
+
+    // Cut the node off from every other node, or undo it
+    pub fn disconnect(&self, car: usize) {
+        self.faults.lock().unwrap().partition(&[self.addrs[car].1]);
+    }
+
+    pub fn reconnect(&self, car: usize) {
+        self.faults.lock().unwrap().rejoin(self.addrs[car].1);
+    }
+
+    fn spawn_node(&mut self, car: usize) {
+        let (id, local) = self.addrs[car];
+        let io = Elevio::from_connection(Box::new(self.cars[car].connect()), &format!("sim-{id}"), self.floors);
+        let net_config = NetConfig {
+            id,
+            local,
+            peers: self.addrs.iter().filter(|(peer, _)| *peer != id).copied().collect(),
+            building: Arc::new(Building::standard(self.floors)),
+            faults: self.faults.clone(),
+            fault_seed: self.fault_seed,
+            dashboard: None,
+            pair: None,
+        };
+        let store = OrderStore::new(self.dir.join(format!("orders_{id}.json")));
+        let clock = self.clock.clone();
+
+        // A node giving up ends its task, `step` reports it
+        let node = tokio::spawn(async move {
+            let mut supervisor = Supervisor::new(clock.clone());
+            let result = match node::start(&mut supervisor, net_config, clock, io, store, None).await {
+                Ok(()) => supervisor.run().await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                error!(error = %e, "Node stopped");
+            }
+        }.instrument(info_span!("node", id)));
+        self.nodes[car] = Some(node.abort_handle());
+    }
+
+    // Move time one step forward, let every node run and report what changed on the cars
+    pub async fn step(&mut self) -> Vec<Event> {
+        self.clock.advance(STEP);
+        for _ in 0..YIELDS {
+            tokio::task::yield_now().await;
+        }
+        let now = self.clock.elapsed();
+        let mut events = Vec::new();
+
+        // A node that stopped by itself is as good as crashed from here on
+        for car in 0..self.cars.len() {
+            if self.nodes[car].as_ref().is_some_and(|node| node.is_finished()) {
+                self.nodes[car] = None;
+                events.push(Event::Violation { car, what: "node stopped, a critical task could not be restored".to_string() });
+            }
+        }
+
+        for (car, sim_car) in self.cars.iter().enumerate() {
+            // A dead controller is not to blame for what its car does
+            let violations = sim_car.take_violations();
+            if self.nodes[car].is_some() {
+                events.extend(violations.into_iter().map(|what| Event::Violation { car, what }));
+            }
+
+            let standing = sim_car.floor().filter(|_| !sim_car.is_moving());
+            if let Some(floor) = standing {
+                self.stopped_at[car][floor as usize] = Some(now);
+                if self.standing[car] != standing {
+                    events.push(Event::Stopped { car, floor });
+                }
+            }
+            self.standing[car] = standing;
+        }
+
+        // A lamp may only go out when a car has served its floor
+        for car in 0..self.cars.len() {
+            for floor in 0..self.floors {
+                for call in CallType::ALL {
+                    let on = self.cars[car].lamp(floor, call);
+                    if std::mem::replace(&mut self.lamps[car][floor as usize][call.index()], on) == on {
+                        continue;
+                    }
+                    events.push(Event::Lamp { car, floor, call, on });
+
+                    let served = match call {
+                        CallType::Cab => self.stopped_recently(car, floor),
+                        _ => (0..self.cars.len()).any(|other| self.stopped_recently(other, floor)),
+                    };
+                    if !on && !served && self.is_running(car) {
+                        let what = format!("lamp {call} at floor {floor} went out without a car stopping there");
+                        events.push(Event::Violation { car, what });
+                    }
+                }
+            }
+        }
+        events
+    }
+
+    pub fn stopped_recently(&self, car: usize, floor: u8) -> bool {
+        self.stopped_at[car][floor as usize].is_some_and(|at| self.clock.elapsed() - at <= LAMP_GRACE)
+    }
+
+    // Last time any car stood still at `floor`
+    pub fn last_stop(&self, floor: u8) -> Option<Duration> {
+        self.stopped_at.iter().filter_map(|floors| floors[floor as usize]).max()
+    }
+
+    // The car that most recently stood still at `floor`, if it did so within the grace period
+    pub fn last_stopped(&self, floor: u8) -> Option<usize> {
+        (0..self.cars.len())
+            .filter(|car| self.stopped_recently(*car, floor))
+            .max_by_key(|car| self.stopped_at[*car][floor as usize])
+    }
+}
+
+impl Drop for Group {
+    fn drop(&mut self) {
+        for car in 0..self.nodes.len() {
+            self.kill(car);
+        }
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+
+pub struct SimConfig {
+    pub seed: u64,
+    pub floors: u8,
+    pub elevators: u8,
+    pub profile: Profile,
+    pub duration: Duration,     // How long passengers keep arriving
+    pub rate: f64,              // Passengers per minute
+    pub base_port: u16,
+}
+
+impl SimConfig {
+
+    // Parse `[--seed S] [--floors F] [--elevators N] [--profile uniform|up-peak|down-peak]
+    // [--duration SECONDS] [--rate PER_MINUTE] [--port BASE_PORT]`
+    pub fn from_args(mut args: impl Iterator<Item = String>) -> io::Result<SimConfig> {
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid argument: {what}"));
+
+        let mut config = SimConfig {
+            seed: 1,
+            floors: NUM_FLOORS,
+            elevators: 3,
+            profile: Profile::Uniform,
+            duration: Duration::from_secs(600),
+            rate: 4.0,
+            base_port: DEFAULT_BASE_PORT,
+        };
+
+        while let Some(arg) = args.next() {
+            let value = args.next().ok_or_else(|| invalid(&arg))?;
+            match arg.as_str() {
+                "--seed" => config.seed = value.parse().map_err(|_| invalid(&value))?,
+                "--floors" => config.floors = value.parse().ok().filter(|f| *f >= 2).ok_or_else(|| invalid(&value))?,
+                "--elevators" => config.elevators = value.parse().ok().filter(|n| *n >= 1).ok_or_else(|| invalid(&value))?,
+                "--profile" => config.profile = value.parse()?,
+                "--duration" => config.duration = Duration::from_secs(value.parse().map_err(|_| invalid(&value))?),
+                "--rate" => config.rate = value.parse().map_err(|_| invalid(&value))?,
+                "--port" => config.base_port = value.parse().map_err(|_| invalid(&value))?,
+                _ => return Err(invalid(&arg)),
+            }
+        }
+        Ok(config)
+    }
+}
+
+enum Stage {
+    Waiting { lit: bool },                              // `lit` once the hall lamp has been seen on
+    Riding { car: usize, wait: Duration, lit: bool },
+    Delivered { car: usize, wait: Duration, journey: Duration },
+}
+
+struct Passenger {
+    origin: u8,
+    destination: u8,
+    panel: usize,       // Car whose hall buttons and lamps the passenger uses
+    arrived: Duration,  // Since the start of the run
+    stage: Stage,
+}
+
+impl Passenger {
+    fn direction(&self) -> CallType {
+        if self.destination > self.origin { CallType::HallUp } else { CallType::HallDown }
+    }
+}
+
+struct Violation {
+    at: Duration,
+    car: usize,
+    what: String,
+}
+
+
+// Run the group with random passengers, then report how long they waited
+// and travelled and anything that should not have happened
+pub async fn run(config: SimConfig) -> io::Result<()> {
+    let mut group = Group::start(config.floors, config.elevators, config.base_port).await?;
+    let mut traffic = Traffic::new(config.profile, config.floors, config.rate, config.seed);
+    let mut passengers: Vec<Passenger> = Vec::new();
+    let mut violations = Vec::new();
+
+    let start = group.clock.elapsed();
+    loop {
+        let events = group.step().await;
+        let now = group.clock.elapsed() - start;
+        for event in events {
+            if let Event::Violation { car, what } = event {
+                violations.push(Violation { at: now, car, what });
+            }
+        }
+        for passenger in passengers.iter_mut() {
+            step_passenger(&group, passenger, now);
+        }
+
+        if now < config.duration && let Some((origin, destination)) = traffic.arrival(STEP) {
+            let panel = traffic.panel(group.elevators());
+            let passenger = Passenger { origin, destination, panel, arrived: now, stage: Stage::Waiting { lit: false } };
+            group.cars[panel].press(origin, passenger.direction());
+            passengers.push(passenger);
+        }
+
+        let all_delivered = passengers.iter().all(|p| matches!(p.stage, Stage::Delivered { .. }));
+        if now >= config.duration && (all_delivered || now >= config.duration + DRAIN_LIMIT) {
+            break;
+        }
+    }
+
+    let end = group.clock.elapsed() - start;
+    for (id, p) in passengers.iter().enumerate() {
+        if !matches!(p.stage, Stage::Delivered { .. }) {
+            let what = format!("passenger {} from floor {} to {} was never delivered", id + 1, p.origin, p.destination);
+            violations.push(Violation { at: end, car: p.panel, what });
+        }
+    }
+    report(&config, &passengers, &violations);
+
+    match violations.len() {
+        0 => Ok(()),
+        count => Err(io::Error::other(format!("{count} invariant violations"))),
+    }
+}
+
+// Passengers board once their hall lamp goes out, and leave once their cab lamp goes out.
+// A lamp going out without a car at the floor means the passenger presses again.
+fn step_passenger(group: &Group, p: &mut Passenger, now: Duration) {
+    let waited = now - p.arrived;
+    let (origin, destination, direction) = (p.origin, p.destination, p.direction());
+    let cars = &group.cars;
+
+    match p.stage {
+        Stage::Waiting { lit } => {
+            if cars[p.panel].lamp(origin, direction) {
+                p.stage = Stage::Waiting { lit: true };
+            } else if lit {
+                match group.last_stopped(origin) {
+                    Some(car) => {
+                        p.stage = Stage::Riding { car, wait: waited, lit: false };
+                        cars[car].press(destination, CallType::Cab);
+                    }
+                    None => {
+                        p.stage = Stage::Waiting { lit: false };
+                        cars[p.panel].press(origin, direction);
+                    }
+                }
+            }
+        }
+        Stage::Riding { car, wait, lit } => {
+            if cars[car].lamp(destination, CallType::Cab) {
+                p.stage = Stage::Riding { car, wait, lit: true };
+            } else if lit {
+                if group.stopped_recently(car, destination) {
+                    p.stage = Stage::Delivered { car, wait, journey: waited };
+                } else {
+                    p.stage = Stage::Riding { car, wait, lit: false };
+                    cars[car].press(destination, CallType::Cab);
+                }
+            }
+        }
+        Stage::Delivered { .. } => (),
+    }
+}
+
+fn report(config: &SimConfig, passengers: &[Passenger], violations: &[Violation]) {
+    println!("Simulated {} elevators, {} floors, {:?} traffic at {}/min for {}s, seed {}",
+        config.elevators, config.floors, config.profile, config.rate, config.duration.as_secs(), config.seed);
+    println!();
+    println!("{:>5} {:>9} {:>5} {:>3} {:>4} {:>8} {:>8}", "order", "arrived", "from", "to", "car", "wait", "journey");
+
+    let (mut waits, mut journeys) = (Vec::new(), Vec::new());
+    for (id, p) in passengers.iter().enumerate() {
+        let (car, wait, journey) = match p.stage {
+            Stage::Waiting { .. } => (None, None, None),
+            Stage::Riding { car, wait, .. } => (Some(car), Some(wait), None),
+            Stage::Delivered { car, wait, journey } => (Some(car), Some(wait), Some(journey)),
+        };
+        waits.extend(wait);
+        journeys.extend(journey);
+        println!("{:>5} {:>9} {:>5} {:>3} {:>4} {:>8} {:>8}", id + 1, seconds(Some(p.arrived)), p.origin, p.destination,
+            car.map_or("-".to_string(), |car| (car + 1).to_string()), seconds(wait), seconds(journey));
+    }
+
+    println!();
+    println!("{} orders, {} delivered", passengers.len(), journeys.len());
+    for (name, times) in [("wait", &mut waits), ("journey", &mut journeys)] {
+        times.sort();
+        let mean = (!times.is_empty()).then(|| times.iter().sum::<Duration>() / times.len() as u32);
+        let p95 = times.get((times.len() * 95).div_ceil(100).saturating_sub(1)).copied();
+        println!("{:<8} mean {:>7}  p95 {:>7}  max {:>7}", name, seconds(mean), seconds(p95), seconds(times.last().copied()));
+    }
+
+    println!();
+    println!("{} invariant violations", violations.len());
+    for v in violations.iter() {
+        println!("  [{}] car {}: {}", seconds(Some(v.at)), v.car + 1, v.what);
+    }
+}
+
+
+// ---------- PURE FUNCTIONS ----------
+
+pub fn seconds(time: Option<Duration>) -> String {
+    time.map_or("-".to_string(), |t| format!("{:.1}s", t.as_secs_f64()))
+}
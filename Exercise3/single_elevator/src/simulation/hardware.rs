@@ -0,0 +1,194 @@
+use std::{collections::VecDeque, fmt, io::{self, Read, Write}, sync::{Arc, Mutex}};
+use tokio::time::{Duration, Instant};
+
+use crate::clock::SharedClock;
+use crate::elevator::elevio::protocol::{CallType, Command, Direction, Floor, Frame, Reply};
+
+// Roughly the speed of the lab elevators
+pub const FLOOR_TRAVEL_TIME: Duration = Duration::from_millis(2000);
+// The floor sensor is active within this many floors of a floor
+const SENSOR_WIDTH: f64 = 0.05;
+
+// A simulated car and its panel, moving in the time of the given clock.
+// Clones share the same car, one is handed to the driver as a connection
+// and the simulation keeps another to press buttons and look at the lamps.
+#[derive(Clone)]
+pub struct SimCar {
+    state: Arc<Mutex<CarState>>,
+    clock: SharedClock,
+}
+
+struct CarState {
+    num_floors: u8,
+    position: f64,                  // In floors, 0.0 is the ground floor
+    motor: Direction,
+    updated: Instant,
+    pressed: Vec<[bool; 3]>,        // Presses not yet read by the driver
+    lamps: Vec<[bool; 3]>,
+    door_light: bool,
+    violations: Vec<String>,
+}
+
+impl SimCar {
+    pub fn new(num_floors: u8, floor: u8, clock: SharedClock) -> SimCar {
+        let state = CarState {
+            num_floors,
+            position: floor as f64,
+            motor: Direction::Stop,
+            updated: clock.now(),
+            pressed: vec![[false; 3]; num_floors as usize],
+            lamps: vec![[false; 3]; num_floors as usize],
+            door_light: false,
+            violations: Vec::new(),
+        };
+        SimCar { state: Arc::new(Mutex::new(state)), clock }
+    }
+
+    // A connection speaking the elevator server protocol, for Elevio::from_connection
+    pub fn connect(&self) -> SimConnection {
+        SimConnection { car: self.clone(), command: Vec::with_capacity(4), response: VecDeque::new() }
+    }
+
+    // Press a button, the press is held until the driver has read it once
+    pub fn press(&self, floor: u8, call: CallType) {
+        if let Some(buttons) = self.state.lock().unwrap().pressed.get_mut(floor as usize) {
+            buttons[call.index()] = true;
+        }
+    }
+
+    pub fn lamp(&self, floor: u8, call: CallType) -> bool {
+        self.state.lock().unwrap().lamps.get(floor as usize).is_some_and(|lamps| lamps[call.index()])
+    }
+
+    // The floor the sensor is at, if any
+    pub fn floor(&self) -> Option<u8> {
+        let mut state = self.state.lock().unwrap();
+        state.update(self.clock.now());
+        state.floor()
+    }
+
+    pub fn is_moving(&self) -> bool {
+        self.state.lock().unwrap().motor != Direction::Stop
+    }
+
+    // Physically impossible or unsafe things the driver made the car do since the last call
+    pub fn take_violations(&self) -> Vec<String> {
+        let mut state = self.state.lock().unwrap();
+        state.update(self.clock.now());
+        std::mem::take(&mut state.violations)
+    }
+
+    // Handle one 4 byte command, returns the reply for the commands that have one.
+    // Frames that are not valid commands are ignored, as the lab server does.
+    fn command(&self, frame: Frame) -> Option<Frame> {
+        let mut state = self.state.lock().unwrap();
+        state.update(self.clock.now());
+        let command = Command::decode(frame, state.num_floors).ok()?;
+        let reply = match command {
+            Command::MotorDirection(direction) => {
+                if direction != Direction::Stop && state.door_light {
+                    state.violations.push("motor started with the door open".to_string());
+                }
+                state.motor = direction;
+                return None;
+            }
+            Command::CallButtonLight { call, floor, on } => {
+                state.lamps[floor.get() as usize][call.index()] = on;
+                return None;
+            }
+            Command::DoorLight(on) => {
+                if on && state.motor != Direction::Stop {
+                    state.violations.push("door opened while moving".to_string());
+                }
+                state.door_light = on;
+                return None;
+            }
+            Command::FloorIndicator(_) | Command::StopButtonLight(_) => return None,
+            Command::CallButton { call, floor } => Reply::CallButton(std::mem::take(&mut state.pressed[floor.get() as usize][call.index()])),
+            Command::FloorSensor => Reply::FloorSensor(state.floor().and_then(|floor| Floor::new(floor, state.num_floors))),
+            Command::StopButton => Reply::StopButton(false),
+            Command::Obstruction => Reply::Obstruction(false),
+        };
+        Some(reply.encode())
+    }
+}
+
+impl CarState {
+    // Move the car according to the motor since the last update
+    fn update(&mut self, now: Instant) {
+        let travelled = now.saturating_duration_since(self.updated).as_secs_f64() / FLOOR_TRAVEL_TIME.as_secs_f64();
+        self.updated = now;
+
+        let before = self.position;
+        self.position += match self.motor {
+            Direction::Up => travelled,
+            Direction::Down => -travelled,
+            Direction::Stop => 0.0,
+        };
+
+        // The end stops are just past the sensors of the top and bottom floor
+        let top = (self.num_floors - 1) as f64 + SENSOR_WIDTH;
+        let bottom = -SENSOR_WIDTH;
+        if self.position > top {
+            if before < top {
+                self.violations.push("drove past the top floor".to_string());
+            }
+            self.position = top;
+        }
+        if self.position < bottom {
+            if before > bottom {
+                self.violations.push("drove past the bottom floor".to_string());
+            }
+            self.position = bottom;
+        }
+    }
+
+    fn floor(&self) -> Option<u8> {
+        let nearest = self.position.round();
+        ((self.position - nearest).abs() <= SENSOR_WIDTH).then_some(nearest as u8)
+    }
+}
+
+
+// The driver side of a simulated car
+pub struct SimConnection {
+    car: SimCar,
+    command: Vec<u8>,
+    response: VecDeque<u8>,
+}
+
+impl fmt::Debug for SimConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SimConnection")
+    }
+}
+
+impl Write for SimConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for byte in buf {
+            self.command.push(*byte);
+            if self.command.len() == 4 {
+                let command = [self.command[0], self.command[1], self.command[2], self.command[3]];
+                self.command.clear();
+                if let Some(reply) = self.car.command(command) {
+                    self.response.extend(reply);
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for SimConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.response.len());
+        for (slot, byte) in buf.iter_mut().zip(self.response.drain(..len)) {
+            *slot = byte;
+        }
+        Ok(len)
+    }
+}
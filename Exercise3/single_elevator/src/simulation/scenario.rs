@@ -0,0 +1,514 @@
+use std::{fs, io, path::Path};
+use tokio::time::Duration;
+
+use crate::elevator::{NUM_FLOORS, elevio::protocol::CallType};
+use crate::simulation::{DEFAULT_BASE_PORT, Event, Group, seconds};
+
+// A scenario is a list of statements, one per line or separated by `;`, run in order
+// against a simulated group. Anything after `#` is a comment.
+//
+//   elevators 3                                    (before any other statement, default 3)
+//   floors 4                                       (default NUM_FLOORS)
+//   [t=<s>] press <hall up|hall down|cab> at floor <f> on node <n>
+//   [t=<s>] kill node <n>                          (crash, the car keeps its last motor command)
+//   [t=<s>] restart node <n>                       (start again from the orders it saved)
+//   [t=<s>] disconnect node <n> | reconnect node <n>
+//   [t=<s>] expect <condition> [within <s> s]
+//
+// Conditions:
+//   floor <f> served                               (its lamps went out after a car stood there)
+//   lamps off on <node <n>|all nodes>
+//   lamp <hall up|hall down|cab> at floor <f> <lit|off> on <node <n>|all nodes>
+//   node <n> at floor <f>                          (standing still there)
+//
+// `t=` is the time since the start of the scenario. Statements without it run right after
+// the previous one, so an expectation is checked once the statement before it is done.
+// Killed nodes are left out of "all nodes".
+
+const DEFAULT_ELEVATORS: u8 = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scenario {
+    pub elevators: u8,
+    pub floors: u8,
+    pub statements: Vec<Statement>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    pub line: usize,
+    pub text: String,
+    pub at: Option<Duration>,
+    pub action: Action,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Press { call: CallType, floor: u8, node: usize },
+    Kill(usize),
+    Restart(usize),
+    Disconnect(usize),
+    Reconnect(usize),
+    Expect { condition: Condition, within: Duration },
+}
+
+// Nodes are counted from 0 here, and from 1 in the scenario text
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    Served(u8),
+    LampsOff(Option<usize>),                                    // None for all nodes
+    Lamp { call: CallType, floor: u8, lit: bool, node: Option<usize> },
+    At { node: usize, floor: u8 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Pass,
+    Fail,
+}
+
+
+// ---------- PARSING ----------
+
+pub fn parse(source: &str) -> io::Result<Scenario> {
+    let mut scenario = Scenario { elevators: DEFAULT_ELEVATORS, floors: NUM_FLOORS, statements: Vec::new() };
+
+    for (number, line) in source.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        for text in line.split(';').map(str::trim).filter(|s| !s.is_empty()) {
+            let error = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {what}: {text}", number + 1));
+            let mut words = Words(text.split_whitespace().collect(), 0);
+
+            match words.peek() {
+                Some("elevators") | Some("floors") if !scenario.statements.is_empty() => {
+                    return Err(error("the group must be set up before the first statement"));
+                }
+                Some("elevators") => {
+                    words.next();
+                    scenario.elevators = words.number().filter(|n| *n >= 1).ok_or_else(|| error("expected a number of elevators"))?;
+                }
+                Some("floors") => {
+                    words.next();
+                    scenario.floors = words.number().filter(|n| *n >= 2).ok_or_else(|| error("expected a number of floors"))?;
+                }
+                _ => {
+                    let at = match words.peek().and_then(|word| word.strip_prefix("t=")) {
+                        Some(time) => {
+                            words.next();
+                            Some(parse_seconds(time).ok_or_else(|| error("invalid time"))?)
+                        }
+                        None => None,
+                    };
+                    let action = parse_action(&mut words).map_err(error)?;
+                    if let Some(word) = words.next() {
+                        return Err(error(&format!("unexpected `{word}`")));
+                    }
+                    scenario.statements.push(Statement { line: number + 1, text: text.to_string(), at, action });
+                }
+            }
+        }
+    }
+    check(&scenario)?;
+    Ok(scenario)
+}
+
+fn parse_action(words: &mut Words) -> Result<Action, &'static str> {
+    match words.next() {
+        Some("press") => {
+            let call = words.button()?;
+            words.expect(&["at", "floor"])?;
+            let floor = words.number().ok_or("expected a floor")?;
+            words.expect(&["on"])?;
+            Ok(Action::Press { call, floor, node: words.node()? })
+        }
+        Some("kill") => Ok(Action::Kill(words.node()?)),
+        Some("restart") => Ok(Action::Restart(words.node()?)),
+        Some("disconnect") => Ok(Action::Disconnect(words.node()?)),
+        Some("reconnect") => Ok(Action::Reconnect(words.node()?)),
+        Some("expect") => {
+            let condition = parse_condition(words)?;
+            let within = match words.peek() {
+                Some("within") => {
+                    words.next();
+                    words.duration().ok_or("expected a duration")?
+                }
+                _ => Duration::ZERO,
+            };
+            Ok(Action::Expect { condition, within })
+        }
+        _ => Err("expected press, kill, restart, disconnect, reconnect or expect"),
+    }
+}
+
+fn parse_condition(words: &mut Words) -> Result<Condition, &'static str> {
+    match words.next() {
+        Some("floor") => {
+            let floor = words.number().ok_or("expected a floor")?;
+            words.expect(&["served"])?;
+            Ok(Condition::Served(floor))
+        }
+        Some("lamp") | Some("lamps") if words.peek() == Some("off") => {
+            words.expect(&["off", "on"])?;
+            Ok(Condition::LampsOff(words.nodes()?))
+        }
+        Some("lamp") => {
+            let call = words.button()?;
+            words.expect(&["at", "floor"])?;
+            let floor = words.number().ok_or("expected a floor")?;
+            let lit = match words.next() {
+                Some("lit") => true,
+                Some("off") => false,
+                _ => return Err("expected lit or off"),
+            };
+            words.expect(&["on"])?;
+            Ok(Condition::Lamp { call, floor, lit, node: words.nodes()? })
+        }
+        Some("node") => {
+            let node = words.number::<usize>().filter(|n| *n >= 1).ok_or("expected a node")? - 1;
+            words.expect(&["at", "floor"])?;
+            Ok(Condition::At { node, floor: words.number().ok_or("expected a floor")? })
+        }
+        _ => Err("expected floor, lamp, lamps or node"),
+    }
+}
+
+// Every node and floor mentioned must exist in the group
+fn check(scenario: &Scenario) -> io::Result<()> {
+    for statement in scenario.statements.iter() {
+        let (nodes, floors) = match &statement.action {
+            Action::Press { floor, node, .. } => (vec![*node], vec![*floor]),
+            Action::Kill(node) | Action::Restart(node) | Action::Disconnect(node) | Action::Reconnect(node) => (vec![*node], vec![]),
+            Action::Expect { condition, .. } => match condition {
+                Condition::Served(floor) => (vec![], vec![*floor]),
+                Condition::LampsOff(node) => (node.iter().copied().collect(), vec![]),
+                Condition::Lamp { floor, node, .. } => (node.iter().copied().collect(), vec![*floor]),
+                Condition::At { node, floor } => (vec![*node], vec![*floor]),
+            },
+        };
+        if nodes.iter().any(|node| *node >= scenario.elevators as usize) || floors.iter().any(|floor| *floor >= scenario.floors) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("line {}: no such node or floor: {}", statement.line, statement.text)));
+        }
+    }
+    Ok(())
+}
+
+struct Words<'a>(Vec<&'a str>, usize);
+
+impl<'a> Words<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.0.get(self.1).copied()
+    }
+
+    fn next(&mut self) -> Option<&'a str> {
+        let word = self.peek();
+        self.1 += 1;
+        word
+    }
+
+    fn expect(&mut self, expected: &[&str]) -> Result<(), &'static str> {
+        for word in expected {
+            if self.next() != Some(word) {
+                return Err("unexpected word");
+            }
+        }
+        Ok(())
+    }
+
+    fn number<T: std::str::FromStr>(&mut self) -> Option<T> {
+        self.next()?.parse().ok()
+    }
+
+    // `node <n>`, counted from 1 in the text
+    fn node(&mut self) -> Result<usize, &'static str> {
+        self.expect(&["node"])?;
+        self.number::<usize>().filter(|n| *n >= 1).map(|n| n - 1).ok_or("expected a node")
+    }
+
+    // `node <n>` or `all nodes`
+    fn nodes(&mut self) -> Result<Option<usize>, &'static str> {
+        if self.peek() == Some("all") {
+            self.expect(&["all", "nodes"])?;
+            return Ok(None);
+        }
+        self.node().map(Some)
+    }
+
+    fn button(&mut self) -> Result<CallType, &'static str> {
+        match (self.next(), self.peek()) {
+            (Some("hall"), Some("up")) => { self.next(); Ok(CallType::HallUp) }
+            (Some("hall"), Some("down")) => { self.next(); Ok(CallType::HallDown) }
+            (Some("cab"), _) => Ok(CallType::Cab),
+            _ => Err("expected hall up, hall down or cab"),
+        }
+    }
+
+    // `20 s`, `20s` or `1.5 s`
+    fn duration(&mut self) -> Option<Duration> {
+        let word = self.next()?;
+        if word.ends_with('s') {
+            return parse_seconds(word);
+        }
+        let duration = parse_seconds(word)?;
+        if self.peek() == Some("s") {
+            self.next();
+        }
+        Some(duration)
+    }
+}
+
+
+// ---------- RUNNING ----------
+
+// Run every scenario file on a fresh group, returns an error if any of them failed
+pub async fn run_files(paths: &[String], base_port: u16) -> io::Result<()> {
+    let mut failed = Vec::new();
+    for path in paths {
+        let scenario = parse(&fs::read_to_string(path)?)?;
+        println!("Scenario {} ({} elevators, {} floors)", path, scenario.elevators, scenario.floors);
+        if run(&scenario, base_port).await? == Verdict::Fail {
+            failed.push(path.as_str());
+        }
+        println!();
+    }
+    match failed.len() {
+        0 => Ok(()),
+        _ => Err(io::Error::other(format!("failed: {}", failed.join(", ")))),
+    }
+}
+
+// Parse `<file>... [--port BASE_PORT]`
+pub fn from_args(args: impl Iterator<Item = String>) -> io::Result<(Vec<String>, u16)> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid argument: {what}"));
+    let (mut paths, mut base_port) = (Vec::new(), DEFAULT_BASE_PORT);
+    let mut args = args.peekable();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--port" => base_port = args.next().and_then(|p| p.parse().ok()).ok_or_else(|| invalid(&arg))?,
+            _ if Path::new(&arg).is_file() => paths.push(arg),
+            _ => return Err(invalid(&arg)),
+        }
+    }
+    if paths.is_empty() {
+        return Err(invalid("no scenario given"));
+    }
+    Ok((paths, base_port))
+}
+
+// Run the statements in order and print a timeline of what they did and what the cars did.
+// Fails if any expectation fails or any invariant is violated.
+pub async fn run(scenario: &Scenario, base_port: u16) -> io::Result<Verdict> {
+    let mut group = Group::start(scenario.floors, scenario.elevators, base_port).await?;
+    let mut run = Run {
+        start: group.clock.elapsed(),
+        last_lit: vec![None; scenario.floors as usize],
+        verdict: Verdict::Pass,
+        group: &mut group,
+    };
+
+    for statement in scenario.statements.iter() {
+        if let Some(at) = statement.at {
+            while run.now() < at {
+                run.step().await;
+            }
+        }
+        match statement.action {
+            Action::Press { call, floor, node } => run.group.cars[node].press(floor, call),
+            Action::Kill(node) => run.group.kill(node),
+            Action::Restart(node) => run.group.restart(node).await,
+            Action::Disconnect(node) => run.group.disconnect(node),
+            Action::Reconnect(node) => run.group.reconnect(node),
+            Action::Expect { ref condition, within } => {
+                let deadline = run.now() + within;
+                let verdict = loop {
+                    if run.holds(condition) {
+                        break Verdict::Pass;
+                    }
+                    if run.now() >= deadline {
+                        break Verdict::Fail;
+                    }
+                    run.step().await;
+                };
+                if verdict == Verdict::Fail {
+                    run.verdict = Verdict::Fail;
+                }
+                println!("{:>8}  {:?}  {}", seconds(Some(run.now())), verdict, statement.text);
+                continue;
+            }
+        }
+        println!("{:>8}  {}", seconds(Some(run.now())), statement.text);
+    }
+
+    println!("{:?}", run.verdict);
+    Ok(run.verdict)
+}
+
+struct Run<'a> {
+    group: &'a mut Group,
+    start: Duration,
+    last_lit: Vec<Option<Duration>>,    // [floor] last time, on the group clock, a lamp at the floor was lit on a running node
+    verdict: Verdict,
+}
+
+impl Run<'_> {
+    fn now(&self) -> Duration {
+        self.group.clock.elapsed() - self.start
+    }
+
+    async fn step(&mut self) {
+        let events = self.group.step().await;
+        let now = self.now();
+        for event in events {
+            match event {
+                Event::Lamp { car, floor, call, on } => {
+                    println!("{:>8}      node {}: {} lamp at floor {} {}", seconds(Some(now)), car + 1, call, floor, if on { "lit" } else { "off" });
+                }
+                Event::Stopped { car, floor } => {
+                    println!("{:>8}      node {}: stopped at floor {}", seconds(Some(now)), car + 1, floor);
+                }
+                Event::Violation { car, what } => {
+                    println!("{:>8}  VIOLATION  node {}: {}", seconds(Some(now)), car + 1, what);
+                    self.verdict = Verdict::Fail;
+                }
+            }
+        }
+        for floor in 0..self.group.floors() {
+            if self.running().any(|car| self.lamps(car, floor).any(|on| on)) {
+                self.last_lit[floor as usize] = Some(self.group.clock.elapsed());
+            }
+        }
+    }
+
+    fn running(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.group.elevators()).filter(|car| self.group.is_running(*car))
+    }
+
+    fn lamps(&self, car: usize, floor: u8) -> impl Iterator<Item = bool> + '_ {
+        CallType::ALL.into_iter().map(move |call| self.group.cars[car].lamp(floor, call))
+    }
+
+    fn holds(&self, condition: &Condition) -> bool {
+        let nodes = |node: Option<usize>| -> Vec<usize> { node.map_or_else(|| self.running().collect(), |node| vec![node]) };
+        match *condition {
+            // Lit at some point, dark now, and a car stood there in between
+            Condition::Served(floor) => {
+                let dark = !self.running().any(|car| self.lamps(car, floor).any(|on| on));
+                let lit = self.last_lit[floor as usize];
+                dark && lit.is_some() && self.group.last_stop(floor) >= lit
+            }
+            Condition::LampsOff(node) => nodes(node).into_iter()
+                .all(|car| (0..self.group.floors()).all(|floor| !self.lamps(car, floor).any(|on| on))),
+            Condition::Lamp { call, floor, lit, node } => nodes(node).into_iter()
+                .all(|car| self.group.cars[car].lamp(floor, call) == lit),
+            Condition::At { node, floor } => {
+                let car = &self.group.cars[node];
+                car.floor() == Some(floor) && !car.is_moving()
+            }
+        }
+    }
+}
+
+
+// ---------- PURE FUNCTIONS ----------
+
+fn parse_seconds(text: &str) -> Option<Duration> {
+    let seconds: f64 = text.strip_suffix('s').unwrap_or(text).parse().ok()?;
+    (seconds >= 0.0 && seconds.is_finite()).then(|| Duration::from_secs_f64(seconds))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn actions(source: &str) -> Vec<(Option<Duration>, Action)> {
+        parse(source).unwrap().statements.into_iter().map(|s| (s.at, s.action)).collect()
+    }
+
+    fn error(source: &str) -> String {
+        let error = parse(source).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        error.to_string()
+    }
+
+    #[test]
+    fn the_group_defaults_and_can_be_set_up() {
+        let scenario = parse("# nothing yet\n").unwrap();
+        assert_eq!((scenario.elevators, scenario.floors, scenario.statements.len()), (DEFAULT_ELEVATORS, NUM_FLOORS, 0));
+
+        let scenario = parse("elevators 2; floors 6").unwrap();
+        assert_eq!((scenario.elevators, scenario.floors), (2, 6));
+    }
+
+    #[test]
+    fn parses_every_action() {
+        let source = "
+            t=1.5 press hall up at floor 2 on node 1
+            press hall down at floor 3 on node 2; press cab at floor 0 on node 3   # two on a line
+            t=4s kill node 2
+            restart node 2
+            disconnect node 3; reconnect node 3
+        ";
+        assert_eq!(actions(source), [
+            (Some(Duration::from_millis(1500)), Action::Press { call: CallType::HallUp, floor: 2, node: 0 }),
+            (None, Action::Press { call: CallType::HallDown, floor: 3, node: 1 }),
+            (None, Action::Press { call: CallType::Cab, floor: 0, node: 2 }),
+            (Some(Duration::from_secs(4)), Action::Kill(1)),
+            (None, Action::Restart(1)),
+            (None, Action::Disconnect(2)),
+            (None, Action::Reconnect(2)),
+        ]);
+    }
+
+    #[test]
+    fn parses_every_condition() {
+        let expect = |condition, within| (None, Action::Expect { condition, within });
+        let source = "
+            expect floor 2 served within 20 s
+            expect lamps off on all nodes within 1s
+            expect lamp off on node 2
+            expect lamp cab at floor 1 lit on node 3 within 0.5 s
+            expect lamp hall down at floor 3 off on all nodes
+            expect node 1 at floor 0 within 10s
+        ";
+        assert_eq!(actions(source), [
+            expect(Condition::Served(2), Duration::from_secs(20)),
+            expect(Condition::LampsOff(None), Duration::from_secs(1)),
+            expect(Condition::LampsOff(Some(1)), Duration::ZERO),
+            expect(Condition::Lamp { call: CallType::Cab, floor: 1, lit: true, node: Some(2) }, Duration::from_millis(500)),
+            expect(Condition::Lamp { call: CallType::HallDown, floor: 3, lit: false, node: None }, Duration::ZERO),
+            expect(Condition::At { node: 0, floor: 0 }, Duration::from_secs(10)),
+        ]);
+    }
+
+    #[test]
+    fn statements_keep_their_line_and_text() {
+        let scenario = parse("elevators 2\n\nkill node 1 # gone\nt=2 restart node 1").unwrap();
+        let lines: Vec<_> = scenario.statements.iter().map(|s| (s.line, s.text.as_str())).collect();
+        assert_eq!(lines, [(3, "kill node 1"), (4, "t=2 restart node 1")]);
+    }
+
+    #[test]
+    fn mistakes_are_reported_with_their_line() {
+        assert!(error("kill node 1\nelevators 2").starts_with("line 2: the group must be set up"));
+        assert!(error("elevators 0").contains("expected a number of elevators"));
+        assert!(error("floors 1").contains("expected a number of floors"));
+        assert!(error("t=-1 kill node 1").contains("invalid time"));
+        assert!(error("t=soon kill node 1").contains("invalid time"));
+        assert!(error("jump node 1").contains("expected press"));
+        assert!(error("kill node 0").contains("expected a node"));
+        assert!(error("press hall sideways at floor 1 on node 1").contains("expected hall up"));
+        assert!(error("kill node 1 now").contains("unexpected `now`"));
+        assert!(error("expect floor 1 served within ages").contains("expected a duration"));
+        assert!(error("expect lamp cab at floor 1 dim on node 1").contains("expected lit or off"));
+        assert!(error("expect the best").contains("expected floor, lamp"));
+    }
+
+    #[test]
+    fn nodes_and_floors_must_exist() {
+        assert!(error("elevators 2\nkill node 3").starts_with("line 2: no such node or floor"));
+        assert!(error("floors 4\npress cab at floor 4 on node 1").contains("no such node or floor"));
+        assert!(error("elevators 1; expect lamps off on node 2").contains("no such node or floor"));
+        assert!(parse("elevators 2; floors 4; press cab at floor 3 on node 2; expect lamps off on all nodes").is_ok());
+    }
+}
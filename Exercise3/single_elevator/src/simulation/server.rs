@@ -0,0 +1,84 @@
+use std::{io::{self, Read, Write}, net::SocketAddr};
+use tokio::{io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader}, net::{TcpListener, TcpStream}};
+use tracing::{info, warn};
+
+use crate::clock;
+use crate::elevator::{NUM_FLOORS, elevio::protocol::CallType};
+use crate::simulation::hardware::SimCar;
+
+// Same port as the lab simulator
+const DEFAULT_PORT: u16 = 15657;
+
+// One simulated car served over TCP in real time, a stand-in for the lab simulator that
+// needs no terminal of its own. Every connection drives the same car. Buttons are pressed
+// by writing `<floor> <up|down|cab>` lines to stdin, which is how the launcher does it.
+pub async fn serve(port: u16, num_floors: u8) -> io::Result<()> {
+    let car = SimCar::new(num_floors, 0, clock::system());
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], port))).await?;
+    info!(port, num_floors, "Simulated elevator listening");
+
+    let buttons = car.clone();
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            match parse_press(&line, num_floors) {
+                Some((floor, call)) => buttons.press(floor, call),
+                None => warn!(line, "Expected `<floor> <up|down|cab>`"),
+            }
+        }
+    });
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let car = car.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, &car).await {
+                warn!(%peer, error = %e, "Connection closed");
+            }
+        });
+    }
+}
+
+// Parse `[--port PORT] [--floors FLOORS]`
+pub fn from_args(mut args: impl Iterator<Item = String>) -> io::Result<(u16, u8)> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid argument: {what}"));
+    let (mut port, mut floors) = (DEFAULT_PORT, NUM_FLOORS);
+    while let Some(arg) = args.next() {
+        let value = args.next().ok_or_else(|| invalid(&arg))?;
+        match arg.as_str() {
+            "--port" => port = value.parse().map_err(|_| invalid(&value))?,
+            "--floors" => floors = value.parse().ok().filter(|f| *f >= 2).ok_or_else(|| invalid(&value))?,
+            _ => return Err(invalid(&arg)),
+        }
+    }
+    Ok((port, floors))
+}
+
+// Pass 4 byte commands to the car and its replies back until the driver hangs up
+async fn handle(mut stream: TcpStream, car: &SimCar) -> io::Result<()> {
+    let mut connection = car.connect();
+    let mut command = [0u8; 4];
+    let mut reply = [0u8; 4];
+    loop {
+        match stream.read_exact(&mut command).await {
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        connection.write_all(&command)?;
+        let len = connection.read(&mut reply)?;
+        stream.write_all(&reply[..len]).await?;
+    }
+}
+
+fn parse_press(line: &str, num_floors: u8) -> Option<(u8, CallType)> {
+    let mut words = line.split_whitespace();
+    let floor = words.next()?.parse().ok().filter(|floor| *floor < num_floors)?;
+    let call = match words.next()? {
+        "up" => CallType::HallUp,
+        "down" => CallType::HallDown,
+        "cab" => CallType::Cab,
+        _ => return None,
+    };
+    words.next().is_none().then_some((floor, call))
+}
@@ -0,0 +1,69 @@
+use std::{io, str::FromStr};
+use tokio::time::Duration;
+
+use crate::networking::fault::Rng;
+
+// Share of the passengers following the main flow in the peak profiles
+const PEAK_SHARE: f64 = 0.8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    Uniform,    // Random origin and destination
+    UpPeak,     // Most passengers arrive at the ground floor and go up
+    DownPeak,   // Most passengers leave through the ground floor
+}
+
+impl FromStr for Profile {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Profile> {
+        match s {
+            "uniform" => Ok(Profile::Uniform),
+            "up-peak" => Ok(Profile::UpPeak),
+            "down-peak" => Ok(Profile::DownPeak),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unknown traffic profile: {s}"))),
+        }
+    }
+}
+
+// Random passengers arriving at a fixed average rate
+pub struct Traffic {
+    profile: Profile,
+    num_floors: u8,
+    per_second: f64,
+    rng: Rng,
+}
+
+impl Traffic {
+    pub fn new(profile: Profile, num_floors: u8, per_minute: f64, seed: u64) -> Traffic {
+        Traffic { profile, num_floors, per_second: per_minute / 60.0, rng: Rng::new(seed) }
+    }
+
+    // The (origin, destination) of a passenger arriving during the next `step`, if one does
+    pub fn arrival(&mut self, step: Duration) -> Option<(u8, u8)> {
+        if self.num_floors < 2 || !self.rng.chance(self.per_second * step.as_secs_f64()) {
+            return None;
+        }
+        let peak = self.rng.chance(PEAK_SHARE);
+        let (origin, destination) = match self.profile {
+            Profile::UpPeak if peak => (0, self.other_floor(0)),
+            Profile::DownPeak if peak => (self.other_floor(0), 0),
+            _ => {
+                let origin = self.rng.below(self.num_floors as u64) as u8;
+                (origin, self.other_floor(origin))
+            }
+        };
+        Some((origin, destination))
+    }
+
+    // Pick any floor but `floor`
+    fn other_floor(&mut self, floor: u8) -> u8 {
+        let other = self.rng.below(self.num_floors as u64 - 1) as u8;
+        if other >= floor { other + 1 } else { other }
+    }
+
+    // The elevator whose panel the passenger uses
+    pub fn panel(&mut self, num_elevators: usize) -> usize {
+        self.rng.below(num_elevators as u64) as usize
+    }
+}
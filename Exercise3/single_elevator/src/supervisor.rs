@@ -0,0 +1,287 @@
+use std::{collections::{HashMap, VecDeque}, future::Future, io, pin::Pin};
+use tokio::task::{AbortHandle, Id, JoinError, JoinSet};
+use tokio::time::{Duration, Instant};
+use tracing::{Instrument, Span, error, info, info_span, warn};
+
+use crate::clock::SharedClock;
+
+type TaskFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+type Factory = Box<dyn FnMut() -> Option<TaskFuture> + Send>;
+
+const RESTART_DELAY: Duration = Duration::from_millis(100);
+
+// What to do when a supervised task panics or returns.
+// All tasks are expected to run forever, so any exit is treated as a failure.
+#[derive(Debug, Clone, Copy)]
+pub struct Policy {
+    pub max_restarts: u32,      // Restarts allowed within `window` before giving up
+    pub window: Duration,
+    pub critical: bool,         // Stop supervising altogether when the task cannot be restored
+}
+
+impl Policy {
+    pub const fn critical(max_restarts: u32) -> Policy {
+        Policy { max_restarts, window: Duration::from_secs(60), critical: true }
+    }
+
+    pub const fn best_effort(max_restarts: u32) -> Policy {
+        Policy { max_restarts, window: Duration::from_secs(60), critical: false }
+    }
+}
+
+struct Task {
+    name: &'static str,
+    policy: Policy,
+    factory: Factory,
+    restarts: VecDeque<Instant>,
+    handle: Option<AbortHandle>,    // Of the running instance
+}
+
+pub struct Supervisor {
+    clock: SharedClock,
+    running: JoinSet<()>,
+    tasks: HashMap<Id, Task>,
+    on_escalate: Vec<Box<dyn Fn() + Send>>,
+    span: Span,     // Parent of the span of every task
+}
+
+impl Supervisor {
+    // Tasks are logged inside the span that is current when the supervisor is created
+    pub fn new(clock: SharedClock) -> Supervisor {
+        Supervisor { clock, running: JoinSet::new(), tasks: HashMap::new(), on_escalate: Vec::new(), span: Span::current() }
+    }
+
+    // Supervise a task which can be restarted by calling `factory` again.
+    // Receivers a task needs across restarts must be shared, e.g. behind an Arc<Mutex<_>>.
+    pub fn spawn<F, Fut>(&mut self, name: &'static str, policy: Policy, mut factory: F)
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.start(Task {
+            name,
+            policy,
+            factory: Box::new(move || Some(Box::pin(factory()) as TaskFuture)),
+            restarts: VecDeque::new(),
+            handle: None,
+        });
+    }
+
+    // Supervise a task that owns state which cannot be rebuilt, it is never restarted
+    pub fn spawn_once<Fut>(&mut self, name: &'static str, critical: bool, future: Fut)
+    where
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let mut future = Some(Box::pin(future) as TaskFuture);
+        self.start(Task {
+            name,
+            policy: Policy { max_restarts: 0, window: Duration::ZERO, critical },
+            factory: Box::new(move || future.take()),
+            restarts: VecDeque::new(),
+            handle: None,
+        });
+    }
+
+    // The running instance of the task called `name`, e.g. to kill it while testing restarts.
+    // Only valid until the task exits, a restarted task has a new handle.
+    pub fn abort_handle(&self, name: &str) -> Option<AbortHandle> {
+        self.tasks.values().find(|task| task.name == name).and_then(|task| task.handle.clone())
+    }
+
+    // Run when a critical task fails for good, before `run` returns, e.g. to stop the motor
+    pub fn on_escalate(&mut self, f: impl Fn() + Send + 'static) {
+        self.on_escalate.push(Box::new(f));
+    }
+
+    // Watch the tasks until a critical one fails for good, then stop every task and return
+    // why. What to do about it is up to the caller: a node exits so its backup can take over,
+    // the simulation only loses that car. Returns Ok once no task is left.
+    pub async fn run(mut self) -> io::Result<()> {
+        while let Some(result) = self.running.join_next_with_id().await {
+            let (id, reason) = match result {
+                Ok((id, ())) => (id, "returned".to_string()),
+                Err(e) => (e.id(), failure_reason(e)),
+            };
+            let Some(mut task) = self.tasks.remove(&id) else { continue };
+            warn!(task = task.name, "Task {}", reason);
+
+            // Forget restarts older than the window
+            let now = self.clock.now();
+            while task.restarts.front().is_some_and(|t| now.duration_since(*t) > task.policy.window) {
+                task.restarts.pop_front();
+            }
+
+            let task = if task.restarts.len() < task.policy.max_restarts as usize {
+                task.restarts.push_back(now);
+                info!(task = task.name, restart = task.restarts.len(), max_restarts = task.policy.max_restarts, "Restarting task");
+                self.clock.sleep(RESTART_DELAY).await;
+                match self.start(task) {
+                    Some(task) => task,
+                    None => continue,
+                }
+            } else {
+                task
+            };
+
+            if task.policy.critical {
+                return Err(self.escalate(task.name));
+            }
+            error!(task = task.name, "Giving up on task");
+        }
+        Ok(())
+    }
+
+    // The task is handed back if its factory has nothing to start
+    fn start(&mut self, mut task: Task) -> Option<Task> {
+        let Some(future) = (task.factory)() else {
+            error!(task = task.name, "Task cannot be restarted");
+            return Some(task);
+        };
+        let span = info_span!(parent: &self.span, "task", name = task.name);
+        let handle = self.running.spawn(future.instrument(span));
+        task.handle = Some(handle.clone());
+        self.tasks.insert(handle.id(), task);
+        None
+    }
+
+    fn escalate(&mut self, name: &str) -> io::Error {
+        error!(task = name, "Critical task could not be restored, stopping every task");
+        for f in self.on_escalate.iter() {
+            f();
+        }
+        self.running.abort_all();
+        io::Error::other(format!("critical task {name} could not be restored"))
+    }
+}
+
+
+// ---------- PURE FUNCTIONS ----------
+
+fn failure_reason(e: JoinError) -> String {
+    if !e.is_panic() {
+        return "was cancelled".to_string();
+    }
+    let payload = e.into_panic();
+    let msg = payload.downcast_ref::<&str>().map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string());
+    format!("panicked: {}", msg)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, atomic::{AtomicU32, Ordering}};
+    use crate::clock::{Clock, VirtualClock};
+
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    fn supervisor() -> (Arc<VirtualClock>, Supervisor) {
+        let clock = VirtualClock::new();
+        (clock.clone(), Supervisor::new(clock))
+    }
+
+    // A task that counts its starts, and fails `after` that long unless told to run forever
+    fn failing(clock: &Arc<VirtualClock>, after: Duration, forever_from: u32) -> (Arc<AtomicU32>, impl FnMut() -> TaskFuture + Send + 'static) {
+        let starts = Arc::new(AtomicU32::new(0));
+        let (clock, counter) = (clock.clone(), starts.clone());
+        let factory = move || {
+            let (clock, start) = (clock.clone(), counter.fetch_add(1, Ordering::Relaxed) + 1);
+            Box::pin(async move {
+                clock.sleep(after).await;
+                if start >= forever_from {
+                    std::future::pending::<()>().await;
+                }
+            }) as TaskFuture
+        };
+        (starts, factory)
+    }
+
+    // Run the supervisor for `duration` of virtual time, its result if it returned by then
+    async fn drive(clock: &VirtualClock, supervisor: Supervisor, duration: Duration) -> Option<io::Result<()>> {
+        let run = tokio::spawn(supervisor.run());
+        let mut elapsed = Duration::ZERO;
+        while elapsed < duration {
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+            if run.is_finished() {
+                return Some(run.await.unwrap());
+            }
+            clock.advance(RESTART_DELAY);
+            elapsed += RESTART_DELAY;
+        }
+        run.abort();
+        None
+    }
+
+    #[tokio::test]
+    async fn a_failing_task_is_restarted_within_the_limit() {
+        let (clock, mut supervisor) = supervisor();
+        let (starts, factory) = failing(&clock, Duration::ZERO, 3);
+        supervisor.spawn("flaky", Policy::critical(2), factory);
+
+        assert!(drive(&clock, supervisor, Duration::from_secs(10)).await.is_none());
+        assert_eq!(starts.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn a_task_failing_too_often_is_given_up() {
+        let (clock, mut supervisor) = supervisor();
+        let (starts, factory) = failing(&clock, Duration::ZERO, u32::MAX);
+        supervisor.spawn("broken", Policy::best_effort(2), factory);
+
+        assert!(drive(&clock, supervisor, Duration::from_secs(10)).await.unwrap().is_ok());
+        assert_eq!(starts.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn failures_older_than_the_window_are_forgiven() {
+        let (clock, mut supervisor) = supervisor();
+        let (starts, factory) = failing(&clock, WINDOW + Duration::from_secs(1), u32::MAX);
+        supervisor.spawn("slow", Policy::critical(1), factory);
+
+        assert!(drive(&clock, supervisor, WINDOW * 5).await.is_none());
+        assert!(starts.load(Ordering::Relaxed) >= 4);
+    }
+
+    #[tokio::test]
+    async fn a_task_spawned_once_is_never_restarted() {
+        let (clock, mut supervisor) = supervisor();
+        let starts = Arc::new(AtomicU32::new(0));
+        let counter = starts.clone();
+        supervisor.spawn_once("once", false, async move {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+
+        assert!(drive(&clock, supervisor, Duration::from_secs(10)).await.unwrap().is_ok());
+        assert_eq!(starts.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn a_critical_task_failing_for_good_escalates_and_stops_the_rest() {
+        for once in [true, false] {
+            let (clock, mut supervisor) = supervisor();
+            let escalated = Arc::new(AtomicU32::new(0));
+            let counter = escalated.clone();
+            supervisor.on_escalate(move || { counter.fetch_add(1, Ordering::Relaxed); });
+
+            let (others, factory) = failing(&clock, Duration::ZERO, 1);
+            supervisor.spawn("bystander", Policy::best_effort(0), factory);
+            let bystander = supervisor.abort_handle("bystander").unwrap();
+            if once {
+                supervisor.spawn_once("critical", true, async { panic!("broken") });
+            } else {
+                let (_, factory) = failing(&clock, Duration::ZERO, u32::MAX);
+                supervisor.spawn("critical", Policy::critical(2), factory);
+            }
+
+            let error = drive(&clock, supervisor, Duration::from_secs(10)).await.unwrap().unwrap_err();
+            assert!(error.to_string().contains("critical task critical could not be restored"), "{error}");
+            assert_eq!(escalated.load(Ordering::Relaxed), 1);
+            assert_eq!(others.load(Ordering::Relaxed), 1);
+            assert!(bystander.is_finished());
+        }
+    }
+}
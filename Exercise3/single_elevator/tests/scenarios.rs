@@ -0,0 +1,18 @@
+use std::fs;
+
+use single_elevator::simulation::scenario;
+
+// Away from DEFAULT_BASE_PORT, so a `sim` or `scenario` run can go on meanwhile
+const BASE_PORT: u16 = 26000;
+
+#[tokio::test]
+async fn every_scenario_passes() {
+    let mut paths: Vec<String> = fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/scenarios"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path().to_string_lossy().into_owned())
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty());
+
+    scenario::run_files(&paths, BASE_PORT).await.unwrap();
+}
@@ -1,36 +1,68 @@
-use tokio::join;
-
-pub mod networking;
-pub mod process;
+use tokio::{sync::{watch, Notify}, time};
+use std::{io, sync::Arc, time::Duration};
+use single_elevator::{clock, logging, process_pair::{self, PairConfig, Role}};
 
 #[tokio::main]
-async fn main(){
+async fn main() -> io::Result<()> {
 
-    let mut args= std::env::args();
-    let local = args.nth(1).unwrap();
-    let remote = args.nth(0).unwrap();
+    // cargo run -- <local addr> <remote addr> [period ms] [timeout ms] [--role primary|backup]
+    logging::init();
+    let config = PairConfig::from_args(std::env::args().skip(1))?;
+    let clock = clock::system();
 
+    let mysock = process_pair::bind(config.local).await?;
 
-    let mysock = networking::init_socket(&local).await;
+    let mut latest = match config.role {
+        Role::Backup => process_pair::backup::<u32>(mysock.clone(), clock.clone(), &config, None).await, // blocks untill there is no master online -> become master
+        Role::Primary => None,
+    };
 
-    let recv_sock = mysock.clone();
-    let highest = process::master_check(recv_sock).await; // blocks untill there is no master online -> become master
+    loop {
+        // Take over with a new epoch, so the old primary steps down if it comes back
+        let (epoch, version, highest) = match latest {
+            Some(snapshot) => (snapshot.epoch + 1, snapshot.version, snapshot.state),
+            None => (1, 0, 0),
+        };
+        println!("Primary with epoch {}", epoch);
 
-    // restart code
+        // The counter is the state shipped to the backup
+        let (state_tx, state_rx) = watch::channel(highest);
+        let heartbeat = Arc::new(Notify::new());
 
-    let _ = std::process::Command::new("gnome-terminal")
-    .arg("--")
-    .arg("bash")
-    .arg("-c")
-    .arg(format!("cargo run {remote} {local}"))
-    .spawn();
+        // restart code
+        let spawn_config = config.clone();
+        let (spawn_clock, spawn_heartbeat) = (clock.clone(), heartbeat.clone());
+        let spawn_thread = tokio::spawn(async move {
+            match process_pair::spawn_backup(spawn_clock, &spawn_config, &spawn_config.backup_args(), spawn_heartbeat).await {
+                Ok(child) => Some(child),
+                Err(e) => {
+                    println!("Could not start backup: {}", e);
+                    None
+                }
+            }
+        });
 
+        let count = async {
+            loop {
+                state_tx.send_modify(|highest| *highest += 1);
+                println!("Counted: {}", *state_tx.borrow());
+                time::sleep(Duration::from_millis(1000)).await;
+            }
+        };
 
+        // Count until another primary with a higher epoch is heard, then go back to being the backup
+        let newer = tokio::select! {
+            newer = process_pair::primary(mysock.clone(), clock.clone(), &config, state_rx, epoch, version, heartbeat) => newer,
+            _ = count => unreachable!(),
+        };
 
-    let send_sock = mysock.clone();
-    let send_thread = tokio::spawn(async move{
-        networking::ping_alive(send_sock, highest, remote).await;
-    });
-    
-    let _ = join!(send_thread);
+        // The backup we started belongs to this primary, so kill it before becoming the backup.
+        // One that is still starting is killed by spawn_backup when the task is aborted.
+        spawn_thread.abort();
+        if let Ok(Some(mut child)) = spawn_thread.await {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        latest = process_pair::backup(mysock.clone(), clock.clone(), &config, Some(newer)).await;
+    }
 }
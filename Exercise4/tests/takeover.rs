@@ -0,0 +1,108 @@
+// The pair on localhost, both halves in this process. A paused primary is one whose future is
+// not polled: its socket keeps queueing what the other half sends, like a stopped process would.
+
+use std::{net::SocketAddr, pin::pin, sync::Arc, time::Duration};
+use tokio::{net::UdpSocket, sync::{watch, Notify}, time};
+use single_elevator::{clock, process_pair::{self, PairConfig, Role, snapshot::Snapshot}};
+
+const PERIOD: Duration = Duration::from_millis(20);
+const TIMEOUT: Duration = Duration::from_millis(200);
+
+struct Half {
+    sock: Arc<UdpSocket>,
+    config: PairConfig,
+}
+
+async fn pair() -> (Half, Half) {
+    let (a, b) = (process_pair::bind(localhost()).await.unwrap(), process_pair::bind(localhost()).await.unwrap());
+    let config = |local: &UdpSocket, remote: &UdpSocket, role| PairConfig {
+        local: local.local_addr().unwrap(),
+        remote: remote.local_addr().unwrap(),
+        period: PERIOD,
+        timeout: TIMEOUT,
+        role,
+    };
+    let (config_a, config_b) = (config(&a, &b, Role::Primary), config(&b, &a, Role::Backup));
+    (Half { sock: a, config: config_a }, Half { sock: b, config: config_b })
+}
+
+fn localhost() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 0))
+}
+
+#[tokio::test]
+async fn the_backup_takes_over_a_paused_primary_which_steps_down_when_resumed() {
+    let (first, second) = pair().await;
+
+    // The first half is primary, and ships the counter to the second half
+    let (_state_tx, state_rx) = watch::channel(17u32);
+    let mut old_primary = pin!(process_pair::primary(first.sock.clone(), clock::system(), &first.config, state_rx, 1, 0, Arc::new(Notify::new())));
+    let backup = tokio::spawn({
+        let (sock, config) = (second.sock.clone(), second.config.clone());
+        async move { process_pair::backup::<u32>(sock, clock::system(), &config, None).await }
+    });
+    assert!(time::timeout(TIMEOUT, &mut old_primary).await.is_err(), "primary stepped down with nobody to step down for");
+
+    // Pause the primary for longer than the timeout, the backup takes over with its state
+    let taken = time::timeout(TIMEOUT * 5, backup).await.expect("backup never took over").unwrap();
+    let taken = taken.expect("backup never got a snapshot");
+    assert_eq!((taken.epoch, taken.state), (1, 17));
+
+    let (_state_tx, state_rx) = watch::channel(taken.state + 1);
+    let new_primary = tokio::spawn({
+        let (sock, config) = (second.sock.clone(), second.config.clone());
+        async move { process_pair::primary(sock, clock::system(), &config, state_rx, taken.epoch + 1, taken.version, Arc::new(Notify::new())).await }
+    });
+
+    // Resumed, the old primary hears the higher epoch and steps down
+    let newer: Snapshot<u32> = time::timeout(TIMEOUT, &mut old_primary).await.expect("old primary did not step down");
+    assert_eq!((newer.epoch, newer.state), (2, 18));
+    assert!(newer.version > taken.version);
+
+    // While the new primary ignores the old one and carries on
+    time::sleep(TIMEOUT).await;
+    assert!(!new_primary.is_finished());
+    new_primary.abort();
+}
+
+#[tokio::test]
+async fn the_old_primary_rejoins_as_backup_and_follows_the_new_one() {
+    let (first, second) = pair().await;
+
+    let (_state_tx, state_rx) = watch::channel(1u32);
+    let new_primary = tokio::spawn({
+        let (sock, config) = (second.sock.clone(), second.config.clone());
+        async move { process_pair::primary(sock, clock::system(), &config, state_rx, 2, 0, Arc::new(Notify::new())).await }
+    });
+
+    // Stepping down leaves the old primary with the snapshot of the new one, it only moves forward from there
+    let stale = Snapshot { epoch: 1, version: 1000, state: 0u32 };
+    let following = tokio::spawn({
+        let (sock, config) = (first.sock.clone(), first.config.clone());
+        async move { process_pair::backup(sock, clock::system(), &config, Some(stale)).await }
+    });
+    time::sleep(TIMEOUT * 2).await;
+    assert!(!following.is_finished(), "backup took over from a live primary");
+
+    // The new primary goes silent in turn, and the old one takes over from its state
+    new_primary.abort();
+    let taken = time::timeout(TIMEOUT * 5, following).await.expect("backup never took over").unwrap().unwrap();
+    assert_eq!((taken.epoch, taken.state), (2, 1));
+}
+
+#[tokio::test]
+async fn on_equal_epochs_the_lowest_address_stays_primary() {
+    let (first, second) = pair().await;
+    let (low, high) = if first.config.local < second.config.local { (first, second) } else { (second, first) };
+
+    let (_state_tx, state_rx) = watch::channel(0u32);
+    let low_primary = tokio::spawn(async move {
+        process_pair::primary(low.sock, clock::system(), &low.config, state_rx, 3, 0, Arc::new(Notify::new())).await
+    });
+    let (_state_tx, state_rx) = watch::channel(0u32);
+    let stepped_down = process_pair::primary(high.sock, clock::system(), &high.config, state_rx, 3, 0, Arc::new(Notify::new()));
+    let newer = time::timeout(TIMEOUT, stepped_down).await.expect("higher address did not step down");
+    assert_eq!(newer.epoch, 3);
+    assert!(!low_primary.is_finished());
+    low_primary.abort();
+}